@@ -2,13 +2,21 @@ use std::thread;
 use std::time;
 
 use rand::Rng;
-use test_rust::MetricsRwLock;
+use test_rust::{MetricsPersistConfig, MetricsPersister, MetricsRwLock};
 use anyhow::Result;
 use anyhow::anyhow;
 const M: usize = 5;
 const N: usize = 3;
+// seconds the example runs before it flushes the metrics one last time and exits
+const RUN_SECS: usize = 60;
 pub fn main() -> Result<()> {
     let my_metrics = MetricsRwLock::new();
+    let persister = MetricsPersister::new(
+        my_metrics.clone(),
+        MetricsPersistConfig::new("tmp/metrics_rwlock.jsonl", time::Duration::from_secs(10)),
+    );
+    persister.restore()?;
+    let flusher = persister.start();
     let my_metrics2 = my_metrics.clone();
     for i in 0..M {
         let _ = practise_rwlock(my_metrics.clone(), format!("key1: {}",i).as_str());
@@ -21,12 +29,12 @@ pub fn main() -> Result<()> {
 
 
     let handler = thread::spawn( move || -> Result<()>{
-        let mut counter = 0;
-        loop {
+        for counter in 1..=RUN_SECS {
             thread::sleep(time::Duration::from_secs(1));
-            counter += 1;
             println!("handler1: {} {:?}", counter, my_metrics.clone().snapshot().map_err(|e| anyhow!(e.to_string()))?);
-    }});
+        }
+        Ok(())
+    });
 
     for _ in 0..2 {
         let my_metrics2 = my_metrics2.clone();
@@ -41,8 +49,10 @@ pub fn main() -> Result<()> {
         });
     }
 
-    let _ =handler.join().unwrap();
-    Ok(())
+    let result = handler.join().map_err(|_| anyhow!("handler panicked"))?;
+    // flush what changed since the last interval before exiting
+    flusher.stop()?;
+    result
 }
 
 pub fn practise_rwlock(metrics: MetricsRwLock, str: &str) -> Result<()> {
@@ -0,0 +1,29 @@
+// roles of the chat server's users, for the first admin of a workspace
+//
+//   cargo run --example chat_admin -- grant <email>    make the user an admin
+//   cargo run --example chat_admin -- revoke <email>   make the admin a regular user again
+//
+// the role is in the user's tokens from the next sign in or refresh on. admins can change
+// the roles of the other users of their workspace with POST /admin/users/{id}/role
+use anyhow::{Result, bail};
+use test_rust::{AppConfig, StorageKind, UserType, chat_store};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let (user_type, email) = match args.as_slice() {
+        [cmd, email] if cmd == "grant" => (UserType::Admin, email),
+        [cmd, email] if cmd == "revoke" => (UserType::Regular, email),
+        _ => bail!("usage: chat_admin grant | revoke <email>"),
+    };
+    let config = AppConfig::load()?;
+    if config.server.storage == StorageKind::Memory {
+        bail!("the memory store lives in the server process, there is nothing to change");
+    }
+    let store = chat_store(&config).await?;
+    let Some(user) = store.set_user_type(email, user_type).await? else {
+        bail!("no user with the email {email}");
+    };
+    println!("{} ({}) is {:?} now", user.name, user.email, user.user_type);
+    Ok(())
+}
@@ -0,0 +1,29 @@
+// master keys of the chat server's file encryption
+//
+//   cargo run --example file_keys -- generate   print a new master key for `server.encryption.keys`
+//   cargo run --example file_keys -- rotate     wrap every file key with `server.encryption.current`
+//
+// stop the server before rotating: a download of an object that is rewritten under it can
+// fail. after rotating, the master keys that are not current can be removed from the config
+use anyhow::{Result, bail};
+use test_rust::{AppConfig, EncryptedStorage, MasterKeys, blob_backend};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    match std::env::args().nth(1).as_deref() {
+        Some("generate") => println!("{}", MasterKeys::generate()),
+        Some("rotate") => {
+            let config = AppConfig::load()?;
+            let backend = blob_backend(&config.server)?;
+            let Some(storage) = EncryptedStorage::from_config(&config.server, backend)? else {
+                bail!("server.encryption is not configured");
+            };
+            for prefix in ["blobs/", "thumbs/"] {
+                let rotated = storage.rotate(prefix).await?;
+                println!("{prefix}: rewrote {rotated} objects");
+            }
+        }
+        _ => bail!("usage: file_keys generate | rotate"),
+    }
+    Ok(())
+}
@@ -0,0 +1,14 @@
+-- columns the chat server needs on top of the initial schema
+-- argon2 hashes are 97 chars, longer than the original varchar(64)
+ALTER TABLE users
+  ALTER COLUMN password TYPE varchar(128),
+  ALTER COLUMN type SET DEFAULT 'regular',
+  ADD COLUMN IF NOT EXISTS teenager boolean NOT NULL DEFAULT FALSE,
+  ADD COLUMN IF NOT EXISTS created_at timestamptz DEFAULT CURRENT_TIMESTAMP;
+
+-- create index for users for email
+CREATE UNIQUE INDEX IF NOT EXISTS email_index ON users(email);
+
+ALTER TABLE chats
+  ALTER COLUMN members SET DEFAULT '{}',
+  ADD COLUMN IF NOT EXISTS created_at timestamptz DEFAULT CURRENT_TIMESTAMP;
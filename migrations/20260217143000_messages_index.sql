@@ -0,0 +1,5 @@
+-- create index for messages for chat_id order by message_id desc, the cursor of a chat's pages
+CREATE INDEX IF NOT EXISTS chat_id_message_id_index ON messages(chat_id, message_id DESC);
+
+-- create index for messages for sender_id
+CREATE INDEX IF NOT EXISTS sender_id_index ON messages(sender_id, created_at DESC);
@@ -0,0 +1,15 @@
+-- opaque refresh tokens, only the hash of the token is kept
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    token_hash varchar(64) PRIMARY KEY,
+    user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    expires_at timestamptz NOT NULL,
+    created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS refresh_tokens_user_id_index ON refresh_tokens(user_id);
+
+-- access tokens revoked before they expire, by jti
+CREATE TABLE IF NOT EXISTS revoked_tokens (
+    jti varchar(64) PRIMARY KEY,
+    expires_at timestamptz NOT NULL
+);
@@ -0,0 +1,7 @@
+-- chat ids used to be chosen by clients, move the sequence past them
+SELECT setval(pg_get_serial_sequence('chats', 'chat_id'), COALESCE(MAX(chat_id), 0) + 1, false)
+FROM chats;
+
+-- public channels are listed and searched by name
+CREATE INDEX IF NOT EXISTS chats_public_name_index ON chats(lower(chat_name))
+WHERE type = 'public_channel';
@@ -0,0 +1,19 @@
+-- owners and moderators manage the members of a chat
+ALTER TABLE chats
+  ADD COLUMN IF NOT EXISTS owner_id bigint REFERENCES users(id) ON DELETE SET NULL,
+  ADD COLUMN IF NOT EXISTS moderators bigint[] NOT NULL DEFAULT '{}';
+
+-- the creator was always the first member
+UPDATE chats SET owner_id = members[1]
+WHERE owner_id IS NULL AND EXISTS (SELECT 1 FROM users WHERE id = members[1]);
+
+-- pending invitations, removed once accepted or declined
+CREATE TABLE IF NOT EXISTS chat_invitations (
+  chat_id bigint NOT NULL REFERENCES chats(chat_id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  inviter_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (chat_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS chat_invitations_user_id_index ON chat_invitations(user_id);
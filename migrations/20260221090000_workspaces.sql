@@ -0,0 +1,29 @@
+-- workspaces separate users and chats, implements the sketch in 20260128031640_workspace.sql
+CREATE TABLE IF NOT EXISTS workspaces(
+  id bigserial PRIMARY KEY,
+  name varchar(32) NOT NULL UNIQUE,
+  -- NULL until the creator has signed up, or after the last member is gone
+  owner_id bigint REFERENCES users(id),
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+ALTER TABLE users
+  ADD COLUMN IF NOT EXISTS ws_id bigint REFERENCES workspaces(id);
+
+ALTER TABLE chats
+  ADD COLUMN IF NOT EXISTS ws_id bigint REFERENCES workspaces(id);
+
+-- workspace 0 takes everything created before workspaces existed, nobody owns it
+INSERT INTO workspaces(id, name)
+  VALUES (0, 'none')
+  ON CONFLICT (id) DO NOTHING;
+UPDATE users SET ws_id = 0 WHERE ws_id IS NULL;
+UPDATE chats SET ws_id = 0 WHERE ws_id IS NULL;
+
+ALTER TABLE users
+  ALTER COLUMN ws_id SET NOT NULL;
+ALTER TABLE chats
+  ALTER COLUMN ws_id SET NOT NULL;
+
+CREATE INDEX IF NOT EXISTS users_ws_id_index ON users(ws_id);
+CREATE INDEX IF NOT EXISTS chats_ws_id_index ON chats(ws_id);
@@ -0,0 +1,15 @@
+-- the files uploaded to a chat, for the upload quotas of the chat and of the uploader.
+-- files are named by the sha256 of their content. the uploader is kept as a plain id, the
+-- files stay with the chat when the user is deleted
+CREATE TABLE IF NOT EXISTS chat_files (
+  chat_id bigint NOT NULL REFERENCES chats(chat_id) ON DELETE CASCADE,
+  hash varchar(64) NOT NULL,
+  ext varchar(16) NOT NULL,
+  size bigint NOT NULL,
+  mime varchar(255) NOT NULL,
+  uploader_id bigint NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (chat_id, hash, ext)
+);
+
+CREATE INDEX IF NOT EXISTS chat_files_uploader_id_index ON chat_files(uploader_id);
@@ -0,0 +1,20 @@
+-- chat files move to one content addressed store keyed by sha256, each blob is kept once
+-- however many chats use it
+CREATE TABLE IF NOT EXISTS blobs (
+  hash varchar(64) PRIMARY KEY,
+  size bigint NOT NULL,
+  mime varchar(255) NOT NULL,
+  -- the chat_files rows using the blob, unused blobs are removed by the garbage collector
+  refcount integer NOT NULL DEFAULT 0 CHECK (refcount >= 0),
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- the files uploaded so far become the first blobs
+INSERT INTO blobs (hash, size, mime, refcount)
+  SELECT hash, max(size), min(mime), count(*) FROM chat_files GROUP BY hash
+  ON CONFLICT (hash) DO NOTHING;
+
+ALTER TABLE chat_files
+  ADD CONSTRAINT chat_files_hash_fkey FOREIGN KEY (hash) REFERENCES blobs(hash);
+
+CREATE INDEX IF NOT EXISTS chat_files_hash_index ON chat_files(hash);
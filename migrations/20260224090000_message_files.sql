@@ -0,0 +1,3 @@
+-- messages carry their attachments as they were when sent: hash, ext, size, mime and url.
+-- the old file column held raw client paths that were never served, it is kept but unused
+ALTER TABLE messages ADD COLUMN files jsonb NOT NULL DEFAULT '[]'::jsonb;
@@ -0,0 +1,6 @@
+-- what processing a blob after its upload found out, null until processed_at is set
+ALTER TABLE blobs
+  ADD COLUMN width integer,
+  ADD COLUMN height integer,
+  ADD COLUMN thumb_mime varchar(32),
+  ADD COLUMN processed_at timestamptz;
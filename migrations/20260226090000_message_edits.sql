@@ -0,0 +1,23 @@
+-- edited and deleted messages. a deleted message stays as a tombstone with its content cleared
+ALTER TABLE messages
+  ADD COLUMN edited_at timestamptz,
+  ADD COLUMN deleted_at timestamptz;
+
+-- earlier contents of edited messages, created_at is when that content was written
+CREATE TABLE IF NOT EXISTS message_edits (
+  id bigserial PRIMARY KEY,
+  message_id bigint NOT NULL REFERENCES messages(message_id) ON DELETE CASCADE,
+  content text NOT NULL,
+  created_at timestamptz NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS message_edits_message_id_index ON message_edits(message_id, id);
+
+-- one row per user and emoji on a message
+CREATE TABLE IF NOT EXISTS message_reactions (
+  message_id bigint NOT NULL REFERENCES messages(message_id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  emoji varchar(64) NOT NULL,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (message_id, user_id, emoji)
+);
@@ -0,0 +1,10 @@
+-- replies point at the root of their thread, a reply to a hard deleted root becomes a plain message
+ALTER TABLE messages
+  ADD COLUMN reply_to bigint REFERENCES messages(message_id) ON DELETE SET NULL,
+  ADD COLUMN mentions bigint[] NOT NULL DEFAULT '{}';
+
+CREATE INDEX IF NOT EXISTS messages_reply_to_index ON messages(reply_to, message_id)
+  WHERE reply_to IS NOT NULL;
+
+-- the mentions inbox looks up messages by mentioned user
+CREATE INDEX IF NOT EXISTS messages_mentions_index ON messages USING GIN (mentions);
@@ -0,0 +1,8 @@
+-- how far each member has read a chat, the last read message only moves forward
+CREATE TABLE IF NOT EXISTS chat_reads (
+  chat_id bigint NOT NULL REFERENCES chats(chat_id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  message_id bigint NOT NULL,
+  updated_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (chat_id, user_id)
+);
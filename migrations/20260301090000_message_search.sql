@@ -0,0 +1,6 @@
+-- full-text search over message contents. the `simple` configuration only lowercases words,
+-- so search behaves the same as the in-memory index. deleted messages have no content left
+ALTER TABLE messages
+  ADD COLUMN search_vector tsvector GENERATED ALWAYS AS (to_tsvector('simple', content)) STORED;
+
+CREATE INDEX IF NOT EXISTS messages_search_index ON messages USING GIN (search_vector);
@@ -0,0 +1,10 @@
+-- the default parser of `simple` keeps emails, hosts and paths such as bob@example.com in one
+-- piece, the in-memory index splits them into words. split the content into its runs of
+-- letters and digits first, like tokenize() in search_index.rs, so both find the same words
+ALTER TABLE messages DROP COLUMN search_vector;
+ALTER TABLE messages
+  ADD COLUMN search_vector tsvector GENERATED ALWAYS AS (
+    to_tsvector('simple', regexp_replace(content, '[^[:alnum:]]+', ' ', 'g'))
+  ) STORED;
+
+CREATE INDEX IF NOT EXISTS messages_search_index ON messages USING GIN (search_vector);
@@ -0,0 +1,375 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::{
+        atomic::Ordering,
+        mpsc::{self, RecvTimeoutError, Sender},
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::Result;
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{MetricsAtomic, MetricsDashMap, MetricsMutex, MetricsRwLock};
+
+// every metrics type can dump its counters and load them back
+pub trait MetricsSnapshot: Clone + Send + 'static {
+    fn snapshot(&self) -> Result<HashMap<String, i32>>;
+    fn restore(&self, data: HashMap<String, i32>) -> Result<()>;
+}
+
+#[derive(Debug, Clone)]
+pub struct MetricsPersistConfig {
+    pub path: PathBuf,
+    pub interval: Duration,
+    // once the file is larger, it is cut down to the newer half of its snapshots
+    pub max_bytes: u64,
+}
+
+// one line of the snapshot file
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MetricsRecord {
+    pub ts: DateTime<Utc>,
+    pub metrics: HashMap<String, i32>,
+}
+
+pub struct MetricsPersister<M: MetricsSnapshot> {
+    metrics: M,
+    config: MetricsPersistConfig,
+}
+
+// the background flushing of MetricsPersister::start, dropping it stops the flushing too
+pub struct MetricsFlusher {
+    stop: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl Default for MetricsPersistConfig {
+    fn default() -> Self {
+        MetricsPersistConfig {
+            path: "tmp/metrics.jsonl".into(),
+            interval: Duration::from_secs(5),
+            max_bytes: 1024 * 1024,
+        }
+    }
+}
+
+impl MetricsPersistConfig {
+    pub fn new(path: impl Into<PathBuf>, interval: Duration) -> Self {
+        MetricsPersistConfig {
+            path: path.into(),
+            interval,
+            ..Default::default()
+        }
+    }
+}
+
+impl<M: MetricsSnapshot> MetricsPersister<M> {
+    pub fn new(metrics: M, config: MetricsPersistConfig) -> Self {
+        MetricsPersister { metrics, config }
+    }
+
+    // load the latest snapshot into the metrics, return false if there is nothing to restore
+    pub fn restore(&self) -> Result<bool> {
+        let Some(record) = self.latest()? else {
+            return Ok(false);
+        };
+        self.metrics.restore(record.metrics)?;
+        Ok(true)
+    }
+
+    // append the current counters to the snapshot file as one json line, so the earlier
+    // snapshots are kept as history, as much of it as fits in `max_bytes`
+    pub fn flush(&self) -> Result<()> {
+        let record = MetricsRecord {
+            ts: Utc::now(),
+            metrics: self.metrics.snapshot()?,
+        };
+        if let Some(parent) = self.config.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&self.config.path)?;
+        let mut line = vec![];
+        // start a new line after a line cut off by a crash
+        if file.metadata()?.len() > 0 {
+            let mut last = [0u8];
+            file.seek(SeekFrom::End(-1))?;
+            file.read_exact(&mut last)?;
+            if last[0] != b'\n' {
+                line.push(b'\n');
+            }
+        }
+        serde_json::to_writer(&mut line, &record)?;
+        line.push(b'\n');
+        // one write per line, so a crash leaves at most the last line cut off
+        file.write_all(&line)?;
+        if file.metadata()?.len() > self.config.max_bytes {
+            self.compact(&mut file)?;
+        }
+        Ok(())
+    }
+
+    // the last line that parses, a line cut off by a crash is skipped. only the end of the
+    // file is read, more of it if the lines there do not parse
+    pub fn latest(&self) -> Result<Option<MetricsRecord>> {
+        let mut file = match File::open(&self.config.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let len = file.metadata()?.len();
+        let mut size = 4096;
+        loop {
+            let tail = read_tail(&mut file, len, size)?;
+            let record = tail
+                .rsplit(|b| *b == b'\n')
+                .find_map(|line| serde_json::from_slice(line).ok());
+            if record.is_some() || size >= len {
+                return Ok(record);
+            }
+            size *= 4;
+        }
+    }
+
+    // keep the newer half of the snapshots, written to a new file that replaces the old one,
+    // so a crash leaves one or the other
+    fn compact(&self, file: &mut File) -> Result<()> {
+        let len = file.metadata()?.len();
+        let keep = read_tail(file, len, self.config.max_bytes / 2)?;
+        let tmp = self.config.path.with_extension("jsonl.tmp");
+        fs::write(&tmp, keep)?;
+        fs::rename(&tmp, &self.config.path)?;
+        Ok(())
+    }
+
+    // flush the metrics every `interval` in a background thread, until the flusher is stopped
+    pub fn start(self) -> MetricsFlusher {
+        let (stop, stopped) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            loop {
+                let stop = !matches!(
+                    stopped.recv_timeout(self.config.interval),
+                    Err(RecvTimeoutError::Timeout)
+                );
+                // a failed flush is retried at the next interval
+                if let Err(e) = self.flush() {
+                    warn!("flush metrics to {:?} failed: {e}", self.config.path);
+                }
+                if stop {
+                    break;
+                }
+            }
+        });
+        MetricsFlusher { stop, handle }
+    }
+}
+
+// the last `size` bytes of the file, the first line is cut off unless it starts the file
+fn read_tail(file: &mut File, len: u64, size: u64) -> Result<Vec<u8>> {
+    let start = len.saturating_sub(size);
+    file.seek(SeekFrom::Start(start))?;
+    let mut tail = vec![];
+    Read::by_ref(file).take(len - start).read_to_end(&mut tail)?;
+    if start > 0 {
+        let first = tail
+            .iter()
+            .position(|b| *b == b'\n')
+            .map_or(tail.len(), |i| i + 1);
+        tail.drain(..first);
+    }
+    Ok(tail)
+}
+
+impl MetricsFlusher {
+    // flush one last time and wait for the thread to end
+    pub fn stop(self) -> Result<()> {
+        let _ = self.stop.send(());
+        self.handle
+            .join()
+            .map_err(|_| anyhow!("metrics flusher panicked"))
+    }
+}
+
+impl MetricsSnapshot for MetricsMutex {
+    fn snapshot(&self) -> Result<HashMap<String, i32>> {
+        MetricsMutex::snapshot(self)
+    }
+
+    fn restore(&self, data: HashMap<String, i32>) -> Result<()> {
+        let mut map = self.lock().map_err(|e| anyhow!(e.to_string()))?;
+        map.extend(data);
+        Ok(())
+    }
+}
+
+impl MetricsSnapshot for MetricsRwLock {
+    // not MetricsRwLock::snapshot, which sleeps to simulate a slow reader
+    fn snapshot(&self) -> Result<HashMap<String, i32>> {
+        Ok(self.read().map_err(|e| anyhow!(e.to_string()))?.clone())
+    }
+
+    fn restore(&self, data: HashMap<String, i32>) -> Result<()> {
+        let mut map = self.write().map_err(|e| anyhow!(e.to_string()))?;
+        map.extend(data);
+        Ok(())
+    }
+}
+
+impl MetricsSnapshot for MetricsDashMap {
+    fn snapshot(&self) -> Result<HashMap<String, i32>> {
+        Ok(self
+            .iter()
+            .map(|kv| (kv.key().clone(), *kv.value()))
+            .collect())
+    }
+
+    fn restore(&self, data: HashMap<String, i32>) -> Result<()> {
+        for (k, v) in data {
+            self.insert(k, v);
+        }
+        Ok(())
+    }
+}
+
+impl MetricsSnapshot for MetricsAtomic {
+    fn snapshot(&self) -> Result<HashMap<String, i32>> {
+        Ok(self
+            .iter()
+            .map(|(k, v)| (k.clone(), v.load(Ordering::Relaxed)))
+            .collect())
+    }
+
+    // the key set is fixed at creation, unknown keys in the snapshot are skipped
+    fn restore(&self, data: HashMap<String, i32>) -> Result<()> {
+        for (k, v) in data {
+            if let Some(counter) = self.get(&k) {
+                counter.store(v, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_config() -> MetricsPersistConfig {
+        let path = std::env::temp_dir().join(format!("metrics_{}.jsonl", nanoid::nanoid!()));
+        MetricsPersistConfig::new(path, Duration::from_millis(10))
+    }
+
+    #[test]
+    fn test_flush_and_restore() -> Result<()> {
+        let config = temp_config();
+        let metrics = MetricsDashMap::new();
+        metrics.inc("a")?;
+        metrics.inc("a")?;
+        metrics.inc("b")?;
+        let persister = MetricsPersister::new(metrics.clone(), config.clone());
+        persister.flush()?;
+        metrics.inc("b")?;
+        persister.flush()?;
+
+        let restored = MetricsMutex::new();
+        let persister = MetricsPersister::new(restored.clone(), config.clone());
+        assert!(persister.restore()?);
+        let data = restored.snapshot()?;
+        assert_eq!(data.get("a"), Some(&2));
+        assert_eq!(data.get("b"), Some(&2));
+        fs::remove_file(&config.path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_without_file() -> Result<()> {
+        let metrics = MetricsAtomic::new(vec!["a".to_string()]);
+        let persister = MetricsPersister::new(metrics.clone(), temp_config());
+        assert!(!persister.restore()?);
+        assert_eq!(MetricsSnapshot::snapshot(&metrics)?.get("a"), Some(&0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_flush_appends_snapshots() -> Result<()> {
+        let config = temp_config();
+        let metrics = MetricsDashMap::new();
+        let persister = MetricsPersister::new(metrics.clone(), config.clone());
+        for _ in 0..3 {
+            metrics.inc("a")?;
+            persister.flush()?;
+        }
+        let content = fs::read_to_string(&config.path)?;
+        assert_eq!(content.lines().count(), 3);
+
+        // a line cut off by a crash is skipped, and the next snapshot starts a new line
+        fs::write(&config.path, format!("{content}{{\"ts\":"))?;
+        let record = persister.latest()?.expect("snapshot written");
+        assert_eq!(record.metrics.get("a"), Some(&3));
+        metrics.inc("a")?;
+        persister.flush()?;
+        let record = persister.latest()?.expect("snapshot written");
+        assert_eq!(record.metrics.get("a"), Some(&4));
+        fs::remove_file(&config.path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_flush_compacts_the_file() -> Result<()> {
+        let config = MetricsPersistConfig {
+            max_bytes: 1024,
+            ..temp_config()
+        };
+        let metrics = MetricsDashMap::new();
+        let persister = MetricsPersister::new(metrics.clone(), config.clone());
+        for _ in 0..100 {
+            metrics.inc("a")?;
+            persister.flush()?;
+        }
+        let content = fs::read_to_string(&config.path)?;
+        assert!(content.len() as u64 <= config.max_bytes);
+        // only whole snapshots are kept, the newest one last
+        assert!(
+            content
+                .lines()
+                .all(|line| serde_json::from_str::<MetricsRecord>(line).is_ok())
+        );
+        let record = persister.latest()?.expect("snapshot written");
+        assert_eq!(record.metrics.get("a"), Some(&100));
+        fs::remove_file(&config.path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_start_flushes_until_stopped() -> Result<()> {
+        let config = MetricsPersistConfig {
+            interval: Duration::from_secs(3600),
+            ..temp_config()
+        };
+        let metrics = MetricsRwLock::new();
+        metrics.inc("a")?;
+        let flusher = MetricsPersister::new(metrics, config.clone()).start();
+        // stopping flushes once more, without waiting for the interval
+        flusher.stop()?;
+
+        let restored = MetricsAtomic::new(vec!["a".to_string()]);
+        assert!(MetricsPersister::new(restored.clone(), config.clone()).restore()?);
+        assert_eq!(
+            restored.get("a").map(|v| v.load(Ordering::Relaxed)),
+            Some(1)
+        );
+        fs::remove_file(&config.path)?;
+        Ok(())
+    }
+}
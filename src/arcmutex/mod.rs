@@ -2,7 +2,9 @@ mod metrics_mutex;
 mod metrics_rwlock;
 mod metrics_dashmap;
 mod metrics_atomic;
+mod metrics_persist;
 pub use metrics_mutex::*;
 pub use metrics_rwlock::*;
 pub use metrics_dashmap::*;
-pub use metrics_atomic::*;
\ No newline at end of file
+pub use metrics_atomic::*;
+pub use metrics_persist::*;
\ No newline at end of file
@@ -0,0 +1,194 @@
+use std::collections::HashSet;
+
+use argon2::{
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+    password_hash::{SaltString, rand_core::OsRng},
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{DateTime, Utc};
+use jwt_simple::prelude::*;
+use sha2::{Digest, Sha256};
+
+use crate::{AppError, FileLink, Jwk, JwtUser, WorkspaceInvite};
+
+// access tokens are short lived, clients keep the session with the refresh token
+pub const ACCESS_TOKEN_DURATION: u64 = 60 * 15;
+pub const REFRESH_TOKEN_DURATION: u64 = 60 * 60 * 24 * 30;
+pub const WORKSPACE_INVITE_DURATION: u64 = 60 * 60 * 24 * 7;
+const JWT_ISS: &str = "chat_server";
+const JWT_AUD: &str = "chat_client";
+// download links are tokens for another audience, so neither kind passes for the other
+const FILE_LINK_AUD: &str = "chat_file";
+const WORKSPACE_INVITE_AUD: &str = "chat_workspace_invite";
+
+#[derive(Clone)]
+pub struct EncodingKey(Ed25519KeyPair);
+#[derive(Clone)]
+pub struct DecodingKey(Ed25519PublicKey);
+
+// what a verified access token carries, `jti` and `expires_at` are needed to revoke it
+#[derive(Debug, Clone)]
+pub struct TokenClaims {
+    pub user: JwtUser,
+    pub jti: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+pub fn hash_password_with_argon2(password: &str) -> Result<String, AppError> {
+    let salt = SaltString::generate(&mut OsRng);
+    let argon2 = Argon2::default();
+    let password_hash = argon2
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| AppError::HashPasswordError(format!("hash password failed: {e}")))?
+        .to_string();
+    Ok(password_hash)
+}
+
+pub fn verify_password_with_argon2(password: &str, password_hash: &str) -> bool {
+    let parsed = match PasswordHash::new(password_hash) {
+        Ok(p) => p,
+        Err(_) => return false,
+    };
+
+    Argon2::default()
+        .verify_password(password.as_bytes(), &parsed)
+        .is_ok()
+}
+
+// refresh tokens are opaque random strings, the server only stores their hash
+pub fn generate_refresh_token() -> String {
+    nanoid::nanoid!(48)
+}
+
+// hex encoded sha-256, as long as refresh_tokens.token_hash
+pub fn hash_refresh_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+impl EncodingKey {
+    pub fn from_pem(pem: &str, kid: &str) -> Result<Self, AppError> {
+        let key = Ed25519KeyPair::from_pem(pem)
+            .map_err(|e| AppError::JwtLoadError(format!("parse encoding key {kid} failed: {e}")))?;
+        Ok(Self(key.with_key_id(kid)))
+    }
+
+    pub fn kid(&self) -> &str {
+        self.0.key_id().as_deref().unwrap_or_default()
+    }
+
+    pub fn decoding_key(&self) -> DecodingKey {
+        DecodingKey(self.0.public_key())
+    }
+
+    // the kid goes into the jwt header, so verifiers know which public key to use
+    pub fn sign(&self, jwt_user: JwtUser) -> Result<String, AppError> {
+        let claims =
+            Claims::with_custom_claims(jwt_user, Duration::from_secs(ACCESS_TOKEN_DURATION))
+                .with_issuer(JWT_ISS)
+                .with_audience(JWT_AUD)
+                .with_jwt_id(nanoid::nanoid!());
+        self.0
+            .sign(claims)
+            .map_err(|e| AppError::JwtSignError(format!("sign jwt failed: {e}")))
+    }
+
+    pub fn sign_file_link(&self, link: FileLink, ttl: u64) -> Result<String, AppError> {
+        let claims = Claims::with_custom_claims(link, Duration::from_secs(ttl))
+            .with_issuer(JWT_ISS)
+            .with_audience(FILE_LINK_AUD);
+        self.0
+            .sign(claims)
+            .map_err(|e| AppError::JwtSignError(format!("sign file link failed: {e}")))
+    }
+
+    pub fn sign_workspace_invite(&self, invite: WorkspaceInvite) -> Result<String, AppError> {
+        let claims =
+            Claims::with_custom_claims(invite, Duration::from_secs(WORKSPACE_INVITE_DURATION))
+                .with_issuer(JWT_ISS)
+                .with_audience(WORKSPACE_INVITE_AUD);
+        self.0
+            .sign(claims)
+            .map_err(|e| AppError::JwtSignError(format!("sign workspace invite failed: {e}")))
+    }
+
+    #[cfg(test)]
+    pub fn generate(kid: &str) -> Self {
+        Self(Ed25519KeyPair::generate().with_key_id(kid))
+    }
+}
+
+impl DecodingKey {
+    pub fn from_pem(pem: &str, kid: &str) -> Result<Self, AppError> {
+        let key = Ed25519PublicKey::from_pem(pem)
+            .map_err(|e| AppError::JwtLoadError(format!("parse decoding key {kid} failed: {e}")))?;
+        Ok(Self(key.with_key_id(kid)))
+    }
+
+    pub fn kid(&self) -> &str {
+        self.0.key_id().as_deref().unwrap_or_default()
+    }
+
+    // the key as a json web key, for other services verifying our tokens
+    pub fn jwk(&self) -> Jwk {
+        Jwk {
+            kty: "OKP".into(),
+            crv: "Ed25519".into(),
+            alg: "EdDSA".into(),
+            key_use: "sig".into(),
+            kid: self.kid().to_string(),
+            x: URL_SAFE_NO_PAD.encode(self.0.to_bytes()),
+        }
+    }
+
+    // only checks the signature and the claims, revocation is checked by AppState::verify_token
+    pub fn verify(&self, token: &str) -> Result<TokenClaims, AppError> {
+        let opts = VerificationOptions {
+            allowed_issuers: Some(HashSet::from_strings(&[JWT_ISS])),
+            allowed_audiences: Some(HashSet::from_strings(&[JWT_AUD])),
+            ..Default::default()
+        };
+        let claims = self
+            .0
+            .verify_token::<JwtUser>(token, Some(opts))
+            .map_err(|e| AppError::JwtVerifyError(format!("verify jwt failed: {e}")))?;
+        let (Some(jti), Some(expires_at)) = (claims.jwt_id, claims.expires_at) else {
+            return Err(AppError::JwtVerifyError("jwt without jti or exp".into()));
+        };
+        Ok(TokenClaims {
+            user: claims.custom,
+            jti,
+            expires_at: DateTime::from_timestamp(expires_at.as_secs() as i64, 0)
+                .unwrap_or_else(Utc::now),
+        })
+    }
+
+    // links are short lived, they expire on time without the usual clock tolerance
+    pub fn verify_file_link(&self, token: &str) -> Result<FileLink, AppError> {
+        let opts = VerificationOptions {
+            allowed_issuers: Some(HashSet::from_strings(&[JWT_ISS])),
+            allowed_audiences: Some(HashSet::from_strings(&[FILE_LINK_AUD])),
+            time_tolerance: Some(Duration::from_secs(0)),
+            ..Default::default()
+        };
+        let claims = self
+            .0
+            .verify_token::<FileLink>(token, Some(opts))
+            .map_err(|e| AppError::JwtVerifyError(format!("verify file link failed: {e}")))?;
+        Ok(claims.custom)
+    }
+
+    pub fn verify_workspace_invite(&self, token: &str) -> Result<WorkspaceInvite, AppError> {
+        let opts = VerificationOptions {
+            allowed_issuers: Some(HashSet::from_strings(&[JWT_ISS])),
+            allowed_audiences: Some(HashSet::from_strings(&[WORKSPACE_INVITE_AUD])),
+            ..Default::default()
+        };
+        let claims = self
+            .0
+            .verify_token::<WorkspaceInvite>(token, Some(opts))
+            .map_err(|e| {
+                AppError::JwtVerifyError(format!("verify workspace invite failed: {e}"))
+            })?;
+        Ok(claims.custom)
+    }
+}
@@ -0,0 +1,283 @@
+use std::{collections::BTreeMap, env, fs::File, path::PathBuf};
+
+use serde::Deserialize;
+
+use crate::AppError;
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct AppConfig {
+    #[serde(default)]
+    pub server: ServerConfig,
+    pub database: Option<DatabaseConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ServerConfig {
+    #[serde(default = "default_addr")]
+    pub addr: String,
+    #[serde(default = "default_base_dir")]
+    pub base_dir: PathBuf,
+    #[serde(default)]
+    pub storage: StorageKind,
+    // `{kid}.pem` signing keys, `{kid}.pub.pem` verify-only keys, `current` names the signer
+    #[serde(default = "default_key_dir")]
+    pub key_dir: PathBuf,
+    #[serde(default)]
+    pub upload: UploadConfig,
+    // where the content of chat files is kept, uploads are staged below base_dir either way
+    #[serde(default)]
+    pub blobs: BlobStorageConfig,
+    // how long a download link is valid, in seconds
+    #[serde(default = "default_file_link_ttl")]
+    pub file_link_ttl: u64,
+    // file contents are encrypted at rest when this is set
+    #[serde(default)]
+    pub encryption: Option<EncryptionConfig>,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+}
+
+// token buckets per route group, a group set to null is not limited
+#[derive(Deserialize, Debug, Clone)]
+pub struct RateLimitConfig {
+    // sign up, sign in and refresh, per client ip
+    #[serde(default = "default_auth_rate_limit")]
+    pub auth: Option<RateLimit>,
+    // the /users/message routes, per user
+    #[serde(default = "default_message_rate_limit")]
+    pub messages: Option<RateLimit>,
+    // the other routes of signed in users, per user
+    #[serde(default = "default_api_rate_limit")]
+    pub api: Option<RateLimit>,
+    // take the client ip from the last X-Forwarded-For entry, only behind a proxy that sets it
+    #[serde(default)]
+    pub trust_forwarded_for: bool,
+    // how often buckets that filled up again are dropped, in seconds
+    #[serde(default = "default_rate_limit_cleanup")]
+    pub cleanup_interval: u64,
+}
+
+// `burst` requests at once, refilled at `per_second` requests a second
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum BlobStorageConfig {
+    // below base_dir unless `dir` is given
+    Local {
+        #[serde(default)]
+        dir: Option<PathBuf>,
+    },
+    S3(S3Config),
+}
+
+// master keys by id, as base64 encoded 32 byte keys. file keys are wrapped by `current`, the
+// other keys are kept to read the files wrapped by them until they are rotated
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct EncryptionConfig {
+    pub current: String,
+    pub keys: BTreeMap<String, String>,
+}
+
+// any S3 compatible service, objects are addressed path style as {endpoint}/{bucket}/{key}
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct S3Config {
+    // e.g. https://s3.eu-west-1.amazonaws.com, or http://localhost:9000 for minio
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+// limits for chat file uploads, sizes are in bytes
+#[derive(Deserialize, Debug, Clone)]
+pub struct UploadConfig {
+    #[serde(default = "default_max_file_size")]
+    pub max_file_size: u64,
+    // all the files of one upload request together
+    #[serde(default = "default_max_request_size")]
+    pub max_request_size: u64,
+    #[serde(default = "default_max_files")]
+    pub max_files: usize,
+    // checked against the type sniffed from the content, `image/*` allows a whole family
+    #[serde(default = "default_allowed_types")]
+    pub allowed_types: Vec<String>,
+    // total size of the files a user uploaded, and of the files stored for a chat
+    #[serde(default = "default_user_quota")]
+    pub user_quota: u64,
+    #[serde(default = "default_chat_quota")]
+    pub chat_quota: u64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct DatabaseConfig {
+    pub url: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageKind {
+    #[default]
+    Memory,
+    Postgres,
+}
+
+fn default_addr() -> String {
+    "127.0.0.1:8080".to_string()
+}
+
+fn default_base_dir() -> PathBuf {
+    "/tmp/chat_server".into()
+}
+
+fn default_key_dir() -> PathBuf {
+    "keys".into()
+}
+
+fn default_file_link_ttl() -> u64 {
+    60 * 5
+}
+
+fn default_auth_rate_limit() -> Option<RateLimit> {
+    Some(RateLimit {
+        burst: 10,
+        per_second: 0.1,
+    })
+}
+
+fn default_message_rate_limit() -> Option<RateLimit> {
+    Some(RateLimit {
+        burst: 20,
+        per_second: 2.0,
+    })
+}
+
+fn default_api_rate_limit() -> Option<RateLimit> {
+    Some(RateLimit {
+        burst: 60,
+        per_second: 10.0,
+    })
+}
+
+fn default_rate_limit_cleanup() -> u64 {
+    60
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_string()
+}
+
+fn default_max_file_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_max_request_size() -> u64 {
+    50 * 1024 * 1024
+}
+
+fn default_max_files() -> usize {
+    10
+}
+
+fn default_allowed_types() -> Vec<String> {
+    [
+        "image/*",
+        "audio/*",
+        "video/*",
+        "text/plain",
+        "application/pdf",
+        "application/zip",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect()
+}
+
+fn default_user_quota() -> u64 {
+    1024 * 1024 * 1024
+}
+
+fn default_chat_quota() -> u64 {
+    5 * 1024 * 1024 * 1024
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            addr: default_addr(),
+            base_dir: default_base_dir(),
+            storage: StorageKind::default(),
+            key_dir: default_key_dir(),
+            upload: UploadConfig::default(),
+            blobs: BlobStorageConfig::default(),
+            file_link_ttl: default_file_link_ttl(),
+            encryption: None,
+            rate_limit: RateLimitConfig::default(),
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            auth: default_auth_rate_limit(),
+            messages: default_message_rate_limit(),
+            api: default_api_rate_limit(),
+            trust_forwarded_for: false,
+            cleanup_interval: default_rate_limit_cleanup(),
+        }
+    }
+}
+
+impl Default for BlobStorageConfig {
+    fn default() -> Self {
+        BlobStorageConfig::Local { dir: None }
+    }
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        UploadConfig {
+            max_file_size: default_max_file_size(),
+            max_request_size: default_max_request_size(),
+            max_files: default_max_files(),
+            allowed_types: default_allowed_types(),
+            user_quota: default_user_quota(),
+            chat_quota: default_chat_quota(),
+        }
+    }
+}
+
+impl UploadConfig {
+    pub fn is_allowed(&self, mime: &str) -> bool {
+        self.allowed_types
+            .iter()
+            .any(|allowed| match allowed.strip_suffix("/*") {
+                Some(family) => mime.split('/').next() == Some(family),
+                None => allowed == mime,
+            })
+    }
+}
+
+impl AppConfig {
+    pub fn load() -> Result<Self, AppError> {
+        let config = match (
+            File::open("app.yml"),
+            File::open("/etc/app.yml"),
+            env::var("CHAT_CONFIG"),
+        ) {
+            (Ok(file), _, _) => serde_yaml::from_reader(file),
+            (_, Ok(file), _) => serde_yaml::from_reader(file),
+            (_, _, Ok(env_var)) => serde_yaml::from_str(&env_var),
+            _ => return Err(AppError::ConfigError("No configuration file found".into())),
+        }
+        .map_err(|e| AppError::ConfigError(format!("parse config failed: {e}")))?;
+        Ok(config)
+    }
+}
@@ -0,0 +1,117 @@
+use axum::{
+    Json,
+    http::{HeaderValue, StatusCode, header::RETRY_AFTER},
+    response::{IntoResponse, Response},
+};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum AppError {
+    #[error("{0}")]
+    CreateUserError(String),
+
+    #[error("{0}")]
+    WorkspaceExists(String),
+    #[error("{0}")]
+    HashPasswordError(String),
+    #[error("{0}")]
+    JwtSignError(String),
+    #[error("{0}")]
+    JwtLoadError(String),
+    #[error("{0}")]
+    JwtVerifyError(String),
+    #[error("{0}")]
+    UserNotFound(String),
+    #[error("{0}")]
+    PasswordVerifyError(String),
+    #[error("{0}")]
+    PermissionDenied(String),
+    #[error("{0}")]
+    CreateChatError(String),
+    #[error("{0}")]
+    ChatNotFound(String),
+    #[error("{0}")]
+    JoinChatError(String),
+    #[error("{0}")]
+    NotChatMember(String),
+    #[error("{0}")]
+    AlreadyChatMember(String),
+    #[error("{0}")]
+    InvitationNotFound(String),
+    #[error("{0}")]
+    MembershipError(String),
+    #[error("{0}")]
+    MessageNotFound(String),
+    #[error("{0}")]
+    MessageDeleted(String),
+    #[error("{0}")]
+    InvalidMessage(String),
+    #[error("{0}")]
+    InvalidSearchQuery(String),
+    // seconds until the client may try again
+    #[error("too many requests, retry after {0} seconds")]
+    RateLimited(u64),
+    #[error("{0}")]
+    UploadFileError(String),
+    #[error("{0}")]
+    FileReadError(#[from] std::io::Error),
+    #[error("{0}")]
+    FileNotFound(String),
+    #[error("{0}")]
+    InvalidFilePath(String),
+    #[error("{0}")]
+    StorageError(String),
+    #[error("{0}")]
+    ConfigError(String),
+    #[error("{0}")]
+    DatabaseError(#[from] sqlx::Error),
+    #[error("{0}")]
+    MigrateError(#[from] sqlx::migrate::MigrateError),
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            Self::CreateUserError(_) => StatusCode::BAD_REQUEST,
+            Self::WorkspaceExists(_) => StatusCode::CONFLICT,
+            Self::HashPasswordError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::JwtSignError(_) => StatusCode::BAD_REQUEST,
+            Self::JwtLoadError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::JwtVerifyError(_) => StatusCode::UNAUTHORIZED,
+            Self::UserNotFound(_) => StatusCode::NOT_FOUND,
+            Self::PasswordVerifyError(_) => StatusCode::UNAUTHORIZED,
+            Self::PermissionDenied(_) => StatusCode::FORBIDDEN,
+            Self::CreateChatError(_) => StatusCode::BAD_REQUEST,
+            Self::ChatNotFound(_) => StatusCode::NOT_FOUND,
+            Self::JoinChatError(_) => StatusCode::BAD_REQUEST,
+            Self::NotChatMember(_) => StatusCode::FORBIDDEN,
+            Self::AlreadyChatMember(_) => StatusCode::CONFLICT,
+            Self::InvitationNotFound(_) => StatusCode::NOT_FOUND,
+            Self::MembershipError(_) => StatusCode::CONFLICT,
+            Self::MessageNotFound(_) => StatusCode::NOT_FOUND,
+            Self::MessageDeleted(_) => StatusCode::GONE,
+            Self::InvalidMessage(_) => StatusCode::BAD_REQUEST,
+            Self::InvalidSearchQuery(_) => StatusCode::BAD_REQUEST,
+            Self::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            Self::UploadFileError(_) => StatusCode::BAD_REQUEST,
+            Self::FileReadError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::FileNotFound(_) => StatusCode::NOT_FOUND,
+            Self::InvalidFilePath(_) => StatusCode::BAD_REQUEST,
+            Self::StorageError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::ConfigError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::MigrateError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let retry_after = match &self {
+            Self::RateLimited(secs) => Some(*secs),
+            _ => None,
+        };
+        let mut res =
+            (status, Json(serde_json::json!({"error": self.to_string()}))).into_response();
+        if let Some(secs) = retry_after {
+            res.headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(secs));
+        }
+        res
+    }
+}
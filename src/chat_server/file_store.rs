@@ -0,0 +1,400 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use chrono::Utc;
+use tokio::{
+    fs,
+    sync::{Mutex, MutexGuard},
+    task::JoinHandle,
+};
+use tracing::{info, warn};
+
+use crate::{
+    AppError, BlobObject, BlobStorage, ChatStore, FileId, FileMeta, FileQuota, FileRecord,
+    is_blob_hash,
+};
+
+// uploads are streamed into {base_dir}/tmp first, the storage takes them once they are
+// complete and hashed
+pub(crate) const TMP_DIR: &str = "tmp";
+const BLOB_PREFIX: &str = "blobs";
+const THUMB_PREFIX: &str = "thumbs";
+// how often unused blobs are collected
+const BLOB_GC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// a blob is only collected once its object is this old, so the blob of an upload that is not
+// indexed yet is left alone
+const BLOB_GC_GRACE: Duration = Duration::from_secs(60 * 60);
+// uploads and the collector take turns on a blob, one lock stands for the blobs whose hashes
+// fall on it
+const BLOB_LOCKS: usize = 64;
+
+// the files of all chats by content. every blob is stored once under the key
+// blobs/{sha256[..2]}/{sha256[2..4]}/{sha256} of the blob storage, the chat store indexes
+// which chats use it and counts the references, blobs nobody uses any more are garbage
+// collected. the thumbnail of a blob is kept the same way below thumbs/ and goes with it
+pub struct FileStore {
+    storage: Arc<dyn BlobStorage>,
+    tmp_dir: PathBuf,
+    store: Arc<dyn ChatStore>,
+    locks: [Mutex<()>; BLOB_LOCKS],
+}
+
+impl FileStore {
+    pub fn new(base_dir: &Path, storage: Arc<dyn BlobStorage>, store: Arc<dyn ChatStore>) -> Self {
+        Self {
+            storage,
+            tmp_dir: base_dir.join(TMP_DIR),
+            store,
+            locks: std::array::from_fn(|_| Mutex::new(())),
+        }
+    }
+
+    async fn lock(&self, hash: &str) -> MutexGuard<'_, ()> {
+        self.locks[Self::lock_index(hash)].lock().await
+    }
+
+    fn lock_index(hash: &str) -> usize {
+        hash.bytes()
+            .fold(0usize, |h, b| h.wrapping_mul(31).wrapping_add(b as usize))
+            % BLOB_LOCKS
+    }
+
+    // a new empty file to stream an upload into
+    pub async fn temp_file(&self) -> Result<(PathBuf, fs::File), AppError> {
+        fs::create_dir_all(&self.tmp_dir).await?;
+        let path = self.tmp_dir.join(nanoid::nanoid!());
+        let file = fs::File::create(&path).await?;
+        Ok((path, file))
+    }
+
+    fn blob_key(hash: &str) -> Result<String, AppError> {
+        Self::key(BLOB_PREFIX, hash)
+    }
+
+    fn thumb_key(hash: &str) -> Result<String, AppError> {
+        Self::key(THUMB_PREFIX, hash)
+    }
+
+    fn key(prefix: &str, hash: &str) -> Result<String, AppError> {
+        if !is_blob_hash(hash) {
+            return Err(AppError::InvalidFilePath(format!(
+                "invalid blob hash {hash:?}"
+            )));
+        }
+        Ok(format!("{prefix}/{}/{}/{hash}", &hash[..2], &hash[2..4]))
+    }
+
+    // store a complete temp file as the blob of its hash, unless the same content is there
+    // already. it is not stored again, a download of it could be under way. the caller holds
+    // the blob's lock until it is indexed, so the collector does not take it meanwhile
+    async fn put_blob(&self, tmp_path: &Path, hash: &str) -> Result<(), AppError> {
+        let key = Self::blob_key(hash)?;
+        if self.storage.size(&key).await?.is_some() {
+            fs::remove_file(tmp_path).await?;
+            return Ok(());
+        }
+        self.storage.put_file(&key, tmp_path).await
+    }
+
+    pub async fn open_blob(&self, hash: &str) -> Result<BlobObject, AppError> {
+        BlobObject::open(self.storage.clone(), &Self::blob_key(hash)?).await
+    }
+
+    pub async fn put_thumb(&self, hash: &str, thumb: &[u8]) -> Result<(), AppError> {
+        self.storage
+            .put(&Self::thumb_key(hash)?, thumb.to_vec())
+            .await
+    }
+
+    pub async fn open_thumb(&self, hash: &str) -> Result<BlobObject, AppError> {
+        BlobObject::open(self.storage.clone(), &Self::thumb_key(hash)?).await
+    }
+
+    pub async fn meta(&self, hash: &str) -> Result<Option<FileMeta>, AppError> {
+        self.store.get_file_meta(hash).await
+    }
+
+    pub async fn set_meta(&self, hash: &str, meta: FileMeta) -> Result<(), AppError> {
+        self.store.set_file_meta(hash, meta).await
+    }
+
+    // return false if the chat already has the file
+    pub async fn add(&self, record: FileRecord) -> Result<bool, AppError> {
+        self.store.add_chat_file(record).await
+    }
+
+    // store the blobs of one upload from their temp files and add the files if they fit in the
+    // quotas, all of them or none. the collector keeps off the blobs until they are indexed,
+    // the ones no chat uses are removed again when the files do not fit
+    pub async fn add_upload(
+        &self,
+        files: Vec<(PathBuf, FileRecord)>,
+        quota: FileQuota,
+    ) -> Result<(), AppError> {
+        let mut locks = files
+            .iter()
+            .map(|(_, record)| Self::lock_index(&record.file.hash))
+            .collect::<Vec<_>>();
+        // always in the same order, uploads sharing blobs do not wait on each other forever
+        locks.sort_unstable();
+        locks.dedup();
+        let mut _guards = vec![];
+        for idx in locks {
+            _guards.push(self.locks[idx].lock().await);
+        }
+        let mut hashes = vec![];
+        let mut records = vec![];
+        for (tmp_path, record) in files {
+            self.put_blob(&tmp_path, &record.file.hash).await?;
+            hashes.push(record.file.hash.clone());
+            records.push(record);
+        }
+        if let Err(e) = self.store.add_chat_files(records, quota).await {
+            for hash in hashes {
+                if self.store.delete_blob(&hash).await? {
+                    self.delete_objects(&hash).await?;
+                }
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub async fn get(&self, file: &FileId) -> Result<Option<FileRecord>, AppError> {
+        self.store.get_chat_file(file).await
+    }
+
+    // remove the blobs no chat uses whose objects are older than `grace`, including objects
+    // that never made it into the index, return how many were removed
+    pub async fn gc(&self, grace: Duration) -> Result<usize, AppError> {
+        let cutoff = Utc::now() - grace;
+        let mut removed = 0;
+        for blob in self.storage.list(&format!("{BLOB_PREFIX}/")).await? {
+            if blob.modified > cutoff {
+                continue;
+            }
+            // objects that are not blobs are none of the collector's business
+            let Some((_, hash)) = blob.key.rsplit_once('/') else {
+                continue;
+            };
+            if !is_blob_hash(hash) {
+                continue;
+            }
+            // an upload of the blob is either indexed by now or stores the blob again after
+            let _lock = self.lock(hash).await;
+            if self.store.delete_blob(hash).await? {
+                self.delete_objects(hash).await?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    async fn delete_objects(&self, hash: &str) -> Result<(), AppError> {
+        self.storage.delete(&Self::blob_key(hash)?).await?;
+        self.storage.delete(&Self::thumb_key(hash)?).await
+    }
+
+    // collect unused blobs every BLOB_GC_INTERVAL
+    pub fn spawn_gc(self: Arc<Self>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(BLOB_GC_INTERVAL).await;
+                match self.gc(BLOB_GC_GRACE).await {
+                    Ok(0) => {}
+                    Ok(n) => info!("removed {n} unused blobs"),
+                    Err(e) => warn!("blob gc failed: {e}"),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use chrono::Utc;
+    use tokio::sync::Notify;
+
+    use super::*;
+    use crate::{
+        BlobInfo, BlobReader, EncryptedStorage, EncryptionConfig, LocalStorage, MasterKeys,
+        MemoryStore, S3Storage, s3_stand_in,
+    };
+
+    // once paused, the next upload waits for `resume` after it looked for its blob or stored it
+    struct PausingStorage {
+        inner: LocalStorage,
+        paused: AtomicBool,
+        stored: Notify,
+        resume: Notify,
+    }
+
+    impl PausingStorage {
+        async fn pause(&self) {
+            if self.paused.swap(false, Ordering::SeqCst) {
+                self.stored.notify_one();
+                self.resume.notified().await;
+            }
+        }
+    }
+
+    #[async_trait]
+    impl BlobStorage for PausingStorage {
+        async fn put_file(&self, key: &str, path: &Path) -> Result<(), AppError> {
+            self.inner.put_file(key, path).await?;
+            self.pause().await;
+            Ok(())
+        }
+
+        async fn put(&self, key: &str, content: Vec<u8>) -> Result<(), AppError> {
+            self.inner.put(key, content).await
+        }
+
+        async fn size(&self, key: &str) -> Result<Option<u64>, AppError> {
+            let size = self.inner.size(key).await?;
+            self.pause().await;
+            Ok(size)
+        }
+
+        async fn read(&self, key: &str, start: u64, end: u64) -> Result<BlobReader, AppError> {
+            self.inner.read(key, start, end).await
+        }
+
+        async fn delete(&self, key: &str) -> Result<(), AppError> {
+            self.inner.delete(key).await
+        }
+
+        async fn list(&self, prefix: &str) -> Result<Vec<BlobInfo>, AppError> {
+            self.inner.list(prefix).await
+        }
+    }
+
+    fn record(chat_id: u32, hash: &str) -> FileRecord {
+        FileRecord {
+            file: FileId {
+                chat_id,
+                hash: hash.to_string(),
+                ext: "txt".into(),
+            },
+            size: 5,
+            mime: "text/plain".into(),
+            uploader_id: 1,
+            created_at: Utc::now(),
+        }
+    }
+
+    async fn put(files: &FileStore, hash: &str) -> Result<()> {
+        let (tmp_path, _) = files.temp_file().await?;
+        fs::write(&tmp_path, "hello").await?;
+        files.put_blob(&tmp_path, hash).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_blobs_are_shared_and_collected() -> Result<()> {
+        let base_dir = std::env::temp_dir().join(format!("chat_files_{}", nanoid::nanoid!()));
+        let local = Arc::new(LocalStorage::new(&base_dir));
+        blobs_are_shared_and_collected(&base_dir, local).await?;
+        let s3 = Arc::new(S3Storage::new(s3_stand_in().await?)?);
+        blobs_are_shared_and_collected(&base_dir, s3.clone()).await?;
+        let keys = MasterKeys::from_config(&EncryptionConfig {
+            current: "k1".into(),
+            keys: [("k1".into(), MasterKeys::generate())].into(),
+        })?;
+        let encrypted = Arc::new(EncryptedStorage::new(s3, keys, base_dir.join(TMP_DIR)));
+        blobs_are_shared_and_collected(&base_dir, encrypted).await
+    }
+
+    async fn blobs_are_shared_and_collected(
+        base_dir: &Path,
+        storage: Arc<dyn BlobStorage>,
+    ) -> Result<()> {
+        let store = Arc::new(MemoryStore::new());
+        let files = FileStore::new(base_dir, storage.clone(), store.clone());
+        let shared = "a".repeat(64);
+        let stray = "b".repeat(64);
+
+        // the same content in two chats is one blob with two references
+        for chat_id in [1, 2] {
+            put(&files, &shared).await?;
+            assert!(files.add(record(chat_id, &shared)).await?);
+        }
+        assert!(!files.add(record(1, &shared)).await?);
+        assert_eq!(store.blob_refcount(&shared).await?, Some(2));
+        // a blob that was stored but never indexed
+        put(&files, &stray).await?;
+        // and an object that is no blob at all
+        storage.put("blobs/x", b"stray".to_vec()).await?;
+        assert_eq!(storage.list("blobs/").await?.len(), 3);
+        assert!(files.open_blob("x").await.is_err());
+
+        // recent blobs are left alone
+        assert_eq!(files.gc(BLOB_GC_GRACE).await?, 0);
+        assert_eq!(files.gc(Duration::ZERO).await?, 1);
+        assert!(files.open_blob(&stray).await.is_err());
+
+        store.delete_chat(1).await?;
+        assert_eq!(store.blob_refcount(&shared).await?, Some(1));
+        assert_eq!(files.gc(Duration::ZERO).await?, 0);
+        files.put_thumb(&shared, b"thumb").await?;
+        store.delete_chat(2).await?;
+        assert_eq!(store.blob_refcount(&shared).await?, Some(0));
+        assert_eq!(files.gc(Duration::ZERO).await?, 1);
+        assert!(files.open_blob(&shared).await.is_err());
+        assert!(files.open_thumb(&shared).await.is_err());
+        assert_eq!(store.blob_refcount(&shared).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_gc_keeps_blobs_being_uploaded() -> Result<()> {
+        let base_dir = std::env::temp_dir().join(format!("chat_files_{}", nanoid::nanoid!()));
+        let storage = Arc::new(PausingStorage {
+            inner: LocalStorage::new(&base_dir),
+            paused: AtomicBool::new(false),
+            stored: Notify::new(),
+            resume: Notify::new(),
+        });
+        let store = Arc::new(MemoryStore::new());
+        let files = Arc::new(FileStore::new(&base_dir, storage.clone(), store.clone()));
+        let hash = "c".repeat(64);
+        let quota = FileQuota {
+            user: u64::MAX,
+            chat: u64::MAX,
+        };
+        // the blob is no longer used and up for collection
+        put(&files, &hash).await?;
+        assert!(files.add(record(1, &hash)).await?);
+        store.delete_chat(1).await?;
+        assert_eq!(store.blob_refcount(&hash).await?, Some(0));
+
+        // the same content is uploaded again, the collector runs between finding the blob
+        // and indexing it
+        storage.paused.store(true, Ordering::SeqCst);
+        let (tmp_path, _) = files.temp_file().await?;
+        fs::write(&tmp_path, "hello").await?;
+        let upload = tokio::spawn({
+            let (files, record) = (files.clone(), record(2, &hash));
+            async move { files.add_upload(vec![(tmp_path, record)], quota).await }
+        });
+        storage.stored.notified().await;
+        let gc = tokio::spawn({
+            let files = files.clone();
+            async move { files.gc(Duration::ZERO).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        storage.resume.notify_one();
+        upload.await??;
+        assert_eq!(gc.await??, 0);
+        assert_eq!(store.blob_refcount(&hash).await?, Some(1));
+        assert!(files.open_blob(&hash).await.is_ok());
+        Ok(())
+    }
+}
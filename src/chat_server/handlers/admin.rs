@@ -0,0 +1,209 @@
+use axum::{
+    Extension, Json,
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use tracing::{info, instrument};
+
+use super::remove_chat;
+use crate::{AppError, AppState, ChatEvent, JwtUser, UserTypeRequest};
+
+// the user's tokens stop working right away, AppState::verify_token checks that the user
+// still exists. admins only manage their own workspace, users of other workspaces are not found
+#[instrument(skip(state, admin))]
+pub(crate) async fn delete_user_handler(
+    State(state): State<AppState>,
+    Extension(admin): Extension<JwtUser>,
+    Path(user_id): Path<u32>,
+) -> Result<impl IntoResponse, AppError> {
+    if user_id == admin.id {
+        return Err(AppError::PermissionDenied("can not delete yourself".into()));
+    }
+    let found = state.store.get_user(user_id).await?;
+    if found.is_none_or(|user| user.ws_id != admin.ws_id) {
+        return Err(AppError::UserNotFound(format!("user {user_id} not found")));
+    }
+    let chats = state.store.list_user_chats(user_id).await?;
+    if !state.store.delete_user(user_id).await? {
+        return Err(AppError::UserNotFound(format!("user {user_id} not found")));
+    }
+    for chat in chats {
+        state.hub.publish_chat(
+            chat.id,
+            ChatEvent::Leave {
+                chat_id: chat.id,
+                user_id,
+            },
+        );
+    }
+    info!("{} deleted user {user_id}", admin.name);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// the role is in the user's tokens from the next sign in or refresh on, the tokens with the
+// old role are refused until then
+#[instrument(skip(state, admin))]
+pub(crate) async fn set_user_type_handler(
+    State(state): State<AppState>,
+    Extension(admin): Extension<JwtUser>,
+    Path(user_id): Path<u32>,
+    Json(payload): Json<UserTypeRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    if user_id == admin.id {
+        return Err(AppError::PermissionDenied(
+            "can not change your own role".into(),
+        ));
+    }
+    let Some(user) = state
+        .store
+        .get_user(user_id)
+        .await?
+        .filter(|user| user.ws_id == admin.ws_id)
+    else {
+        return Err(AppError::UserNotFound(format!("user {user_id} not found")));
+    };
+    let Some(user) = state
+        .store
+        .set_user_type(&user.email, payload.user_type)
+        .await?
+    else {
+        return Err(AppError::UserNotFound(format!("user {user_id} not found")));
+    };
+    info!("{} made user {user_id} {:?}", admin.name, payload.user_type);
+    Ok(Json(user))
+}
+
+#[instrument(skip(state, admin))]
+pub(crate) async fn delete_chat_handler(
+    State(state): State<AppState>,
+    Extension(admin): Extension<JwtUser>,
+    Path(chat_id): Path<u32>,
+) -> Result<impl IntoResponse, AppError> {
+    let Some(chat) = state
+        .store
+        .get_chat(chat_id)
+        .await?
+        .filter(|chat| chat.ws_id == admin.ws_id)
+    else {
+        return Err(AppError::ChatNotFound(format!("chat {chat_id} not found")));
+    };
+    remove_chat(&state, chat).await?;
+    info!("{} deleted chat {chat_id}", admin.name);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    use crate::chat_server::test_util::*;
+
+    #[tokio::test]
+    async fn test_admin_routes_require_admin() -> Result<()> {
+        let app = TestApp::new();
+        let (alice, _) = app.signup("alice").await?;
+        let (status, _) = app.get("/users/list", None).await?;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, body) = app.get("/users/list", Some(&alice)).await?;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["error"], "Admin role required");
+
+        let (admin, _) = app.signup_admin("root").await?;
+        let (status, users) = app.get("/users/list", Some(&admin)).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(users[1]["user_type"], "admin");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_admin_grants_role() -> Result<()> {
+        let app = TestApp::new();
+        let (admin, _) = app.signup_admin("root").await?;
+        let (alice, alice_user) = app.signup("alice").await?;
+        let role_uri = format!("/admin/users/{}/role", alice_user["id"]);
+        let (status, _) = app
+            .post(&role_uri, Some(&alice), json!({"user_type": "admin"}))
+            .await?;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, user) = app
+            .post(&role_uri, Some(&admin), json!({"user_type": "admin"}))
+            .await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(user["user_type"], "admin");
+        // the token with the old role is refused
+        let (status, _) = app.get("/users/chats", Some(&alice)).await?;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        // the role is in the token once alice signs in again
+        let (_, tokens) = app
+            .post(
+                "/users/signin",
+                None,
+                json!({"email": "alice@example.com", "password": "password123"}),
+            )
+            .await?;
+        let (status, _) = app.get("/users/list", tokens["token"].as_str()).await?;
+        assert_eq!(status, StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_admin_deletes_user_and_chat() -> Result<()> {
+        let app = TestApp::new();
+        let (admin, _) = app.signup_admin("root").await?;
+        let (alice, _) = app.signup("alice").await?;
+        let (bob, bob_user) = app.signup("bob").await?;
+        app.post("/users/create_chat", Some(&alice), json!({"name": "rust"}))
+            .await?;
+        app.post("/users/join_chat", Some(&bob), json!({"chat_id": 1}))
+            .await?;
+        app.post(
+            "/users/message/send_message/1",
+            Some(&bob),
+            json!({"content": "hello", "files": []}),
+        )
+        .await?;
+
+        let bob_uri = format!("/admin/users/{}", bob_user["id"]);
+        let (status, _) = app
+            .request(Method::DELETE, &bob_uri, Some(&alice), None)
+            .await?;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        // admins of another workspace do not see bob
+        let (eve, _) = app.signup_admin_in("eve", "globex").await?;
+        let (status, _) = app
+            .request(Method::DELETE, &bob_uri, Some(&eve), None)
+            .await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = app
+            .request(Method::DELETE, &bob_uri, Some(&admin), None)
+            .await?;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = app
+            .request(Method::DELETE, &bob_uri, Some(&admin), None)
+            .await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        // bob's token goes with him
+        let (status, _) = app
+            .post("/users/create_chat", Some(&bob), json!({"name": "go"}))
+            .await?;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (_, messages) = app.get("/users/message/1", Some(&alice)).await?;
+        assert_eq!(messages.as_array().map(Vec::len), Some(0));
+
+        let (status, _) = app
+            .request(Method::DELETE, "/admin/chats/1", Some(&admin), None)
+            .await?;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = app.get("/users/message/1", Some(&alice)).await?;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = app
+            .request(Method::DELETE, "/admin/chats/1", Some(&admin), None)
+            .await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        Ok(())
+    }
+}
@@ -0,0 +1,410 @@
+use axum::{
+    Extension, Json,
+    extract::{Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use tracing::instrument;
+
+use crate::{
+    AppError, AppState, ChatEvent, ChatType, CreateChat, CreateChatRequest, JoinChatRequest,
+    JoinOutput, JwtUser, MAX_CHAT_LIMIT, PublicChatFilter,
+};
+
+// chats.chat_name is varchar(64)
+const MAX_CHAT_NAME_LEN: usize = 64;
+
+//the user's chats with how many messages the user has not read yet
+pub(crate) async fn my_chats_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<JwtUser>,
+) -> Result<impl IntoResponse, AppError> {
+    let chats = state.store.list_user_chats_unread(user.id).await?;
+    Ok(Json(chats))
+}
+
+#[instrument(skip(state))]
+pub(crate) async fn create_chat_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<JwtUser>,
+    Json(input): Json<CreateChatRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    //the creator comes first, then the other members without duplicates
+    let mut members = vec![user.id];
+    for id in input.members {
+        if !members.contains(&id) {
+            members.push(id);
+        }
+    }
+    //members come from the creator's workspace
+    for id in &members[1..] {
+        let found = state.store.get_user(*id).await?;
+        if found.is_none_or(|member| member.ws_id != user.ws_id) {
+            return Err(AppError::UserNotFound(format!("user {id} not found")));
+        }
+    }
+    //only single chats start with both users, the others are invited and join by accepting
+    let invitees = match input.chat_type {
+        ChatType::Single => vec![],
+        _ => members.split_off(1),
+    };
+
+    let name = input
+        .name
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty());
+    let name = match (input.chat_type, name) {
+        (ChatType::Single, name) => {
+            if members.len() != 2 {
+                return Err(AppError::CreateChatError(
+                    "a single chat is between exactly two users".into(),
+                ));
+            }
+            //two users share one single chat, creating it again returns the existing one
+            let existing = state
+                .store
+                .list_user_chats(user.id)
+                .await?
+                .into_iter()
+                .find(|chat| {
+                    chat.chat_type == ChatType::Single && chat.members.contains(&members[1])
+                });
+            if let Some(chat) = existing {
+                return Ok((StatusCode::OK, Json(chat)));
+            }
+            name.unwrap_or_default()
+        }
+        (_, Some(name)) => name,
+        (_, None) => return Err(AppError::CreateChatError("chat name is required".into())),
+    };
+    if name.chars().count() > MAX_CHAT_NAME_LEN {
+        return Err(AppError::CreateChatError(format!(
+            "chat name is longer than {MAX_CHAT_NAME_LEN} characters"
+        )));
+    }
+
+    let chat = state
+        .store
+        .create_chat(CreateChat {
+            ws_id: user.ws_id,
+            name,
+            chat_type: input.chat_type,
+            owner_id: user.id,
+            members,
+        })
+        .await?;
+    //open connections of the members start following the new chat
+    for user_id in &chat.members {
+        state.hub.publish_user(
+            *user_id,
+            ChatEvent::Join {
+                chat_id: chat.id,
+                user_id: *user_id,
+            },
+        );
+    }
+    for user_id in invitees {
+        state
+            .store
+            .create_invitation(chat.id, user_id, user.id)
+            .await?;
+        state.hub.publish_user(
+            user_id,
+            ChatEvent::Invited {
+                chat_id: chat.id,
+                user_id,
+                inviter_id: user.id,
+            },
+        );
+    }
+    Ok((StatusCode::CREATED, Json(chat)))
+}
+
+#[instrument(skip(state))]
+pub(crate) async fn join_chat_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<JwtUser>,
+    Json(join_chat): Json<JoinChatRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    //chats of other workspaces do not exist for the user
+    let Some(chat) = state
+        .store
+        .get_chat(join_chat.chat_id)
+        .await?
+        .filter(|chat| chat.ws_id == user.ws_id)
+    else {
+        return Err(AppError::JoinChatError("chat id not found".into()));
+    };
+    match chat.chat_type {
+        ChatType::Single => {
+            return Err(AppError::PermissionDenied(
+                "single chats can not be joined".into(),
+            ));
+        }
+        ChatType::PrivateChannel if !chat.members.contains(&user.id) => {
+            return Err(AppError::PermissionDenied(
+                "private channels can only be joined by invitation".into(),
+            ));
+        }
+        _ => {}
+    }
+    let already_joined = chat.members.contains(&user.id);
+    if state
+        .store
+        .add_chat_member(join_chat.chat_id, user.id)
+        .await?
+        .is_none()
+    {
+        return Err(AppError::JoinChatError("chat id not found".into()));
+    }
+    if !already_joined {
+        let event = ChatEvent::Join {
+            chat_id: join_chat.chat_id,
+            user_id: user.id,
+        };
+        state.hub.publish_chat(join_chat.chat_id, event.clone());
+        state.hub.publish_user(user.id, event);
+    }
+    Ok((
+        StatusCode::OK,
+        Json(JoinOutput {
+            message: format!("join the chat {} successfully", join_chat.chat_id),
+        }),
+    ))
+}
+
+//public channels, optionally filtered by a part of the name
+#[instrument(skip(state))]
+pub(crate) async fn public_chats_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<JwtUser>,
+    Query(filter): Query<PublicChatFilter>,
+) -> Result<impl IntoResponse, AppError> {
+    let query = filter.q.as_deref().map(str::trim).filter(|q| !q.is_empty());
+    let limit = filter.limit.clamp(1, MAX_CHAT_LIMIT);
+    let chats = state
+        .store
+        .search_public_chats(user.ws_id, query, limit)
+        .await?;
+    Ok(Json(chats))
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    use crate::chat_server::test_util::*;
+
+    #[tokio::test]
+    async fn test_create_and_join_chat() -> Result<()> {
+        let app = TestApp::new();
+        let (alice, _) = app.signup("alice").await?;
+        let (bob, bob_user) = app.signup("bob").await?;
+
+        let (status, chat) = app
+            .post("/users/create_chat", Some(&alice), json!({"name": "rust"}))
+            .await?;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(chat["name"], "rust");
+        assert_eq!(chat["chat_type"], "group");
+        let chat_id = chat["id"].as_u64().unwrap() as u32;
+
+        let (status, other) = app
+            .post("/users/create_chat", Some(&bob), json!({"name": "go"}))
+            .await?;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_ne!(other["id"], chat["id"]);
+        let (status, _) = app
+            .post("/users/create_chat", Some(&bob), json!({"name": " "}))
+            .await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = app
+            .post("/users/join_chat", Some(&bob), json!({"chat_id": chat_id}))
+            .await?;
+        assert_eq!(status, StatusCode::OK);
+        let chat = app
+            .state
+            .store
+            .get_chat(chat_id)
+            .await?
+            .expect("chat exists");
+        assert!(
+            chat.members
+                .contains(&(bob_user["id"].as_u64().unwrap() as u32))
+        );
+
+        let (status, _) = app
+            .post("/users/join_chat", Some(&bob), json!({"chat_id": 100}))
+            .await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_single_chat_is_between_two_users() -> Result<()> {
+        let app = TestApp::new();
+        let (alice, _) = app.signup("alice").await?;
+        let (bob, bob_user) = app.signup("bob").await?;
+        let (tom, tom_user) = app.signup("tom").await?;
+
+        let single = json!({"chat_type": "single", "members": [bob_user["id"]]});
+        let (status, chat) = app
+            .post("/users/create_chat", Some(&alice), single.clone())
+            .await?;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(chat["members"].as_array().map(Vec::len), Some(2));
+        // the same pair gets the same chat back, from either side
+        let (status, again) = app.post("/users/create_chat", Some(&alice), single).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(again["id"], chat["id"]);
+
+        let (status, _) = app
+            .post(
+                "/users/create_chat",
+                Some(&alice),
+                json!({"chat_type": "single", "members": [bob_user["id"], tom_user["id"]]}),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = app
+            .post(
+                "/users/join_chat",
+                Some(&tom),
+                json!({"chat_id": chat["id"]}),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = app
+            .post(
+                "/users/message/send_message/1",
+                Some(&bob),
+                json!({"content": "hi", "files": []}),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_private_and_public_channels() -> Result<()> {
+        let app = TestApp::new();
+        let (alice, _) = app.signup("alice").await?;
+        let (bob, bob_user) = app.signup("bob").await?;
+        let (tom, _) = app.signup("tom").await?;
+
+        let (_, private) = app
+            .post(
+                "/users/create_chat",
+                Some(&alice),
+                json!({"chat_type": "private_channel", "name": "team", "members": [bob_user["id"]]}),
+            )
+            .await?;
+        // the listed members are invited, not added
+        assert_eq!(private["members"].as_array().map(Vec::len), Some(1));
+        for token in [&tom, &bob] {
+            let (status, _) = app
+                .post(
+                    "/users/join_chat",
+                    Some(token),
+                    json!({"chat_id": private["id"]}),
+                )
+                .await?;
+            assert_eq!(status, StatusCode::FORBIDDEN);
+        }
+        let (status, _) = app
+            .post(
+                &format!("/users/invitations/{}/accept", private["id"]),
+                Some(&bob),
+                json!({}),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::OK);
+
+        for name in ["Rust News", "rustaceans", "go"] {
+            app.post(
+                "/users/create_chat",
+                Some(&alice),
+                json!({"chat_type": "public_channel", "name": name}),
+            )
+            .await?;
+        }
+        let (status, chats) = app.get("/users/public_chats?q=RUST", Some(&tom)).await?;
+        assert_eq!(status, StatusCode::OK);
+        let names = chats
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| c["name"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["Rust News", "rustaceans"]);
+        let (_, chats) = app.get("/users/public_chats?limit=1", Some(&tom)).await?;
+        assert_eq!(chats.as_array().map(Vec::len), Some(1));
+
+        let (status, _) = app
+            .post(
+                "/users/join_chat",
+                Some(&tom),
+                json!({"chat_id": chats[0]["id"]}),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_workspaces_are_isolated() -> Result<()> {
+        let app = TestApp::new();
+        let (alice, alice_user) = app.signup("alice").await?;
+        let (eve, eve_user) = app.signup_in("eve", "globex").await?;
+        assert_ne!(alice_user["ws_id"], eve_user["ws_id"]);
+        let ws = app
+            .state
+            .store
+            .find_workspace_by_name("globex")
+            .await?
+            .expect("workspace exists");
+        assert_eq!(ws.owner_id.map(u64::from), eve_user["id"].as_u64());
+
+        let (status, chat) = app
+            .post(
+                "/users/create_chat",
+                Some(&alice),
+                json!({"chat_type": "public_channel", "name": "rust"}),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(chat["ws_id"], alice_user["ws_id"]);
+        let (_, chats) = app.get("/users/public_chats", Some(&eve)).await?;
+        assert_eq!(chats.as_array().map(Vec::len), Some(0));
+        let (status, _) = app
+            .post(
+                "/users/join_chat",
+                Some(&eve),
+                json!({"chat_id": chat["id"]}),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = app
+            .post(
+                "/users/create_chat",
+                Some(&eve),
+                json!({"chat_type": "single", "members": [alice_user["id"]]}),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chat_requires_token() -> Result<()> {
+        let app = TestApp::new();
+        let (status, _) = app
+            .post("/users/create_chat", None, json!({"name": "rust"}))
+            .await?;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        Ok(())
+    }
+}
@@ -0,0 +1,893 @@
+use std::{
+    collections::HashSet,
+    path::{Component, Path as FsPath, PathBuf},
+    sync::Arc,
+};
+
+use axum::{
+    Extension, Json,
+    body::Body,
+    extract::{Multipart, Path, Query, State, multipart::Field},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use tokio::{fs, io::AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+use tracing::info;
+
+use crate::{
+    AppError, AppState, BlobObject, FileId, FileLink, FileLinkResponse, FileQuery, FileQuota,
+    FileRecord, JwtUser, LocalStorage, UploadConfig, is_file_ext, process,
+};
+
+// how much of the content is looked at to tell its type
+const SNIFF_LEN: usize = 8192;
+
+fn upload_error(e: impl ToString) -> AppError {
+    AppError::UploadFileError(e.to_string())
+}
+
+// what is known about a field once it is written
+struct WrittenFile {
+    tmp_path: PathBuf,
+    hash: String,
+    ext: String,
+    size: u64,
+    mime: String,
+}
+
+// the temp files of an upload, the ones not moved into place are removed when it is dropped
+struct TempFiles(Vec<PathBuf>);
+
+impl Drop for TempFiles {
+    fn drop(&mut self) {
+        for path in &self.0 {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+//upload the files to the chat as {chat_id}/file/{sha256}.{ext}. each field is streamed into a
+//temp file while it is hashed, the size limits are checked while streaming and the type once
+//the start of the content is in. the files are stored only once all of them are in and within
+//the quotas, so a failed upload stores nothing
+pub(crate) async fn upload_file_handler(
+    Extension(user): Extension<JwtUser>,
+    Extension(chat_id): Extension<u32>,
+    State(state): State<AppState>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, AppError> {
+    let limits = &state.upload;
+    let mut temp_files = TempFiles(vec![]);
+    let mut written = vec![];
+    let mut request_left = limits.max_request_size;
+    while let Some(mut field) = multipart.next_field().await.map_err(upload_error)? {
+        if written.len() >= limits.max_files {
+            return Err(upload_error(format!(
+                "at most {} files can be uploaded at once",
+                limits.max_files
+            )));
+        }
+        let Some(filename) = field.file_name().map(|s| s.to_string()) else {
+            return Err(upload_error("every field must be a file with a filename"));
+        };
+        let check_size = |size: u64| {
+            if size > limits.max_file_size {
+                Err(upload_error(format!(
+                    "{filename} is larger than {} bytes",
+                    limits.max_file_size
+                )))
+            } else if size > request_left {
+                Err(upload_error(format!(
+                    "the upload is larger than {} bytes",
+                    limits.max_request_size
+                )))
+            } else {
+                Ok(())
+            }
+        };
+        let (tmp_path, tmp_file) = state.files.temp_file().await.map_err(upload_error)?;
+        temp_files.0.push(tmp_path.clone());
+        let file = write_field(
+            &mut field,
+            (tmp_path, tmp_file),
+            &filename,
+            limits,
+            check_size,
+        )
+        .await?;
+        request_left -= file.size;
+        written.push(file);
+    }
+
+    //content the chat already has, or that comes twice, takes no more space
+    let mut new_files = HashSet::new();
+    let mut new_size = 0;
+    let mut files = vec![];
+    for file in written {
+        let file_id = FileId {
+            chat_id,
+            hash: file.hash.clone(),
+            ext: file.ext.clone(),
+        };
+        if state.files.get(&file_id).await?.is_none() && new_files.insert(file_id.clone()) {
+            new_size += file.size;
+        }
+        files.push((file_id, file));
+    }
+    //a quick check before anything is stored, the files are checked again when they are added
+    let quota = FileQuota {
+        user: limits.user_quota,
+        chat: limits.chat_quota,
+    };
+    quota.check(
+        new_size,
+        state.store.user_file_usage(user.id).await?,
+        state.store.chat_file_usage(chat_id).await?,
+    )?;
+
+    let mut uploads = vec![];
+    for (file_id, file) in &files {
+        info!("{} upload file {}", user.name, file_id.path().display());
+        let record = FileRecord {
+            file: file_id.clone(),
+            size: file.size,
+            mime: file.mime.clone(),
+            uploader_id: user.id,
+            created_at: Utc::now(),
+        };
+        uploads.push((file.tmp_path.clone(), record));
+    }
+    //concurrent uploads may have used up the quotas in the meantime
+    state.files.add_upload(uploads, quota).await?;
+    let mut paths: Vec<PathBuf> = vec![];
+    for (file_id, file) in files {
+        state.media.enqueue(&file.hash, &file.mime);
+        paths.push(file_id.path());
+    }
+    Ok(Json(paths))
+}
+
+//the lowercased extension of the file name, `bin` if it has none that is safe to put in a path
+fn file_ext(filename: &str) -> String {
+    match filename.rsplit_once('.') {
+        Some((_, ext)) if is_file_ext(ext) => ext.to_ascii_lowercase(),
+        _ => "bin".to_string(),
+    }
+}
+
+//the type told by the content, text that is not recognised otherwise is text/plain
+fn sniff_mime(head: &[u8]) -> &'static str {
+    if let Some(kind) = infer::get(head) {
+        return kind.mime_type();
+    }
+    let text = match std::str::from_utf8(head) {
+        Ok(_) => head,
+        //the head may end in the middle of a character
+        Err(e) if e.error_len().is_none() => &head[..e.valid_up_to()],
+        Err(_) => return "application/octet-stream",
+    };
+    if text.contains(&0) {
+        "application/octet-stream"
+    } else {
+        "text/plain"
+    }
+}
+
+//the sniffed type must be allowed, and an extension naming a type we can recognise must
+//match the content
+fn check_mime(
+    head: &[u8],
+    filename: &str,
+    ext: &str,
+    limits: &UploadConfig,
+) -> Result<String, AppError> {
+    let mime = sniff_mime(head);
+    if !limits.is_allowed(mime) {
+        return Err(upload_error(format!(
+            "{filename} is a {mime} file, which is not allowed"
+        )));
+    }
+    if let Some(claimed) = mime_guess::from_ext(ext).first() {
+        let claimed = claimed.essence_str();
+        if claimed != mime && infer::is_mime_supported(claimed) {
+            return Err(upload_error(format!(
+                "{filename} is a {mime} file, not {claimed}"
+            )));
+        }
+    }
+    Ok(mime.to_string())
+}
+
+//copy the field chunk by chunk into the file, hashing it and checking its size and type
+async fn write_field(
+    field: &mut Field<'_>,
+    (tmp_path, mut file): (PathBuf, fs::File),
+    filename: &str,
+    limits: &UploadConfig,
+    check_size: impl Fn(u64) -> Result<(), AppError>,
+) -> Result<WrittenFile, AppError> {
+    let ext = file_ext(filename);
+    let mut hasher = Sha256::new();
+    let mut size = 0u64;
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    let mut mime = None;
+    while let Some(chunk) = field.chunk().await.map_err(upload_error)? {
+        size += chunk.len() as u64;
+        check_size(size)?;
+        if mime.is_none() {
+            let take = chunk.len().min(SNIFF_LEN - head.len());
+            head.extend_from_slice(&chunk[..take]);
+            if head.len() == SNIFF_LEN {
+                mime = Some(check_mime(&head, filename, &ext, limits)?);
+            }
+        }
+        hasher.update(&chunk);
+        file.write_all(&chunk).await.map_err(upload_error)?;
+    }
+    let mime = match mime {
+        Some(mime) => mime,
+        None => check_mime(&head, filename, &ext, limits)?,
+    };
+    file.sync_all().await.map_err(upload_error)?;
+    Ok(WrittenFile {
+        tmp_path,
+        hash: format!("{:x}", hasher.finalize()),
+        ext,
+        size,
+        mime,
+    })
+}
+
+//resolve the client supplied path inside {base_dir}/{chat_id}/file. only plain relative
+//paths are accepted, and after resolving symlinks the file must still be inside the directory
+async fn resolve_chat_file(
+    base_dir: &FsPath,
+    chat_id: u32,
+    file_path: &str,
+) -> Result<PathBuf, AppError> {
+    let relative = FsPath::new(file_path);
+    if relative.as_os_str().is_empty()
+        || !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+    {
+        return Err(AppError::InvalidFilePath(format!(
+            "invalid file path {file_path}"
+        )));
+    }
+    let not_found = |_| AppError::FileNotFound("file not found".to_string());
+    let dir = fs::canonicalize(base_dir.join(format!("{chat_id}/file")))
+        .await
+        .map_err(not_found)?;
+    let full_path = fs::canonicalize(dir.join(relative))
+        .await
+        .map_err(not_found)?;
+    if !full_path.starts_with(&dir) {
+        return Err(AppError::InvalidFilePath(format!(
+            "invalid file path {file_path}"
+        )));
+    }
+    if !full_path.is_file() {
+        return Err(AppError::FileNotFound("file not found".to_string()));
+    }
+    Ok(full_path)
+}
+
+// the part of the file a `Range` header asks for
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    // no range, or one we do not support such as several ranges: the whole file
+    Full,
+    // [start, end)
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+fn parse_range(range: Option<&str>, len: u64) -> ByteRange {
+    let Some(spec) = range.and_then(|r| r.trim().strip_prefix("bytes=")) else {
+        return ByteRange::Full;
+    };
+    let Some((start, end)) = spec.split_once('-') else {
+        return ByteRange::Full;
+    };
+    let (start, end) = match (start.trim(), end.trim()) {
+        //the last n bytes
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(n) => (len.saturating_sub(n), len),
+            Err(_) => return ByteRange::Full,
+        },
+        (start, end) => {
+            let Ok(start) = start.parse::<u64>() else {
+                return ByteRange::Full;
+            };
+            let end = match end {
+                "" => len,
+                end => match end.parse::<u64>() {
+                    Ok(end) if end >= start => end.saturating_add(1).min(len),
+                    _ => return ByteRange::Full,
+                },
+            };
+            (start, end)
+        }
+    };
+    if start >= len {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(start, end)
+}
+
+//files of the old layout are content addressed too, the path without the extension is the
+//sha1 of the content
+fn file_etag(file_path: &str) -> String {
+    let hash = file_path
+        .rsplit_once('.')
+        .map_or(file_path, |(hash, _)| hash);
+    format!("\"{}\"", hash.replace('/', ""))
+}
+
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+//only members of the chat can read the files sent in it. files uploaded as {sha256}.{ext} are
+//looked up in the file store, other paths are files of the old layout below the chat directory.
+//`?thumb=1` asks for the thumbnail of a processed image
+pub(crate) async fn file_handler(
+    Path((chat_id, file_path)): Path<(u32, String)>,
+    Query(query): Query<FileQuery>,
+    State(state): State<AppState>,
+    Extension(user): Extension<JwtUser>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    check_member(&state, chat_id, &user).await?;
+    if let Some(file_id) = FileId::parse(chat_id, &file_path) {
+        if query.thumb == 0 {
+            info!("{} download file {}", user.name, file_id.path().display());
+        }
+        return serve_file(&state, &file_id, query.thumb != 0, &headers).await;
+    }
+    if query.thumb != 0 {
+        return Err(AppError::FileNotFound("thumbnail not found".to_string()));
+    }
+    let full_path = resolve_chat_file(&state.base_dir, chat_id, &file_path).await?;
+    info!("{} download file {}", user.name, full_path.display());
+    let (Some(dir), Some(name)) = (
+        full_path.parent(),
+        full_path.file_name().and_then(|name| name.to_str()),
+    ) else {
+        return Err(AppError::FileNotFound("file not found".to_string()));
+    };
+    let file = BlobObject::open(Arc::new(LocalStorage::new(dir)), name).await?;
+    let mime = mime_guess::from_path(&full_path).first_or_octet_stream();
+    stream_file(file, &file_etag(&file_path), mime.as_ref(), &headers).await
+}
+
+//a link to an uploaded file that works without the access token until it expires, for
+//players and download managers that can not send one
+pub(crate) async fn file_link_handler(
+    Path((chat_id, file_path)): Path<(u32, String)>,
+    Query(query): Query<FileQuery>,
+    State(state): State<AppState>,
+    Extension(user): Extension<JwtUser>,
+) -> Result<Json<FileLinkResponse>, AppError> {
+    check_member(&state, chat_id, &user).await?;
+    let Some(file_id) = FileId::parse(chat_id, &file_path) else {
+        return Err(AppError::InvalidFilePath(format!(
+            "{file_path} is not an uploaded file"
+        )));
+    };
+    if state.files.get(&file_id).await?.is_none() {
+        return Err(AppError::FileNotFound("file not found".to_string()));
+    }
+    let link = FileLink {
+        chat_id,
+        hash: file_id.hash,
+        ext: file_id.ext,
+        thumb: query.thumb != 0,
+    };
+    let expires_at = Utc::now() + chrono::Duration::seconds(state.file_link_ttl as i64);
+    let token = state
+        .keys
+        .key_set()
+        .sign_file_link(link, state.file_link_ttl)?;
+    Ok(Json(FileLinkResponse {
+        url: format!("/files/{token}"),
+        expires_at,
+    }))
+}
+
+//the file a download link points at, the link is all the authorization there is
+pub(crate) async fn file_link_download_handler(
+    Path(token): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let link = state.keys.key_set().verify_file_link(&token)?;
+    let file_id = FileId {
+        chat_id: link.chat_id,
+        hash: link.hash,
+        ext: link.ext,
+    };
+    serve_file(&state, &file_id, link.thumb, &headers).await
+}
+
+async fn check_member(state: &AppState, chat_id: u32, user: &JwtUser) -> Result<(), AppError> {
+    if !state.store.is_chat_member(chat_id, user.id).await? {
+        return Err(AppError::NotChatMember(format!(
+            "user {} is not a member of chat {chat_id}",
+            user.id
+        )));
+    }
+    Ok(())
+}
+
+//an uploaded file of the chat, or the thumbnail of it
+async fn serve_file(
+    state: &AppState,
+    file_id: &FileId,
+    thumb: bool,
+    headers: &HeaderMap,
+) -> Result<Response, AppError> {
+    let Some(record) = state.files.get(file_id).await? else {
+        return Err(AppError::FileNotFound("file not found".to_string()));
+    };
+    if thumb {
+        //the background job may have been lost to a full queue or a restart
+        let meta = match state.files.meta(&file_id.hash).await? {
+            Some(meta) => meta,
+            None => process(&state.files, &file_id.hash, &record.mime).await?,
+        };
+        let Some(mime) = meta.thumb_mime else {
+            return Err(AppError::FileNotFound("thumbnail not found".to_string()));
+        };
+        let thumb = state.files.open_thumb(&file_id.hash).await?;
+        let etag = format!("\"{}-thumb\"", file_id.hash);
+        return stream_file(thumb, &etag, &mime, headers).await;
+    }
+    let file = state.files.open_blob(&file_id.hash).await?;
+    let etag = format!("\"{}\"", file_id.hash);
+    stream_file(file, &etag, &record.mime, headers).await
+}
+
+//stream the file, honouring a single `Range` and `If-None-Match`
+async fn stream_file(
+    file: BlobObject,
+    etag: &str,
+    mime: &str,
+    headers: &HeaderMap,
+) -> Result<Response, AppError> {
+    let mut header = HeaderMap::new();
+    if let Ok(value) = etag.parse() {
+        header.insert(header::ETAG, value);
+    }
+    if etag_matches(headers, etag) {
+        return Ok((StatusCode::NOT_MODIFIED, header).into_response());
+    }
+
+    let len = file.size;
+    let range = headers.get(header::RANGE).and_then(|v| v.to_str().ok());
+    let (status, start, end) = match parse_range(range, len) {
+        ByteRange::Full => (StatusCode::OK, 0, len),
+        ByteRange::Partial(start, end) => {
+            header.insert(
+                header::CONTENT_RANGE,
+                format!("bytes {start}-{}/{len}", end - 1).parse().unwrap(),
+            );
+            (StatusCode::PARTIAL_CONTENT, start, end)
+        }
+        ByteRange::Unsatisfiable => {
+            header.insert(
+                header::CONTENT_RANGE,
+                format!("bytes */{len}").parse().unwrap(),
+            );
+            return Ok((StatusCode::RANGE_NOT_SATISFIABLE, header).into_response());
+        }
+    };
+    if let Ok(value) = mime.parse() {
+        header.insert(header::CONTENT_TYPE, value);
+    }
+    header.insert(header::CONTENT_LENGTH, (end - start).into());
+    header.insert(header::ACCEPT_RANGES, "bytes".parse().unwrap());
+    let body = Body::from_stream(ReaderStream::new(file.read(start, end).await?));
+    Ok((status, header, body).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use axum::http::{StatusCode, header};
+    use serde_json::json;
+
+    use super::*;
+    use crate::chat_server::test_util::*;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range(None, 10), ByteRange::Full);
+        assert_eq!(parse_range(Some("bytes=0-4"), 10), ByteRange::Partial(0, 5));
+        assert_eq!(parse_range(Some("bytes=5-"), 10), ByteRange::Partial(5, 10));
+        assert_eq!(parse_range(Some("bytes=-3"), 10), ByteRange::Partial(7, 10));
+        assert_eq!(
+            parse_range(Some("bytes=8-100"), 10),
+            ByteRange::Partial(8, 10)
+        );
+        assert_eq!(parse_range(Some("bytes=10-"), 10), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range(Some("bytes=0-1,4-5"), 10), ByteRange::Full);
+        assert_eq!(parse_range(Some("items=0-1"), 10), ByteRange::Full);
+    }
+
+    #[test]
+    fn test_sniff_mime() {
+        assert_eq!(sniff_mime(b"\x89PNG\r\n\x1a\n0000"), "image/png");
+        assert_eq!(sniff_mime("héllo".as_bytes()), "text/plain");
+        // cut in the middle of a character
+        assert_eq!(sniff_mime(&"héllo".as_bytes()[..2]), "text/plain");
+        assert_eq!(sniff_mime(b"\x00\x01\x02"), "application/octet-stream");
+        assert_eq!(file_ext("cats.PNG"), "png");
+        assert_eq!(file_ext("a./../x"), "bin");
+        assert_eq!(file_ext("README"), "bin");
+    }
+
+    #[tokio::test]
+    async fn test_upload_limits() -> Result<()> {
+        let app = TestApp::with_upload(UploadConfig {
+            max_file_size: 100,
+            max_request_size: 150,
+            max_files: 3,
+            user_quota: 250,
+            ..Default::default()
+        });
+        let (alice, _) = app.signup("alice").await?;
+        app.post("/users/create_chat", Some(&alice), json!({"name": "rust"}))
+            .await?;
+        let upload_error = |status: StatusCode, body: &serde_json::Value, msg: &str| {
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            let error = body["error"].as_str().unwrap();
+            assert!(error.contains(msg), "{error}");
+        };
+
+        let (status, body) = app.upload(1, &alice, &[("big.txt", &[b'a'; 101])]).await?;
+        upload_error(status, &body, "big.txt is larger than 100 bytes");
+        let (status, body) = app
+            .upload(1, &alice, &[("a.txt", &[b'a'; 80]), ("b.txt", &[b'b'; 80])])
+            .await?;
+        upload_error(status, &body, "the upload is larger than 150 bytes");
+        // nothing of a failed upload is kept
+        assert_eq!(app.state.store.user_file_usage(1).await?, 0);
+        let (status, body) = app
+            .upload(
+                1,
+                &alice,
+                &[
+                    ("a.txt", b"a"),
+                    ("b.txt", b"b"),
+                    ("c.txt", b"c"),
+                    ("d.txt", b"d"),
+                ],
+            )
+            .await?;
+        upload_error(status, &body, "at most 3 files");
+        let (status, body) = app.upload(1, &alice, &[("", b"no name")]).await?;
+        upload_error(status, &body, "every field must be a file with a filename");
+
+        // the type comes from the content, the extension has to agree with it
+        let png = [b"\x89PNG\r\n\x1a\n".as_slice(), &[0; 16]].concat();
+        let (status, _) = app.upload(1, &alice, &[("cat.png", &png)]).await?;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = app.upload(1, &alice, &[("cat.png", b"just text")]).await?;
+        upload_error(status, &body, "is a text/plain file, not image/png");
+        let elf = [b"\x7fELF\x02\x01\x01".as_slice(), &[0; 16]].concat();
+        let (status, body) = app.upload(1, &alice, &[("tool.txt", &elf)]).await?;
+        upload_error(status, &body, "which is not allowed");
+
+        // 24 bytes of png so far, the same content again is free
+        for content in [[b'a'; 100], [b'b'; 100]] {
+            let (status, body) = app.upload(1, &alice, &[("a.txt", &content)]).await?;
+            assert_eq!(status, StatusCode::OK, "{body}");
+        }
+        let (status, _) = app.upload(1, &alice, &[("a.txt", &[b'a'; 100])]).await?;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = app.upload(1, &alice, &[("c.txt", &[b'c'; 100])]).await?;
+        upload_error(status, &body, "the upload quota of the user is exceeded");
+        assert_eq!(app.state.store.user_file_usage(1).await?, 224);
+        let mut tmp = tokio::fs::read_dir(app.state.base_dir.join("tmp")).await?;
+        assert!(tmp.next_entry().await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upload_and_stream_download() -> Result<()> {
+        let app = TestApp::new();
+        let (alice, _) = app.signup("alice").await?;
+        app.post("/users/create_chat", Some(&alice), json!({"name": "rust"}))
+            .await?;
+        let content = b"hello, streaming world".repeat(1000);
+        let (status, files) = app
+            .upload(
+                1,
+                &alice,
+                &[("hello.txt", &content), ("again.txt", &content)],
+            )
+            .await?;
+        assert_eq!(status, StatusCode::OK);
+        let path = files[0].as_str().unwrap().to_string();
+        // the same content is stored once
+        assert_eq!(files[1], files[0]);
+        // nothing is left behind in the temp dir
+        let mut tmp = tokio::fs::read_dir(app.state.base_dir.join("tmp")).await?;
+        assert!(tmp.next_entry().await?.is_none());
+
+        let Some(relative) = path.strip_prefix("1/file/") else {
+            panic!("unexpected path {path}");
+        };
+        let uri = format!("/users/message/files/1/{relative}");
+        let (status, headers, body) = app.download(&uri, &alice, &[]).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, content);
+        assert_eq!(headers[header::CONTENT_LENGTH], content.len().to_string());
+        assert_eq!(headers[header::CONTENT_TYPE], "text/plain");
+        let etag = headers[header::ETAG].to_str()?.to_string();
+        let hash = format!("{:x}", Sha256::digest(&content));
+        assert_eq!(etag, format!("\"{hash}\""));
+        assert_eq!(path, format!("1/file/{hash}.txt"));
+
+        let (status, headers, body) = app
+            .download(&uri, &alice, &[(header::RANGE, "bytes=7-15")])
+            .await?;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(body, content[7..16]);
+        assert_eq!(
+            headers[header::CONTENT_RANGE],
+            format!("bytes 7-15/{}", content.len())
+        );
+        assert_eq!(headers[header::CONTENT_LENGTH], "9");
+
+        let (status, headers, _) = app
+            .download(&uri, &alice, &[(header::RANGE, "bytes=100000-")])
+            .await?;
+        assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(
+            headers[header::CONTENT_RANGE],
+            format!("bytes */{}", content.len())
+        );
+
+        let (status, _, body) = app
+            .download(&uri, &alice, &[(header::IF_NONE_MATCH, &etag)])
+            .await?;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        assert!(body.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_images_get_thumbnails() -> Result<()> {
+        let app = TestApp::new();
+        let (alice, _) = app.signup("alice").await?;
+        app.post("/users/create_chat", Some(&alice), json!({"name": "rust"}))
+            .await?;
+        let mut png = std::io::Cursor::new(vec![]);
+        image::DynamicImage::new_rgb8(600, 300).write_to(&mut png, image::ImageFormat::Png)?;
+        let (_, paths) = app
+            .upload(
+                1,
+                &alice,
+                &[("photo.png", png.get_ref()), ("notes.txt", b"notes")],
+            )
+            .await?;
+        let (photo, notes) = (paths[0].as_str().unwrap(), paths[1].as_str().unwrap());
+
+        // processing runs in the background
+        let hash = &photo["1/file/".len()..photo.len() - ".png".len()];
+        let mut meta = None;
+        for _ in 0..200 {
+            meta = app.state.files.meta(hash).await?;
+            if meta.is_some() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let meta = meta.expect("the image is processed");
+        assert_eq!((meta.width, meta.height), (Some(600), Some(300)));
+
+        let (_, msg) = app
+            .post(
+                "/users/message/send_message/1",
+                Some(&alice),
+                json!({"content": "look", "files": [photo, notes]}),
+            )
+            .await?;
+        let files = msg["files"].as_array().unwrap();
+        assert_eq!(files[0]["width"], 600);
+        assert_eq!(files[0]["height"], 300);
+        let thumb_url = files[0]["thumb_url"].as_str().unwrap();
+        assert!(files[1].get("thumb_url").is_none());
+
+        let (status, headers, body) = app.download(thumb_url, &alice, &[]).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "image/jpeg");
+        let thumb = image::load_from_memory(&body)?;
+        assert_eq!((thumb.width(), thumb.height()), (256, 128));
+        let uri = format!(
+            "/users/message/files/1/{}?thumb=1",
+            &notes["1/file/".len()..]
+        );
+        let (status, _, _) = app.download(&uri, &alice, &[]).await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unprocessed_images_get_thumbnails_on_demand() -> Result<()> {
+        let app = TestApp::new();
+        let (alice, _) = app.signup("alice").await?;
+        app.post("/users/create_chat", Some(&alice), json!({"name": "rust"}))
+            .await?;
+        // stored without a processing job, as if the job was dropped
+        let (tmp_path, _) = app.state.files.temp_file().await?;
+        image::DynamicImage::new_rgb8(600, 300)
+            .save_with_format(&tmp_path, image::ImageFormat::Png)?;
+        let hash = "c".repeat(64);
+        let record = FileRecord {
+            file: FileId {
+                chat_id: 1,
+                hash: hash.clone(),
+                ext: "png".into(),
+            },
+            size: fs::metadata(&tmp_path).await?.len(),
+            mime: "image/png".into(),
+            uploader_id: 1,
+            created_at: Utc::now(),
+        };
+        let quota = FileQuota {
+            user: u64::MAX,
+            chat: u64::MAX,
+        };
+        app.state
+            .files
+            .add_upload(vec![(tmp_path, record)], quota)
+            .await?;
+        assert!(app.state.files.meta(&hash).await?.is_none());
+
+        let uri = format!("/users/message/files/1/{hash}.png?thumb=1");
+        let (status, _, body) = app.download(&uri, &alice, &[]).await?;
+        assert_eq!(status, StatusCode::OK);
+        let thumb = image::load_from_memory(&body)?;
+        assert_eq!((thumb.width(), thumb.height()), (256, 128));
+        let meta = app.state.files.meta(&hash).await?.expect("processed");
+        assert_eq!((meta.width, meta.height), (Some(600), Some(300)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_signed_download_links() -> Result<()> {
+        let app = TestApp::new();
+        let (alice, _) = app.signup("alice").await?;
+        let (bob, _) = app.signup("bob").await?;
+        app.post("/users/create_chat", Some(&alice), json!({"name": "rust"}))
+            .await?;
+        let (_, paths) = app.upload(1, &alice, &[("notes.txt", b"my notes")]).await?;
+        let relative = &paths[0].as_str().unwrap()["1/file/".len()..];
+
+        let (status, link) = app
+            .get(&format!("/users/message/links/1/{relative}"), Some(&alice))
+            .await?;
+        assert_eq!(status, StatusCode::OK);
+        let link: FileLinkResponse = serde_json::from_value(link)?;
+        assert!(link.expires_at > Utc::now());
+        // the link is all it takes, ranges work as well
+        let (status, body) = app.get(&link.url, None).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "my notes");
+        let (status, _, body) = app
+            .download(&link.url, "", &[(header::RANGE, "bytes=3-")])
+            .await?;
+        assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(body, "notes");
+
+        // only members get links, and only to uploaded files
+        let (status, _) = app
+            .get(&format!("/users/message/links/1/{relative}"), Some(&bob))
+            .await?;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = app
+            .get("/users/message/links/1/abc/hello.txt", Some(&alice))
+            .await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // a link is not an access token, an access token is not a link
+        let token = link.url.strip_prefix("/files/").unwrap();
+        let (status, _) = app.get("/users/public_chats", Some(token)).await?;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = app.get(&format!("/files/{alice}"), None).await?;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let keys = app.state.keys.key_set();
+        let file = FileId::parse(1, relative).unwrap();
+        let expired = keys.sign_file_link(
+            FileLink {
+                chat_id: 1,
+                hash: file.hash,
+                ext: file.ext,
+                thumb: false,
+            },
+            0,
+        )?;
+        tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+        let (status, _) = app.get(&format!("/files/{expired}"), None).await?;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_download_is_confined_to_the_chat() -> Result<()> {
+        let app = TestApp::new();
+        let (alice, _) = app.signup("alice").await?;
+        let (bob, _) = app.signup("bob").await?;
+        app.post("/users/create_chat", Some(&alice), json!({"name": "rust"}))
+            .await?;
+        app.post("/users/create_chat", Some(&bob), json!({"name": "go"}))
+            .await?;
+        let base_dir = &app.state.base_dir;
+        tokio::fs::create_dir_all(base_dir.join("1/file/abc")).await?;
+        tokio::fs::write(base_dir.join("1/file/abc/hello.txt"), "hello").await?;
+        tokio::fs::create_dir_all(base_dir.join("2/file")).await?;
+        tokio::fs::write(base_dir.join("2/file/secret.txt"), "secret").await?;
+        tokio::fs::write(base_dir.join("secret.txt"), "secret").await?;
+
+        let (status, body) = app
+            .get("/users/message/files/1/abc/hello.txt", Some(&alice))
+            .await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "hello");
+        // bob is not a member of chat 1
+        let (status, _) = app
+            .get("/users/message/files/1/abc/hello.txt", Some(&bob))
+            .await?;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        for path in [
+            "../../2/file/secret.txt",
+            "abc/../../../secret.txt",
+            "..%2F..%2F2%2Ffile%2Fsecret.txt",
+            "%2Fetc%2Fpasswd",
+            "./abc/hello.txt",
+        ] {
+            let (status, body) = app
+                .get(&format!("/users/message/files/1/{path}"), Some(&alice))
+                .await?;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{path}");
+            assert!(
+                body["error"]
+                    .as_str()
+                    .unwrap()
+                    .starts_with("invalid file path")
+            );
+        }
+
+        // a symlink can not lead out of the chat directory either
+        #[cfg(unix)]
+        {
+            tokio::fs::symlink(base_dir.join("2/file"), base_dir.join("1/file/link")).await?;
+            let (status, _) = app
+                .get("/users/message/files/1/link/secret.txt", Some(&alice))
+                .await?;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        }
+
+        let (status, _) = app
+            .get("/users/message/files/1/abc/missing.txt", Some(&alice))
+            .await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        Ok(())
+    }
+}
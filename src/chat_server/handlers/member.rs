@@ -0,0 +1,616 @@
+use axum::{
+    Extension, Json,
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use tracing::{info, instrument};
+
+use crate::{
+    AppError, AppState, Chat, ChatEvent, ChatMemberRequest, ChatType, JoinOutput, JwtUser,
+    ModeratorRequest, ReadReceipt, ReadRequest,
+};
+
+// the chat, if the user is one of its members
+async fn member_chat(state: &AppState, chat_id: u32, user_id: u32) -> Result<Chat, AppError> {
+    let Some(chat) = state.store.get_chat(chat_id).await? else {
+        return Err(AppError::ChatNotFound(format!("chat {chat_id} not found")));
+    };
+    if !chat.is_member(user_id) {
+        return Err(AppError::NotChatMember(format!(
+            "user {user_id} is not a member of chat {chat_id}"
+        )));
+    }
+    Ok(chat)
+}
+
+// tell the chat, and the user's own connections so they stop following it
+fn publish_leave(state: &AppState, chat_id: u32, user_id: u32) {
+    let event = ChatEvent::Leave { chat_id, user_id };
+    state.hub.publish_chat(chat_id, event.clone());
+    state.hub.publish_user(user_id, event);
+}
+
+// delete the chat with its messages and invitations, for the owner and for admins
+pub(crate) async fn remove_chat(state: &AppState, chat: Chat) -> Result<(), AppError> {
+    state.store.delete_chat(chat.id).await?;
+    // members' subscriptions drop the chat on leave
+    for user_id in chat.members {
+        state.hub.publish_user(
+            user_id,
+            ChatEvent::Leave {
+                chat_id: chat.id,
+                user_id,
+            },
+        );
+    }
+    Ok(())
+}
+
+//mark the chat read up to a message, the other members are told when the user reads further
+#[instrument(skip(state))]
+pub(crate) async fn mark_read_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<JwtUser>,
+    Path(chat_id): Path<u32>,
+    input: Option<Json<ReadRequest>>,
+) -> Result<impl IntoResponse, AppError> {
+    member_chat(&state, chat_id, user.id).await?;
+    let Json(input) = input.unwrap_or_default();
+    let message_id = match input.message_id {
+        Some(id) => match state.store.get_message(chat_id, id).await? {
+            Some(msg) => msg.id,
+            None => return Err(AppError::MessageNotFound(format!("message {id} not found"))),
+        },
+        None => state
+            .store
+            .list_messages(chat_id, None, 1)
+            .await?
+            .first()
+            .map_or(0, |msg| msg.id),
+    };
+    let earlier = state.store.mark_read(chat_id, user.id, message_id).await?;
+    if message_id > earlier {
+        state.hub.publish_chat(
+            chat_id,
+            ChatEvent::Read {
+                chat_id,
+                user_id: user.id,
+                message_id,
+            },
+        );
+    }
+    Ok(Json(ReadReceipt {
+        chat_id,
+        user_id: user.id,
+        message_id: earlier.max(message_id),
+    }))
+}
+
+//the owner has to hand the chat over first, unless nobody else is left
+#[instrument(skip(state))]
+pub(crate) async fn leave_chat_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<JwtUser>,
+    Path(chat_id): Path<u32>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = member_chat(&state, chat_id, user.id).await?;
+    if chat.is_owner(user.id) {
+        if chat.members.len() > 1 {
+            return Err(AppError::MembershipError(
+                "transfer the ownership before leaving the chat".into(),
+            ));
+        }
+        remove_chat(&state, chat).await?;
+        return Ok(StatusCode::NO_CONTENT);
+    }
+    state.store.remove_chat_member(chat_id, user.id).await?;
+    publish_leave(&state, chat_id, user.id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+//members invite into groups and public channels, only moderators into private channels
+#[instrument(skip(state))]
+pub(crate) async fn invite_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<JwtUser>,
+    Path(chat_id): Path<u32>,
+    Json(input): Json<ChatMemberRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = member_chat(&state, chat_id, user.id).await?;
+    match chat.chat_type {
+        ChatType::Single => {
+            return Err(AppError::MembershipError(
+                "nobody can be invited into a single chat".into(),
+            ));
+        }
+        ChatType::PrivateChannel if !chat.is_moderator(user.id) => {
+            return Err(AppError::PermissionDenied(
+                "only moderators can invite into a private channel".into(),
+            ));
+        }
+        _ => {}
+    }
+    if chat.is_member(input.user_id) {
+        return Err(AppError::AlreadyChatMember(format!(
+            "user {} is already a member of chat {chat_id}",
+            input.user_id
+        )));
+    }
+    //only users of the same workspace can be invited
+    let invitee = state.store.get_user(input.user_id).await?;
+    if invitee.is_none_or(|invitee| invitee.ws_id != user.ws_id) {
+        return Err(AppError::UserNotFound(format!(
+            "user {} not found",
+            input.user_id
+        )));
+    }
+    state
+        .store
+        .create_invitation(chat_id, input.user_id, user.id)
+        .await?;
+    state.hub.publish_user(
+        input.user_id,
+        ChatEvent::Invited {
+            chat_id,
+            user_id: input.user_id,
+            inviter_id: user.id,
+        },
+    );
+    Ok(StatusCode::CREATED)
+}
+
+#[instrument(skip(state))]
+pub(crate) async fn list_invitations_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<JwtUser>,
+) -> Result<impl IntoResponse, AppError> {
+    let invitations = state.store.list_invitations(user.id).await?;
+    Ok(Json(invitations))
+}
+
+#[instrument(skip(state))]
+pub(crate) async fn accept_invitation_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<JwtUser>,
+    Path(chat_id): Path<u32>,
+) -> Result<impl IntoResponse, AppError> {
+    if state
+        .store
+        .take_invitation(chat_id, user.id)
+        .await?
+        .is_none()
+    {
+        return Err(AppError::InvitationNotFound(format!(
+            "no invitation to chat {chat_id}"
+        )));
+    }
+    if state
+        .store
+        .add_chat_member(chat_id, user.id)
+        .await?
+        .is_none()
+    {
+        return Err(AppError::ChatNotFound(format!("chat {chat_id} not found")));
+    }
+    let event = ChatEvent::Join {
+        chat_id,
+        user_id: user.id,
+    };
+    state.hub.publish_chat(chat_id, event.clone());
+    state.hub.publish_user(user.id, event);
+    Ok((
+        StatusCode::OK,
+        Json(JoinOutput {
+            message: format!("join the chat {chat_id} successfully"),
+        }),
+    ))
+}
+
+#[instrument(skip(state))]
+pub(crate) async fn decline_invitation_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<JwtUser>,
+    Path(chat_id): Path<u32>,
+) -> Result<impl IntoResponse, AppError> {
+    if state
+        .store
+        .take_invitation(chat_id, user.id)
+        .await?
+        .is_none()
+    {
+        return Err(AppError::InvitationNotFound(format!(
+            "no invitation to chat {chat_id}"
+        )));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+//moderators remove members, only the owner removes moderators, nobody removes the owner
+#[instrument(skip(state))]
+pub(crate) async fn kick_member_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<JwtUser>,
+    Path(chat_id): Path<u32>,
+    Json(input): Json<ChatMemberRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = member_chat(&state, chat_id, user.id).await?;
+    if !chat.is_moderator(user.id) {
+        return Err(AppError::PermissionDenied(
+            "only moderators can remove members".into(),
+        ));
+    }
+    if input.user_id == user.id {
+        return Err(AppError::MembershipError(
+            "leave the chat instead of removing yourself".into(),
+        ));
+    }
+    if !chat.is_member(input.user_id) {
+        return Err(AppError::NotChatMember(format!(
+            "user {} is not a member of chat {chat_id}",
+            input.user_id
+        )));
+    }
+    if chat.is_owner(input.user_id) || (chat.is_moderator(input.user_id) && !chat.is_owner(user.id))
+    {
+        return Err(AppError::PermissionDenied(
+            "only the owner can remove moderators".into(),
+        ));
+    }
+    state
+        .store
+        .remove_chat_member(chat_id, input.user_id)
+        .await?;
+    publish_leave(&state, chat_id, input.user_id);
+    info!(
+        "{} removed user {} from chat {chat_id}",
+        user.name, input.user_id
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[instrument(skip(state))]
+pub(crate) async fn set_moderator_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<JwtUser>,
+    Path(chat_id): Path<u32>,
+    Json(input): Json<ModeratorRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = member_chat(&state, chat_id, user.id).await?;
+    if !chat.is_owner(user.id) {
+        return Err(AppError::PermissionDenied(
+            "only the owner can change moderators".into(),
+        ));
+    }
+    if !chat.is_member(input.user_id) {
+        return Err(AppError::NotChatMember(format!(
+            "user {} is not a member of chat {chat_id}",
+            input.user_id
+        )));
+    }
+    if chat.is_owner(input.user_id) {
+        return Err(AppError::MembershipError(
+            "the owner is always a moderator".into(),
+        ));
+    }
+    let Some(chat) = state
+        .store
+        .set_chat_moderator(chat_id, input.user_id, input.moderator)
+        .await?
+    else {
+        return Err(AppError::ChatNotFound(format!("chat {chat_id} not found")));
+    };
+    Ok((StatusCode::OK, Json(chat)))
+}
+
+//hand the chat over to another member, the old owner stays a member
+#[instrument(skip(state))]
+pub(crate) async fn transfer_owner_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<JwtUser>,
+    Path(chat_id): Path<u32>,
+    Json(input): Json<ChatMemberRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = member_chat(&state, chat_id, user.id).await?;
+    if !chat.is_owner(user.id) {
+        return Err(AppError::PermissionDenied(
+            "only the owner can transfer the chat".into(),
+        ));
+    }
+    if input.user_id == user.id {
+        return Err(AppError::MembershipError(
+            "the chat already belongs to you".into(),
+        ));
+    }
+    if !chat.is_member(input.user_id) {
+        return Err(AppError::NotChatMember(format!(
+            "user {} is not a member of chat {chat_id}",
+            input.user_id
+        )));
+    }
+    let Some(chat) = state.store.set_chat_owner(chat_id, input.user_id).await? else {
+        return Err(AppError::ChatNotFound(format!("chat {chat_id} not found")));
+    };
+    info!(
+        "{} transferred chat {chat_id} to user {}",
+        user.name, input.user_id
+    );
+    Ok((StatusCode::OK, Json(chat)))
+}
+
+#[instrument(skip(state))]
+pub(crate) async fn delete_own_chat_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<JwtUser>,
+    Path(chat_id): Path<u32>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = member_chat(&state, chat_id, user.id).await?;
+    if !chat.is_owner(user.id) {
+        return Err(AppError::PermissionDenied(
+            "only the owner can delete the chat".into(),
+        ));
+    }
+    remove_chat(&state, chat).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use axum::http::{Method, StatusCode};
+    use serde_json::{Value, json};
+
+    use crate::{ChatEvent, chat_server::test_util::*};
+
+    struct Members {
+        app: TestApp,
+        alice: String,
+        bob: String,
+        tom: String,
+        bob_id: Value,
+        tom_id: Value,
+    }
+
+    // alice owns group chat 1, bob and tom are members
+    async fn setup() -> Result<Members> {
+        let app = TestApp::new();
+        let (alice, _) = app.signup("alice").await?;
+        let (bob, bob_user) = app.signup("bob").await?;
+        let (tom, tom_user) = app.signup("tom").await?;
+        app.post("/users/create_chat", Some(&alice), json!({"name": "rust"}))
+            .await?;
+        for token in [&bob, &tom] {
+            app.post("/users/join_chat", Some(token), json!({"chat_id": 1}))
+                .await?;
+        }
+        Ok(Members {
+            app,
+            alice,
+            bob,
+            tom,
+            bob_id: bob_user["id"].clone(),
+            tom_id: tom_user["id"].clone(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_leave_and_kick() -> Result<()> {
+        let m = setup().await?;
+        let app = &m.app;
+
+        let (status, body) = app
+            .post("/users/chats/1/leave", Some(&m.alice), json!({}))
+            .await?;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(
+            body["error"],
+            "transfer the ownership before leaving the chat"
+        );
+
+        let kick = json!({"user_id": m.tom_id});
+        let (status, _) = app
+            .post("/users/chats/1/kick", Some(&m.bob), kick.clone())
+            .await?;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        app.post(
+            "/users/chats/1/moderators",
+            Some(&m.alice),
+            json!({"user_id": m.bob_id, "moderator": true}),
+        )
+        .await?;
+        let (status, _) = app
+            .post("/users/chats/1/kick", Some(&m.bob), kick.clone())
+            .await?;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = app.get("/users/message/1", Some(&m.tom)).await?;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = app.post("/users/chats/1/kick", Some(&m.bob), kick).await?;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (status, _) = app
+            .post("/users/chats/1/leave", Some(&m.bob), json!({}))
+            .await?;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let chat = app.state.store.get_chat(1).await?.expect("chat exists");
+        assert_eq!(chat.members.len(), 1);
+        assert!(chat.moderators.is_empty());
+
+        // the last member leaving takes the chat with them
+        let (status, _) = app
+            .post("/users/chats/1/leave", Some(&m.alice), json!({}))
+            .await?;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(app.state.store.get_chat(1).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invitations() -> Result<()> {
+        let app = TestApp::new();
+        let (alice, _) = app.signup("alice").await?;
+        let (bob, bob_user) = app.signup("bob").await?;
+        let (tom, tom_user) = app.signup("tom").await?;
+        app.post(
+            "/users/create_chat",
+            Some(&alice),
+            json!({"chat_type": "private_channel", "name": "team", "members": [bob_user["id"]]}),
+        )
+        .await?;
+        app.post("/users/invitations/1/accept", Some(&bob), json!({}))
+            .await?;
+
+        let invite_tom = json!({"user_id": tom_user["id"]});
+        let (status, _) = app
+            .post("/users/chats/1/invite", Some(&bob), invite_tom.clone())
+            .await?;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = app
+            .post("/users/chats/1/invite", Some(&alice), invite_tom.clone())
+            .await?;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, _) = app
+            .post(
+                "/users/chats/1/invite",
+                Some(&alice),
+                json!({"user_id": bob_user["id"]}),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let (_, invitations) = app.get("/users/invitations", Some(&tom)).await?;
+        assert_eq!(invitations[0]["chat_name"], "team");
+        let (status, _) = app
+            .post("/users/invitations/1/decline", Some(&tom), json!({}))
+            .await?;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = app
+            .post("/users/invitations/1/accept", Some(&tom), json!({}))
+            .await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        app.post("/users/chats/1/invite", Some(&alice), invite_tom)
+            .await?;
+        let (status, _) = app
+            .post("/users/invitations/1/accept", Some(&tom), json!({}))
+            .await?;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = app.get("/users/message/1", Some(&tom)).await?;
+        assert_eq!(status, StatusCode::OK);
+        let (_, invitations) = app.get("/users/invitations", Some(&tom)).await?;
+        assert_eq!(invitations.as_array().map(Vec::len), Some(0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_transfer_and_delete() -> Result<()> {
+        let m = setup().await?;
+        let app = &m.app;
+
+        let (status, _) = app
+            .request(Method::DELETE, "/users/chats/1", Some(&m.bob), None)
+            .await?;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, chat) = app
+            .post(
+                "/users/chats/1/transfer",
+                Some(&m.alice),
+                json!({"user_id": m.bob_id}),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(chat["owner_id"], m.bob_id);
+        let (status, _) = app
+            .post(
+                "/users/chats/1/transfer",
+                Some(&m.alice),
+                json!({"user_id": m.tom_id}),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (status, _) = app
+            .request(Method::DELETE, "/users/chats/1", Some(&m.bob), None)
+            .await?;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = app
+            .request(Method::DELETE, "/users/chats/1", Some(&m.bob), None)
+            .await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_receipts_and_unread_counts() -> Result<()> {
+        let m = setup().await?;
+        let app = &m.app;
+        let mut events = app.state.hub.subscribe_chat(1);
+        let mut sent = vec![];
+        for (token, content) in [(&m.alice, "one"), (&m.bob, "two"), (&m.alice, "three")] {
+            let (_, msg) = app
+                .post(
+                    "/users/message/send_message/1",
+                    Some(token),
+                    json!({"content": content, "files": []}),
+                )
+                .await?;
+            sent.push(msg["id"].as_u64().unwrap());
+        }
+        let unread = async |token: &str| -> Result<(Value, Value)> {
+            let (status, chats) = app.get("/users/chats", Some(token)).await?;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(chats[0]["id"], 1);
+            assert_eq!(chats[0]["name"], "rust");
+            Ok((chats[0]["unread"].clone(), chats[0]["last_read"].clone()))
+        };
+        // the user's own messages are not unread
+        assert_eq!(unread(&m.tom).await?, (json!(3), json!(0)));
+        assert_eq!(unread(&m.bob).await?, (json!(2), json!(0)));
+
+        let read = async |token: &str, body: Value| {
+            app.post("/users/chats/1/read", Some(token), body).await
+        };
+        let (status, receipt) = read(&m.tom, json!({"message_id": sent[1]})).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(receipt["user_id"], m.tom_id);
+        assert_eq!(receipt["message_id"], sent[1]);
+        assert_eq!(unread(&m.tom).await?, (json!(1), json!(sent[1])));
+        let mut receipts = vec![];
+        while let Ok(event) = events.try_recv() {
+            if let ChatEvent::Read {
+                user_id,
+                message_id,
+                ..
+            } = event
+            {
+                receipts.push((json!(user_id), message_id));
+            }
+        }
+        assert_eq!(receipts, vec![(m.tom_id.clone(), sent[1])]);
+
+        // the last read message never moves back, and nothing is announced then
+        let (_, receipt) = read(&m.tom, json!({"message_id": sent[0]})).await?;
+        assert_eq!(receipt["message_id"], sent[1]);
+        assert!(events.try_recv().is_err());
+
+        // deleted messages are not unread, no message id reads up to the latest
+        let uri = format!("/users/message/1/{}", sent[2]);
+        app.request(Method::DELETE, &uri, Some(&m.alice), None)
+            .await?;
+        assert_eq!(unread(&m.bob).await?, (json!(1), json!(0)));
+        let (status, receipt) = app
+            .request(Method::POST, "/users/chats/1/read", Some(&m.bob), None)
+            .await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(receipt["message_id"], sent[2]);
+        assert_eq!(unread(&m.bob).await?, (json!(0), json!(sent[2])));
+
+        let (status, _) = read(&m.bob, json!({"message_id": 999})).await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (eve, _) = app.signup("eve").await?;
+        let (status, _) = read(&eve, json!({})).await?;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (_, chats) = app.get("/users/chats", Some(&eve)).await?;
+        assert_eq!(chats, json!([]));
+        Ok(())
+    }
+}
@@ -0,0 +1,1118 @@
+use std::collections::HashSet;
+
+use axum::{
+    Extension, Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+
+use crate::{
+    AppError, AppState, ChatEvent, ChatFile, CreateMessage, EditMessageRequest, FileId, JwtUser,
+    ListMessages, ListReplies, MAX_MESSAGE_LIMIT, Message, MessageRequest, MessageThread,
+    ReactionRequest, SearchQuery, tokenize,
+};
+
+// longest message content, in characters, every message is stored, indexed and sent to every
+// subscriber of the chat
+const MAX_MESSAGE_LEN: usize = 16 * 1024;
+// longest emoji accepted for a reaction, in bytes, enough for flags and zwj sequences
+const MAX_EMOJI_LEN: usize = 64;
+// every word of a search query is a prefix lookup, keep queries short
+const MAX_SEARCH_TERMS: usize = 16;
+
+//send message in the chat, message_verify_jwt_token_middleware has checked the user joined the chat.
+//the files of the message are uploaded already, a message with files may have no text
+pub(crate) async fn send_message_handler(
+    Extension(chat_id): Extension<u32>,
+    Extension(user): Extension<JwtUser>,
+    State(state): State<AppState>,
+    Json(message_request): Json<MessageRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let content = message_request.content;
+    if content.is_empty() && message_request.files.is_empty() {
+        return Err(AppError::InvalidMessage(
+            "content should not be empty".to_string(),
+        ));
+    }
+    check_content_len(&content)?;
+    let files = message_files(&state, chat_id, &message_request.files).await?;
+    // threads are one level deep, replying to a reply goes to the thread it is in
+    let reply_to = match message_request.reply_to {
+        Some(id) => {
+            let parent = find_message(&state, chat_id, id).await?;
+            Some(parent.reply_to.unwrap_or(parent.id))
+        }
+        None => None,
+    };
+    let mentions = message_mentions(&state, chat_id, &content).await?;
+
+    let msg = state
+        .store
+        .create_message(CreateMessage {
+            chat_id,
+            sender_id: user.id,
+            content,
+            files,
+            reply_to,
+            mentions,
+        })
+        .await?;
+    state
+        .hub
+        .publish_chat(chat_id, ChatEvent::NewMessage(msg.clone()));
+    Ok(Json(msg))
+}
+
+//the attachments are the paths returned by the upload, they must be files uploaded to this chat
+async fn message_files(
+    state: &AppState,
+    chat_id: u32,
+    paths: &[String],
+) -> Result<Vec<ChatFile>, AppError> {
+    if paths.len() > state.upload.max_files {
+        return Err(AppError::InvalidFilePath(format!(
+            "a message can have at most {} files",
+            state.upload.max_files
+        )));
+    }
+    let mut files = vec![];
+    let mut seen = HashSet::new();
+    for path in paths {
+        let Some(file_id) = FileId::parse_path(path) else {
+            return Err(AppError::InvalidFilePath(format!(
+                "invalid file path {path}"
+            )));
+        };
+        if file_id.chat_id != chat_id {
+            return Err(AppError::InvalidFilePath(format!(
+                "file {path} does not belong to chat {chat_id}"
+            )));
+        }
+        let Some(record) = state.files.get(&file_id).await? else {
+            return Err(AppError::FileNotFound(format!("file {path} not found")));
+        };
+        if seen.insert(file_id) {
+            let meta = state.files.meta(&record.file.hash).await?;
+            //give a blob whose processing job was lost another chance
+            if meta.is_none() {
+                state.media.enqueue(&record.file.hash, &record.mime);
+            }
+            files.push(record.chat_file(meta.as_ref()));
+        }
+    }
+    Ok(files)
+}
+
+fn check_content_len(content: &str) -> Result<(), AppError> {
+    if content.chars().count() > MAX_MESSAGE_LEN {
+        return Err(AppError::InvalidMessage(format!(
+            "content is longer than {MAX_MESSAGE_LEN} characters"
+        )));
+    }
+    Ok(())
+}
+
+//tell the other members of the chat that the user is typing, nothing is stored
+pub(crate) async fn typing_handler(
+    Extension(chat_id): Extension<u32>,
+    Extension(user): Extension<JwtUser>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    state.hub.publish_chat(
+        chat_id,
+        ChatEvent::Typing {
+            chat_id,
+            user_id: user.id,
+        },
+    );
+    StatusCode::NO_CONTENT
+}
+
+//list the messages of the chat newest first, the membership is checked by message_verify_jwt_token_middleware
+pub(crate) async fn list_messages_handler(
+    Extension(chat_id): Extension<u32>,
+    State(state): State<AppState>,
+    Query(query): Query<ListMessages>,
+) -> Result<impl IntoResponse, AppError> {
+    let limit = query.limit.clamp(1, MAX_MESSAGE_LIMIT);
+    let messages = state
+        .store
+        .list_messages(chat_id, query.before, limit)
+        .await?;
+    Ok(Json(messages))
+}
+
+//the root of the thread the message is in and the replies to it, oldest first
+pub(crate) async fn thread_handler(
+    Extension(chat_id): Extension<u32>,
+    State(state): State<AppState>,
+    Path((_, message_id)): Path<(u32, u64)>,
+    Query(query): Query<ListReplies>,
+) -> Result<impl IntoResponse, AppError> {
+    let not_found = || AppError::MessageNotFound(format!("message {message_id} not found"));
+    // a deleted root still holds its thread together
+    let mut root = state
+        .store
+        .get_message(chat_id, message_id)
+        .await?
+        .ok_or_else(not_found)?;
+    if let Some(root_id) = root.reply_to {
+        root = state
+            .store
+            .get_message(chat_id, root_id)
+            .await?
+            .ok_or_else(not_found)?;
+    }
+    let limit = query.limit.clamp(1, MAX_MESSAGE_LIMIT);
+    let replies = state
+        .store
+        .list_replies(chat_id, root.id, query.after, limit)
+        .await?;
+    Ok(Json(MessageThread { root, replies }))
+}
+
+//messages in the user's chats mentioning the user, newest first
+pub(crate) async fn mentions_handler(
+    Extension(user): Extension<JwtUser>,
+    State(state): State<AppState>,
+    Query(query): Query<ListMessages>,
+) -> Result<impl IntoResponse, AppError> {
+    let limit = query.limit.clamp(1, MAX_MESSAGE_LIMIT);
+    let messages = state
+        .store
+        .list_mentions(user.id, query.before, limit)
+        .await?;
+    Ok(Json(messages))
+}
+
+//search the messages of the user's chats, best matches first
+pub(crate) async fn search_handler(
+    Extension(user): Extension<JwtUser>,
+    State(state): State<AppState>,
+    Query(mut query): Query<SearchQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let terms = tokenize(&query.q).count();
+    if terms == 0 {
+        return Err(AppError::InvalidSearchQuery(
+            "search query has no words".to_string(),
+        ));
+    }
+    if terms > MAX_SEARCH_TERMS {
+        return Err(AppError::InvalidSearchQuery(format!(
+            "search query has more than {MAX_SEARCH_TERMS} words"
+        )));
+    }
+    query.limit = query.limit.clamp(1, MAX_MESSAGE_LIMIT);
+    let hits = state.store.search_messages(user.id, &query).await?;
+    Ok(Json(hits))
+}
+
+//edit the content of a message, only the sender can, the old content is kept in the history
+pub(crate) async fn edit_message_handler(
+    Extension(chat_id): Extension<u32>,
+    Extension(user): Extension<JwtUser>,
+    State(state): State<AppState>,
+    Path((_, message_id)): Path<(u32, u64)>,
+    Json(input): Json<EditMessageRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    if input.content.is_empty() {
+        return Err(AppError::InvalidMessage(
+            "content should not be empty".to_string(),
+        ));
+    }
+    check_content_len(&input.content)?;
+    let msg = find_message(&state, chat_id, message_id).await?;
+    if msg.sender_id != user.id {
+        return Err(AppError::PermissionDenied(
+            "only the sender can edit the message".to_string(),
+        ));
+    }
+    let mentions = message_mentions(&state, chat_id, &input.content).await?;
+    let msg = state
+        .store
+        .edit_message(chat_id, message_id, input.content, mentions)
+        .await?
+        .ok_or_else(|| deleted(message_id))?;
+    state
+        .hub
+        .publish_chat(chat_id, ChatEvent::MessageUpdated(msg.clone()));
+    Ok(Json(msg))
+}
+
+//delete a message, leaving a tombstone. the sender and the moderators of the chat can
+pub(crate) async fn delete_message_handler(
+    Extension(chat_id): Extension<u32>,
+    Extension(user): Extension<JwtUser>,
+    State(state): State<AppState>,
+    Path((_, message_id)): Path<(u32, u64)>,
+) -> Result<impl IntoResponse, AppError> {
+    let msg = find_message(&state, chat_id, message_id).await?;
+    if msg.sender_id != user.id {
+        let moderator = state
+            .store
+            .get_chat(chat_id)
+            .await?
+            .is_some_and(|chat| chat.is_moderator(user.id));
+        if !moderator {
+            return Err(AppError::PermissionDenied(
+                "only the sender or a moderator can delete the message".to_string(),
+            ));
+        }
+    }
+    let msg = state
+        .store
+        .delete_message(chat_id, message_id)
+        .await?
+        .ok_or_else(|| deleted(message_id))?;
+    state
+        .hub
+        .publish_chat(chat_id, ChatEvent::MessageUpdated(msg.clone()));
+    Ok(Json(msg))
+}
+
+//the earlier contents of the message, oldest first
+pub(crate) async fn message_history_handler(
+    Extension(chat_id): Extension<u32>,
+    State(state): State<AppState>,
+    Path((_, message_id)): Path<(u32, u64)>,
+) -> Result<impl IntoResponse, AppError> {
+    find_message(&state, chat_id, message_id).await?;
+    let history = state.store.message_history(chat_id, message_id).await?;
+    Ok(Json(history))
+}
+
+//react to a message with an emoji, reacting again with the same emoji takes it back
+pub(crate) async fn toggle_reaction_handler(
+    Extension(chat_id): Extension<u32>,
+    Extension(user): Extension<JwtUser>,
+    State(state): State<AppState>,
+    Path((_, message_id)): Path<(u32, u64)>,
+    Json(input): Json<ReactionRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let emoji = input.emoji;
+    if emoji.is_empty()
+        || emoji.len() > MAX_EMOJI_LEN
+        || emoji.chars().any(|c| c.is_whitespace() || c.is_control())
+    {
+        return Err(AppError::InvalidMessage(format!("invalid emoji {emoji:?}")));
+    }
+    find_message(&state, chat_id, message_id).await?;
+    let msg = state
+        .store
+        .toggle_reaction(chat_id, message_id, user.id, &emoji)
+        .await?
+        .ok_or_else(|| deleted(message_id))?;
+    state
+        .hub
+        .publish_chat(chat_id, ChatEvent::MessageUpdated(msg.clone()));
+    Ok(Json(msg))
+}
+
+//a message of the chat that is not deleted
+async fn find_message(
+    state: &AppState,
+    chat_id: u32,
+    message_id: u64,
+) -> Result<Message, AppError> {
+    match state.store.get_message(chat_id, message_id).await? {
+        Some(msg) if msg.deleted_at.is_none() => Ok(msg),
+        Some(_) => Err(deleted(message_id)),
+        None => Err(AppError::MessageNotFound(format!(
+            "message {message_id} not found"
+        ))),
+    }
+}
+
+//the members of the chat mentioned in the content, in the order they are first mentioned.
+//names are matched case insensitively, a name shared by several members mentions them all
+async fn message_mentions(
+    state: &AppState,
+    chat_id: u32,
+    content: &str,
+) -> Result<Vec<u32>, AppError> {
+    let names = mentioned_names(content);
+    if names.is_empty() {
+        return Ok(vec![]);
+    }
+    let names = names.into_iter().map(str::to_lowercase).collect::<Vec<_>>();
+    let members = state
+        .store
+        .find_chat_members_by_name(chat_id, &names)
+        .await?;
+    let mut mentions = vec![];
+    for name in &names {
+        for member in &members {
+            if member.name.to_lowercase() == *name && !mentions.contains(&member.id) {
+                mentions.push(member.id);
+            }
+        }
+    }
+    Ok(mentions)
+}
+
+//the names after an @, an @ inside a word like in an email address is not a mention.
+//a name ends at whitespace or punctuation other than `_`, `-` and `.`, and trailing dots are
+//dropped so that "thanks @bob." mentions bob
+fn mentioned_names(content: &str) -> Vec<&str> {
+    let is_name_char = |c: char| c.is_alphanumeric() || matches!(c, '_' | '-' | '.');
+    let mut names = vec![];
+    let mut prev = None;
+    for (idx, c) in content.char_indices() {
+        if c == '@' && !prev.is_some_and(is_name_char) {
+            let rest = &content[idx + 1..];
+            let end = rest.find(|c| !is_name_char(c)).unwrap_or(rest.len());
+            let name = rest[..end].trim_end_matches('.');
+            if !name.is_empty() {
+                names.push(name);
+            }
+        }
+        prev = Some(c);
+    }
+    names
+}
+
+fn deleted(message_id: u64) -> AppError {
+    AppError::MessageDeleted(format!("message {message_id} has been deleted"))
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use axum::http::{Method, StatusCode};
+    use serde_json::{Value, json};
+
+    use super::MAX_MESSAGE_LEN;
+    use crate::{RateLimit, RateLimitConfig, chat_server::test_util::*};
+
+    #[tokio::test]
+    async fn test_send_message_requires_membership() -> Result<()> {
+        let app = TestApp::new();
+        let (alice, _) = app.signup("alice").await?;
+        let (bob, _) = app.signup("bob").await?;
+        app.post("/users/create_chat", Some(&alice), json!({"name": "rust"}))
+            .await?;
+
+        let (status, msg) = app
+            .post(
+                "/users/message/send_message/1",
+                Some(&alice),
+                json!({"content": "hello", "files": []}),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(msg["content"], "hello");
+
+        let (status, _) = app
+            .post(
+                "/users/message/send_message/1",
+                Some(&bob),
+                json!({"content": "hello", "files": []}),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_message_attachments_are_uploaded_files() -> Result<()> {
+        let app = TestApp::new();
+        let (alice, _) = app.signup("alice").await?;
+        for name in ["rust", "go"] {
+            app.post("/users/create_chat", Some(&alice), json!({"name": name}))
+                .await?;
+        }
+        let (_, paths) = app.upload(1, &alice, &[("hello.txt", b"hello")]).await?;
+        let path = paths[0].as_str().unwrap();
+        let (_, other) = app.upload(2, &alice, &[("hello.txt", b"hello")]).await?;
+        let send = |files: serde_json::Value| {
+            app.post(
+                "/users/message/send_message/1",
+                Some(&alice),
+                json!({"content": "see attached", "files": files}),
+            )
+        };
+
+        let (status, body) = app
+            .post(
+                "/users/message/send_message/1",
+                Some(&alice),
+                json!({"content": "", "files": []}),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "content should not be empty");
+        // the files are enough
+        let (status, _) = app
+            .post(
+                "/users/message/send_message/1",
+                Some(&alice),
+                json!({"content": "", "files": [path]}),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, msg) = send(json!([path, path])).await?;
+        assert_eq!(status, StatusCode::OK);
+        let files = msg["files"].as_array().unwrap();
+        assert_eq!(files.len(), 1);
+        let hash = path.strip_prefix("1/file/").unwrap().strip_suffix(".txt");
+        assert_eq!(files[0]["hash"].as_str(), hash);
+        assert_eq!(files[0]["ext"], "txt");
+        assert_eq!(files[0]["size"], 5);
+        assert_eq!(files[0]["mime"], "text/plain");
+        let (status, body) = app
+            .get(files[0]["url"].as_str().unwrap(), Some(&alice))
+            .await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "hello");
+        let (_, page) = app.get("/users/message/1", Some(&alice)).await?;
+        assert_eq!(page[0]["files"], msg["files"]);
+
+        // the same content uploaded to another chat is not a file of this one
+        let (status, _) = send(other.clone()).await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = send(json!(["/etc/passwd"])).await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let missing = format!("1/file/{}.txt", "0".repeat(64));
+        let (status, _) = send(json!([missing])).await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_messages_with_cursor() -> Result<()> {
+        let app = TestApp::new();
+        let (alice, _) = app.signup("alice").await?;
+        let (bob, _) = app.signup("bob").await?;
+        app.post("/users/create_chat", Some(&alice), json!({"name": "rust"}))
+            .await?;
+        for i in 0..5 {
+            app.post(
+                "/users/message/send_message/1",
+                Some(&alice),
+                json!({"content": format!("msg {i}"), "files": []}),
+            )
+            .await?;
+        }
+
+        let (status, page) = app.get("/users/message/1?limit=2", Some(&alice)).await?;
+        assert_eq!(status, StatusCode::OK);
+        let page = page.as_array().unwrap();
+        assert_eq!(page.len(), 2);
+        assert_eq!(page[0]["content"], "msg 4");
+        assert_eq!(page[1]["content"], "msg 3");
+
+        let before = page[1]["id"].as_u64().unwrap();
+        let (_, page) = app
+            .get(
+                &format!("/users/message/1?limit=10&before={before}"),
+                Some(&alice),
+            )
+            .await?;
+        let contents = page
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["content"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(contents, vec!["msg 2", "msg 1", "msg 0"]);
+
+        let (status, _) = app.get("/users/message/1", Some(&bob)).await?;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sent_messages_are_read_back() -> Result<()> {
+        let app = TestApp::new();
+        let (alice, _) = app.signup("alice").await?;
+        let (bob, _) = app.signup("bob").await?;
+        app.post("/users/create_chat", Some(&alice), json!({"name": "rust"}))
+            .await?;
+        app.post("/users/join_chat", Some(&bob), json!({"chat_id": 1}))
+            .await?;
+
+        let mut sent = vec![];
+        for (token, content) in [(&alice, "hi bob"), (&bob, "hi alice"), (&alice, "bye")] {
+            let (status, msg) = app
+                .post(
+                    "/users/message/send_message/1",
+                    Some(token),
+                    json!({"content": content, "files": []}),
+                )
+                .await?;
+            assert_eq!(status, StatusCode::OK);
+            sent.push(msg["id"].as_u64().unwrap());
+        }
+        assert!(sent.windows(2).all(|w| w[0] < w[1]));
+        let (status, body) = app
+            .post(
+                "/users/message/send_message/1",
+                Some(&alice),
+                json!({"content": "a".repeat(MAX_MESSAGE_LEN + 1), "files": []}),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "content is longer than 16384 characters");
+
+        let (_, page) = app.get("/users/message/1", Some(&bob)).await?;
+        let page = page.as_array().unwrap();
+        let ids = page
+            .iter()
+            .map(|m| m["id"].as_u64().unwrap())
+            .rev()
+            .collect::<Vec<_>>();
+        assert_eq!(ids, sent);
+        assert_eq!(page[1]["content"], "hi alice");
+        Ok(())
+    }
+
+    struct Sent {
+        app: TestApp,
+        alice: String,
+        bob: String,
+        carol: String,
+        bob_id: Value,
+        carol_id: Value,
+        msg: Value,
+    }
+
+    // alice owns chat 1, bob and carol are members, bob sent `msg`
+    async fn setup() -> Result<Sent> {
+        let app = TestApp::new();
+        let (alice, _) = app.signup("alice").await?;
+        let (bob, bob_user) = app.signup("bob").await?;
+        let (carol, carol_user) = app.signup("carol").await?;
+        app.post("/users/create_chat", Some(&alice), json!({"name": "rust"}))
+            .await?;
+        for token in [&bob, &carol] {
+            app.post("/users/join_chat", Some(token), json!({"chat_id": 1}))
+                .await?;
+        }
+        let (_, msg) = app
+            .post(
+                "/users/message/send_message/1",
+                Some(&bob),
+                json!({"content": "helo", "files": []}),
+            )
+            .await?;
+        Ok(Sent {
+            app,
+            alice,
+            bob,
+            carol,
+            bob_id: bob_user["id"].clone(),
+            carol_id: carol_user["id"].clone(),
+            msg,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_edit_message_keeps_history() -> Result<()> {
+        let Sent {
+            app,
+            alice,
+            bob,
+            msg,
+            ..
+        } = setup().await?;
+        let uri = format!("/users/message/1/{}", msg["id"]);
+        assert_eq!(msg["edited_at"], Value::Null);
+        assert_eq!(msg["reactions"], json!([]));
+
+        let edit = async |token: &str, content: &str| {
+            app.request(
+                Method::PATCH,
+                &uri,
+                Some(token),
+                Some(json!({"content": content})),
+            )
+            .await
+        };
+        let (status, _) = edit(&alice, "hijacked").await?;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = edit(&bob, "").await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = edit(&bob, &"a".repeat(MAX_MESSAGE_LEN + 1)).await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, edited) = edit(&bob, "hello").await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(edited["content"], "hello");
+        assert_eq!(edited["id"], msg["id"]);
+        assert!(edited["edited_at"].is_string());
+        edit(&bob, "hello all").await?;
+
+        let (status, history) = app.get(&format!("{uri}/history"), Some(&alice)).await?;
+        assert_eq!(status, StatusCode::OK);
+        let contents = history
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v["content"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(contents, vec!["helo", "hello"]);
+        assert_eq!(history[0]["date"], msg["date"]);
+        assert_eq!(history[1]["date"], edited["edited_at"]);
+        let (_, page) = app.get("/users/message/1", Some(&alice)).await?;
+        assert_eq!(page[0]["content"], "hello all");
+
+        let (status, _) = app
+            .request(
+                Method::PATCH,
+                "/users/message/1/999",
+                Some(&bob),
+                Some(json!({"content": "hi"})),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deleted_message_leaves_tombstone() -> Result<()> {
+        let Sent {
+            app,
+            alice,
+            bob,
+            carol,
+            msg,
+            ..
+        } = setup().await?;
+        let uri = format!("/users/message/1/{}", msg["id"]);
+        app.request(
+            Method::PATCH,
+            &uri,
+            Some(&bob),
+            Some(json!({"content": "hello"})),
+        )
+        .await?;
+        app.post(
+            &format!("{uri}/reactions"),
+            Some(&carol),
+            json!({"emoji": "👍"}),
+        )
+        .await?;
+
+        let (status, _) = app
+            .request(Method::DELETE, &uri, Some(&carol), None)
+            .await?;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, tombstone) = app.request(Method::DELETE, &uri, Some(&bob), None).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert!(tombstone["deleted_at"].is_string());
+        assert_eq!(tombstone["content"], "");
+        assert_eq!(tombstone["reactions"], json!([]));
+
+        let (_, page) = app.get("/users/message/1", Some(&carol)).await?;
+        assert_eq!(page[0]["id"], msg["id"]);
+        assert_eq!(page[0]["deleted_at"], tombstone["deleted_at"]);
+        assert_eq!(page[0]["content"], "");
+
+        let (status, _) = app.request(Method::DELETE, &uri, Some(&bob), None).await?;
+        assert_eq!(status, StatusCode::GONE);
+        let (status, _) = app
+            .request(
+                Method::PATCH,
+                &uri,
+                Some(&bob),
+                Some(json!({"content": "back"})),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::GONE);
+        let (status, _) = app.get(&format!("{uri}/history"), Some(&bob)).await?;
+        assert_eq!(status, StatusCode::GONE);
+        let (status, _) = app
+            .post(
+                &format!("{uri}/reactions"),
+                Some(&alice),
+                json!({"emoji": "👍"}),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::GONE);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_moderator_deletes_message() -> Result<()> {
+        let Sent {
+            app,
+            alice,
+            bob,
+            carol,
+            carol_id,
+            msg,
+            ..
+        } = setup().await?;
+        let (_, other) = app
+            .post(
+                "/users/message/send_message/1",
+                Some(&bob),
+                json!({"content": "spam", "files": []}),
+            )
+            .await?;
+        let (_, chat) = app.get("/users/message/1", Some(&alice)).await?;
+        assert_eq!(chat.as_array().unwrap().len(), 2);
+
+        // the owner moderates the chat
+        let uri = format!("/users/message/1/{}", msg["id"]);
+        let (status, _) = app
+            .request(Method::DELETE, &uri, Some(&alice), None)
+            .await?;
+        assert_eq!(status, StatusCode::OK);
+
+        let uri = format!("/users/message/1/{}", other["id"]);
+        let (status, _) = app
+            .request(Method::DELETE, &uri, Some(&carol), None)
+            .await?;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        app.post(
+            "/users/chats/1/moderators",
+            Some(&alice),
+            json!({"user_id": carol_id, "moderator": true}),
+        )
+        .await?;
+        let (status, _) = app
+            .request(Method::DELETE, &uri, Some(&carol), None)
+            .await?;
+        assert_eq!(status, StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_toggle_reactions() -> Result<()> {
+        let Sent {
+            app,
+            alice,
+            bob,
+            carol,
+            msg,
+            ..
+        } = setup().await?;
+        let uri = format!("/users/message/1/{}/reactions", msg["id"]);
+        let react = async |token: &str, emoji: &str| {
+            app.post(&uri, Some(token), json!({"emoji": emoji})).await
+        };
+
+        react(&alice, "👍").await?;
+        react(&carol, "🎉").await?;
+        let (status, msg) = react(&carol, "👍").await?;
+        assert_eq!(status, StatusCode::OK);
+        let users = |v: &Value| v["user_ids"].as_array().unwrap().len();
+        let reactions = msg["reactions"].as_array().unwrap();
+        assert_eq!(reactions.len(), 2);
+        assert_eq!(reactions[0]["emoji"], "👍");
+        assert_eq!(reactions[0]["count"], 2);
+        assert_eq!(users(&reactions[0]), 2);
+        assert_eq!(reactions[1]["emoji"], "🎉");
+        assert_eq!(reactions[1]["count"], 1);
+
+        // reacting again takes it back, an emoji nobody uses any more is dropped
+        react(&alice, "👍").await?;
+        let (_, msg) = react(&carol, "🎉").await?;
+        assert_eq!(msg["reactions"].as_array().unwrap().len(), 1);
+        assert_eq!(msg["reactions"][0]["count"], 1);
+        let (_, page) = app.get("/users/message/1", Some(&bob)).await?;
+        assert_eq!(page[0]["reactions"], msg["reactions"]);
+
+        for emoji in ["", "a b", &"x".repeat(65)] {
+            let (status, _) = react(&bob, emoji).await?;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        }
+        let (status, _) = app
+            .post(
+                "/users/message/1/999/reactions",
+                Some(&bob),
+                json!({"emoji": "👍"}),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_thread_replies() -> Result<()> {
+        let Sent {
+            app,
+            alice,
+            bob,
+            carol,
+            msg,
+            ..
+        } = setup().await?;
+        let reply = async |token: &str, reply_to: &Value| {
+            app.post(
+                "/users/message/send_message/1",
+                Some(token),
+                json!({"content": "re", "files": [], "reply_to": reply_to}),
+            )
+            .await
+        };
+        let (status, first) = reply(&alice, &msg["id"]).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(first["reply_to"], msg["id"]);
+        // replying to a reply stays in the thread of the root
+        let (_, second) = reply(&carol, &first["id"]).await?;
+        assert_eq!(second["reply_to"], msg["id"]);
+        app.post(
+            "/users/message/send_message/1",
+            Some(&bob),
+            json!({"content": "unrelated", "files": []}),
+        )
+        .await?;
+
+        let ids = |v: &Value| {
+            v.as_array()
+                .unwrap()
+                .iter()
+                .map(|m| m["id"].clone())
+                .collect::<Vec<_>>()
+        };
+        let thread_uri = format!("/users/message/1/{}/thread", msg["id"]);
+        let (status, thread) = app.get(&thread_uri, Some(&bob)).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(thread["root"]["id"], msg["id"]);
+        assert_eq!(
+            ids(&thread["replies"]),
+            vec![first["id"].clone(), second["id"].clone()]
+        );
+        // any message of the thread leads to the whole thread
+        let (_, same) = app
+            .get(
+                &format!("/users/message/1/{}/thread", second["id"]),
+                Some(&bob),
+            )
+            .await?;
+        assert_eq!(same["root"]["id"], msg["id"]);
+        let (_, page) = app
+            .get(
+                &format!("{thread_uri}?after={}&limit=5", first["id"]),
+                Some(&bob),
+            )
+            .await?;
+        assert_eq!(ids(&page["replies"]), vec![second["id"].clone()]);
+
+        // the thread outlives its deleted root
+        let root_uri = format!("/users/message/1/{}", msg["id"]);
+        app.request(Method::DELETE, &root_uri, Some(&bob), None)
+            .await?;
+        let (_, thread) = app.get(&thread_uri, Some(&bob)).await?;
+        assert!(thread["root"]["deleted_at"].is_string());
+        assert_eq!(thread["replies"].as_array().unwrap().len(), 2);
+        let (status, _) = reply(&alice, &msg["id"]).await?;
+        assert_eq!(status, StatusCode::GONE);
+        let (status, _) = reply(&alice, &json!(999)).await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (eve, _) = app.signup("eve").await?;
+        let (status, _) = app.get(&thread_uri, Some(&eve)).await?;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mentions_inbox() -> Result<()> {
+        let Sent {
+            app,
+            alice,
+            bob,
+            carol,
+            bob_id,
+            carol_id,
+            ..
+        } = setup().await?;
+        let (eve, eve_user) = app.signup("eve").await?;
+        let (_, mentioned) = app
+            .post(
+                "/users/message/send_message/1",
+                Some(&alice),
+                json!({
+                    "content": "@Carol and @bob, mail bob@example.com, not @eve or @nobody. thanks @carol.",
+                    "files": [],
+                }),
+            )
+            .await?;
+        assert_eq!(mentioned["mentions"], json!([carol_id, bob_id]));
+        assert!(
+            !mentioned["mentions"]
+                .as_array()
+                .unwrap()
+                .contains(&eve_user["id"])
+        );
+
+        let (status, inbox) = app.get("/users/mentions", Some(&carol)).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(inbox.as_array().unwrap().len(), 1);
+        assert_eq!(inbox[0]["id"], mentioned["id"]);
+        let (_, inbox) = app.get("/users/mentions", Some(&eve)).await?;
+        assert_eq!(inbox, json!([]));
+
+        // editing the mention away takes the message out of the inbox
+        let uri = format!("/users/message/1/{}", mentioned["id"]);
+        let (_, edited) = app
+            .request(
+                Method::PATCH,
+                &uri,
+                Some(&alice),
+                Some(json!({"content": "never mind @bob"})),
+            )
+            .await?;
+        assert_eq!(edited["mentions"], json!([bob_id]));
+        let (_, inbox) = app.get("/users/mentions", Some(&carol)).await?;
+        assert_eq!(inbox, json!([]));
+
+        // only chats the user is still in
+        let (_, inbox) = app.get("/users/mentions", Some(&bob)).await?;
+        assert_eq!(inbox.as_array().unwrap().len(), 1);
+        app.post("/users/chats/1/leave", Some(&bob), json!({}))
+            .await?;
+        let (_, inbox) = app.get("/users/mentions", Some(&bob)).await?;
+        assert_eq!(inbox, json!([]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_messages_are_rate_limited_per_user() -> Result<()> {
+        let app = TestApp::with_rate_limits(RateLimitConfig {
+            messages: Some(RateLimit {
+                burst: 2,
+                per_second: 0.5,
+            }),
+            api: None,
+            ..Default::default()
+        })?;
+        let (alice, _) = app.signup("alice").await?;
+        let (bob, _) = app.signup("bob").await?;
+        app.post("/users/create_chat", Some(&alice), json!({"name": "rust"}))
+            .await?;
+        app.post("/users/create_chat", Some(&bob), json!({"name": "go"}))
+            .await?;
+        let hello = json!({"content": "hello", "files": []});
+        for _ in 0..2 {
+            let (status, _) = app
+                .post("/users/message/send_message/1", Some(&alice), hello.clone())
+                .await?;
+            assert_eq!(status, StatusCode::OK);
+        }
+        let (status, _) = app
+            .post("/users/message/send_message/1", Some(&alice), hello.clone())
+            .await?;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        let (status, headers, _) = app.download("/users/message/1", &alice, &[]).await?;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(headers["retry-after"], "2");
+
+        // other users and the other routes have their own buckets
+        let (status, _) = app
+            .post("/users/message/send_message/2", Some(&bob), hello)
+            .await?;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = app.get("/users/chats", Some(&alice)).await?;
+        assert_eq!(status, StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_search_messages() -> Result<()> {
+        let Sent {
+            app,
+            alice,
+            bob,
+            carol,
+            bob_id,
+            ..
+        } = setup().await?;
+        app.post("/users/create_chat", Some(&alice), json!({"name": "go"}))
+            .await?;
+        let mut sent = vec![];
+        for (token, chat_id, content) in [
+            (&bob, 1, "Rust <b>async</b> is great"),
+            (&alice, 1, "rust, rust and Rust"),
+            (&carol, 1, "go is fine"),
+            (&alice, 2, "rust in another chat"),
+        ] {
+            let (_, msg) = app
+                .post(
+                    &format!("/users/message/send_message/{chat_id}"),
+                    Some(token),
+                    json!({"content": content, "files": []}),
+                )
+                .await?;
+            sent.push(msg["id"].clone());
+        }
+        let search = async |token: &str, query: &str| -> Result<Vec<Value>> {
+            let (status, hits) = app
+                .get(&format!("/users/search?{query}"), Some(token))
+                .await?;
+            assert_eq!(status, StatusCode::OK, "{hits}");
+            Ok(hits.as_array().unwrap().clone())
+        };
+        let ids = |hits: &[Value]| {
+            hits.iter()
+                .map(|hit| hit["message"]["id"].clone())
+                .collect::<Vec<_>>()
+        };
+
+        // only the chats the user is in, more matches rank higher
+        let hits = search(&carol, "q=RUST").await?;
+        assert_eq!(ids(&hits), vec![sent[1].clone(), sent[0].clone()]);
+        assert!(hits[0]["rank"].as_f64().unwrap() > hits[1]["rank"].as_f64().unwrap());
+        assert_eq!(
+            hits[1]["highlight"],
+            "<mark>Rust</mark> &lt;b&gt;async&lt;/b&gt; is great"
+        );
+        assert_eq!(search(&alice, "q=rust").await?.len(), 3);
+        assert_eq!(
+            ids(&search(&carol, "q=rust%20asy").await?),
+            vec![sent[0].clone()]
+        );
+        assert!(search(&carol, "q=rust%20fine").await?.is_empty());
+
+        // filters and paging
+        assert!(search(&carol, "q=rust&chat_id=2").await?.is_empty());
+        assert_eq!(search(&alice, "q=rust&chat_id=2").await?.len(), 1);
+        let hits = search(&carol, &format!("q=rust&sender_id={bob_id}")).await?;
+        assert_eq!(ids(&hits), vec![sent[0].clone()]);
+        assert!(
+            search(&carol, "q=rust&from=2100-01-01T00:00:00Z")
+                .await?
+                .is_empty()
+        );
+        assert_eq!(
+            search(&carol, "q=rust&to=2100-01-01T00:00:00Z")
+                .await?
+                .len(),
+            2
+        );
+        let hits = search(&carol, "q=rust&limit=1&offset=1").await?;
+        assert_eq!(ids(&hits), vec![sent[0].clone()]);
+
+        // edits are searched, deleted messages are not
+        let uri = format!("/users/message/1/{}", sent[0]);
+        app.request(
+            Method::PATCH,
+            &uri,
+            Some(&bob),
+            Some(json!({"content": "Zig is great"})),
+        )
+        .await?;
+        assert_eq!(ids(&search(&carol, "q=zig").await?), vec![sent[0].clone()]);
+        assert_eq!(search(&carol, "q=rust").await?.len(), 1);
+        app.request(Method::DELETE, &uri, Some(&bob), None).await?;
+        assert!(search(&carol, "q=zig").await?.is_empty());
+
+        let (status, _) = app.get("/users/search?q=%20!?", Some(&carol)).await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = app.get("/users/search?q=rust", None).await?;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mentioned_names() {
+        assert_eq!(
+            super::mentioned_names("@alice hi @bob_2, (@carol) @x.y. a@b.com @ @@dan"),
+            vec!["alice", "bob_2", "carol", "x.y", "dan"]
+        );
+    }
+}
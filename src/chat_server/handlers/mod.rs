@@ -0,0 +1,17 @@
+mod admin;
+mod chat;
+mod file;
+mod member;
+mod message;
+mod sse;
+mod user;
+mod ws;
+
+pub(crate) use admin::*;
+pub(crate) use chat::*;
+pub(crate) use file::*;
+pub(crate) use member::*;
+pub(crate) use message::*;
+pub(crate) use sse::*;
+pub(crate) use user::*;
+pub(crate) use ws::*;
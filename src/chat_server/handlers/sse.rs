@@ -0,0 +1,288 @@
+use std::collections::HashSet;
+
+use axum::{
+    Extension,
+    extract::State,
+    http::HeaderMap,
+    response::{
+        IntoResponse,
+        sse::{Event, KeepAlive, Sse},
+    },
+};
+use futures::{StreamExt, stream};
+use tracing::info;
+
+use super::session_ended;
+use crate::{AppError, AppState, ChatEvent, ChatSubscription, MAX_MESSAGE_LIMIT, TokenClaims};
+
+// at most this many missed messages are replayed, a client that missed more gets a `resync`
+// event and has to load the chats again
+const MAX_REPLAY_MESSAGES: usize = 1000;
+
+// same events as the websocket, for clients behind proxies that break websockets.
+// message events carry the message id as the sse id, so a reconnecting client sends it back
+// as Last-Event-ID and gets the messages it missed replayed from the message log. the stream
+// ends with the session, the client reconnects with a fresh token
+pub(crate) async fn events_handler(
+    State(state): State<AppState>,
+    Extension(claims): Extension<TokenClaims>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, AppError> {
+    let user = claims.user.clone();
+    let chats = state.store.list_user_chats(user.id).await?;
+    // subscribe before reading the log, so nothing sent in between is lost
+    let events = ChatSubscription::new(state.hub.clone(), user.id, chats.iter().map(|c| c.id));
+
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    let mut replay = vec![];
+    let mut resync = false;
+    if let Some(last_event_id) = last_event_id {
+        'chats: for chat in &chats {
+            let mut after = last_event_id;
+            loop {
+                let page = state
+                    .store
+                    .list_messages_after(chat.id, after, MAX_MESSAGE_LIMIT)
+                    .await?;
+                let Some(last) = page.last() else {
+                    break;
+                };
+                after = last.id;
+                replay.extend(page);
+                if replay.len() > MAX_REPLAY_MESSAGES {
+                    resync = true;
+                    break 'chats;
+                }
+            }
+        }
+        replay.sort_by_key(|msg| msg.id);
+    }
+    info!(
+        "{} subscribed to events, replaying {} messages{}",
+        user.name,
+        replay.len(),
+        if resync { ", too many missed" } else { "" }
+    );
+
+    // messages sent while the log was read come live too, skip those replayed by id. the log
+    // is read chat by chat, so a live message can be older than the newest replayed one
+    if resync {
+        replay.clear();
+    }
+    let replayed = replay.iter().map(|msg| msg.id).collect::<HashSet<_>>();
+    let live = stream::unfold(events, |mut events| async move {
+        events.recv().await.map(|event| (event, events))
+    })
+    .filter(move |event| {
+        let skip = matches!(event, ChatEvent::NewMessage(msg) if replayed.contains(&msg.id));
+        async move { !skip }
+    })
+    .map(|event| sse_event(&event))
+    .take_until(session_ended(state, claims));
+    let head = match resync {
+        // no id, so the client keeps its Last-Event-ID until the next live message
+        true => vec![Ok(Event::default().event("resync").data("{}"))],
+        false => replay
+            .into_iter()
+            .map(|msg| sse_event(&ChatEvent::NewMessage(msg)))
+            .collect(),
+    };
+    let stream = stream::iter(head).chain(live);
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+fn sse_event(event: &ChatEvent) -> Result<Event, axum::Error> {
+    let sse = Event::default().event(event.name()).json_data(event)?;
+    Ok(match event {
+        ChatEvent::NewMessage(msg) => sse.id(msg.id.to_string()),
+        _ => sse,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use axum::{body::Body, http::Request};
+    use http_body_util::BodyExt;
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    use super::MAX_REPLAY_MESSAGES;
+    use crate::{CreateMessage, chat_server::test_util::*};
+
+    // read sse frames until `n` events arrived
+    async fn read_events(body: &mut Body, n: usize) -> Result<Vec<(String, String)>> {
+        let mut events = vec![];
+        let mut buf = String::new();
+        while events.len() < n {
+            let frame = tokio::time::timeout(Duration::from_secs(5), body.frame())
+                .await?
+                .expect("stream ended")?;
+            if let Ok(data) = frame.into_data() {
+                buf.push_str(&String::from_utf8_lossy(&data));
+            }
+            while let Some(end) = buf.find("\n\n") {
+                let block = buf[..end].to_string();
+                buf.drain(..end + 2);
+                let field = |name: &str| {
+                    block
+                        .lines()
+                        .find_map(|l| l.strip_prefix(&format!("{name}:")))
+                        .unwrap_or_default()
+                        .trim()
+                        .to_string()
+                };
+                if !field("event").is_empty() {
+                    events.push((field("event"), field("data")));
+                }
+            }
+        }
+        Ok(events)
+    }
+
+    async fn subscribe(app: &TestApp, token: &str, last_event_id: Option<u64>) -> Result<Body> {
+        let mut req =
+            Request::get("/users/events").header("authorization", format!("Bearer {token}"));
+        if let Some(id) = last_event_id {
+            req = req.header("last-event-id", id.to_string());
+        }
+        let res = app.router.clone().oneshot(req.body(Body::empty())?).await?;
+        assert_eq!(res.headers()["content-type"], "text/event-stream");
+        Ok(res.into_body())
+    }
+
+    // straight into the store, faster than the api for many messages
+    async fn store_messages(app: &TestApp, chat_id: u32, sender_id: u32, n: usize) -> Result<()> {
+        for i in 0..n {
+            app.state
+                .store
+                .create_message(CreateMessage {
+                    chat_id,
+                    sender_id,
+                    content: format!("chat {chat_id} message {i}"),
+                    files: vec![],
+                    reply_to: None,
+                    mentions: vec![],
+                })
+                .await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_events_stream_messages_and_typing() -> Result<()> {
+        let app = TestApp::new();
+        let (alice, _) = app.signup("alice").await?;
+        let (bob, _) = app.signup("bob").await?;
+        app.post("/users/create_chat", Some(&alice), json!({"name": "rust"}))
+            .await?;
+        let mut body = subscribe(&app, &alice, None).await?;
+
+        app.post("/users/join_chat", Some(&bob), json!({"chat_id": 1}))
+            .await?;
+        app.post("/users/message/typing/1", Some(&bob), json!({}))
+            .await?;
+        app.post(
+            "/users/message/send_message/1",
+            Some(&bob),
+            json!({"content": "hello", "files": []}),
+        )
+        .await?;
+
+        let events = read_events(&mut body, 3).await?;
+        let names = events.iter().map(|(e, _)| e.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["join", "typing", "new_message"]);
+        assert!(events[2].1.contains("hello"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_events_resume_from_last_event_id() -> Result<()> {
+        let app = TestApp::new();
+        let (alice, _) = app.signup("alice").await?;
+        app.post("/users/create_chat", Some(&alice), json!({"name": "rust"}))
+            .await?;
+        let mut ids = vec![];
+        for content in ["one", "two", "three"] {
+            let (_, msg) = app
+                .post(
+                    "/users/message/send_message/1",
+                    Some(&alice),
+                    json!({"content": content, "files": []}),
+                )
+                .await?;
+            ids.push(msg["id"].as_u64().unwrap());
+        }
+
+        let mut body = subscribe(&app, &alice, Some(ids[0])).await?;
+        let events = read_events(&mut body, 2).await?;
+        assert!(events[0].1.contains("two"));
+        assert!(events[1].1.contains("three"));
+
+        app.post(
+            "/users/message/send_message/1",
+            Some(&alice),
+            json!({"content": "four", "files": []}),
+        )
+        .await?;
+        let events = read_events(&mut body, 1).await?;
+        assert!(events[0].1.contains("four"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_events_replay_every_missed_message() -> Result<()> {
+        let app = TestApp::new();
+        let (alice, alice_user) = app.signup("alice").await?;
+        let alice_id = alice_user["id"].as_u64().unwrap() as u32;
+        for name in ["rust", "go"] {
+            app.post("/users/create_chat", Some(&alice), json!({"name": name}))
+                .await?;
+        }
+        // more than a page in the first chat, and the newest message in the second one
+        store_messages(&app, 1, alice_id, 1).await?;
+        store_messages(&app, 1, alice_id, 150).await?;
+        store_messages(&app, 2, alice_id, 3).await?;
+        store_messages(&app, 1, alice_id, 2).await?;
+        store_messages(&app, 2, alice_id, 1).await?;
+
+        let mut body = subscribe(&app, &alice, Some(1)).await?;
+        let events = read_events(&mut body, 156).await?;
+        let ids = events
+            .iter()
+            .map(|(_, data)| {
+                serde_json::from_str::<serde_json::Value>(data).unwrap()["id"].as_u64()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(ids, (2..=157).map(Some).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_events_resync_when_too_many_missed() -> Result<()> {
+        let app = TestApp::new();
+        let (alice, alice_user) = app.signup("alice").await?;
+        let alice_id = alice_user["id"].as_u64().unwrap() as u32;
+        app.post("/users/create_chat", Some(&alice), json!({"name": "rust"}))
+            .await?;
+        store_messages(&app, 1, alice_id, MAX_REPLAY_MESSAGES + 2).await?;
+
+        let mut body = subscribe(&app, &alice, Some(1)).await?;
+        app.post(
+            "/users/message/send_message/1",
+            Some(&alice),
+            json!({"content": "live", "files": []}),
+        )
+        .await?;
+        let events = read_events(&mut body, 2).await?;
+        assert_eq!(events[0].0, "resync");
+        assert_eq!(events[1].0, "new_message");
+        assert!(events[1].1.contains("live"));
+        Ok(())
+    }
+}
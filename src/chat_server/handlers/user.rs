@@ -0,0 +1,561 @@
+use std::cmp::Reverse;
+
+use axum::{
+    Extension, Json,
+    extract::{Query, State, rejection::JsonRejection},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use chrono::{Duration, Utc};
+use tracing::{info, instrument};
+
+use crate::{
+    ACCESS_TOKEN_DURATION, AppError, AppState, CreateUser, JwtUser, MAX_EMAIL_LEN, MAX_GENDER_LEN,
+    MAX_USER_NAME_LEN, MAX_WORKSPACE_NAME_LEN, REFRESH_TOKEN_DURATION, RefreshRequest,
+    SigninOutput, SigninRequest, SignoutRequest, SignupOutput, TokenClaims, User,
+    UserCreateRequest, UserFilter, UserType, WORKSPACE_INVITE_DURATION, WorkspaceInvite,
+    WorkspaceInviteOutput, WorkspaceInviteRequest, generate_refresh_token,
+    hash_password_with_argon2, hash_refresh_token, verify_password_with_argon2,
+};
+
+// a new access token plus a new refresh token, the refresh token is stored hashed
+async fn issue_tokens(state: &AppState, user: &User) -> Result<SigninOutput, AppError> {
+    let token = state.keys.key_set().sign(JwtUser::from(user))?;
+    let refresh_token = generate_refresh_token();
+    let expires_at = Utc::now() + Duration::seconds(REFRESH_TOKEN_DURATION as i64);
+    state
+        .store
+        .create_refresh_token(&hash_refresh_token(&refresh_token), user.id, expires_at)
+        .await?;
+    Ok(SigninOutput {
+        token,
+        refresh_token,
+        expires_in: ACCESS_TOKEN_DURATION,
+    })
+}
+
+#[instrument(skip(state))]
+pub(crate) async fn list_users_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<JwtUser>,
+) -> Result<impl IntoResponse, AppError> {
+    let users = state.store.list_users(user.ws_id).await?;
+    Ok(Json(users))
+}
+
+#[instrument(skip(state, payload))]
+pub(crate) async fn signup_handler(
+    State(state): State<AppState>,
+    payload: Result<Json<UserCreateRequest>, JsonRejection>,
+) -> Result<impl IntoResponse, AppError> {
+    let Json(payload) = match payload {
+        Ok(v) => v,
+        Err(e) => return Err(AppError::CreateUserError(format!("invalid json: {e}"))),
+    };
+
+    let Some(gender) = payload.gender else {
+        return Err(AppError::CreateUserError("gender is required".into()));
+    };
+    //the columns would reject longer values
+    for (field, value, max) in [
+        ("name", &payload.name, MAX_USER_NAME_LEN),
+        ("email", &payload.email, MAX_EMAIL_LEN),
+        ("gender", &gender, MAX_GENDER_LEN),
+    ] {
+        if value.is_empty() || value.chars().count() > max {
+            return Err(AppError::CreateUserError(format!(
+                "{field} must be 1 to {max} characters"
+            )));
+        }
+    }
+    //find whether the user with the same email already exists, the store checks again
+    //when inserting, for signups racing each other
+    if state
+        .store
+        .find_user_by_email(&payload.email)
+        .await?
+        .is_some()
+    {
+        return Err(AppError::CreateUserError("email already exists".into()));
+    }
+    let hashed_password = hash_password_with_argon2(&payload.password)?;
+
+    //create a new workspace and become its owner, or join one with an invitation
+    let (ws, created) = match (payload.workspace, payload.invite_token) {
+        (Some(workspace), None) => {
+            let workspace = workspace.trim();
+            if workspace.is_empty() || workspace.chars().count() > MAX_WORKSPACE_NAME_LEN {
+                return Err(AppError::CreateUserError(format!(
+                    "workspace name must be 1 to {MAX_WORKSPACE_NAME_LEN} characters"
+                )));
+            }
+            let Some(ws) = state.store.create_workspace(workspace).await? else {
+                return Err(AppError::WorkspaceExists(format!(
+                    "workspace {workspace} already exists, ask its owner for an invitation"
+                )));
+            };
+            (ws, true)
+        }
+        (None, Some(token)) => {
+            let invite = state.keys.key_set().verify_workspace_invite(&token)?;
+            if invite.email != payload.email {
+                return Err(AppError::PermissionDenied(
+                    "the invitation is for another email".into(),
+                ));
+            }
+            let Some(ws) = state.store.get_workspace(invite.ws_id).await? else {
+                return Err(AppError::CreateUserError("workspace not found".into()));
+            };
+            (ws, false)
+        }
+        _ => {
+            return Err(AppError::CreateUserError(
+                "either workspace or invite_token is required".into(),
+            ));
+        }
+    };
+    let user = state
+        .store
+        .create_user(CreateUser {
+            ws_id: ws.id,
+            name: payload.name,
+            email: payload.email,
+            gender,
+            teenager: payload.teenager,
+            hash_password: hashed_password,
+        })
+        .await;
+    let user = match user {
+        Ok(user) => user,
+        //give the name back, or the retry would find it taken
+        Err(e) if created => {
+            state.store.delete_empty_workspace(ws.id).await?;
+            return Err(e);
+        }
+        Err(e) => return Err(e),
+    };
+    if created {
+        state.store.set_workspace_owner(ws.id, user.id).await?;
+    }
+    let tokens = issue_tokens(&state, &user).await?;
+    Ok((StatusCode::CREATED, Json(SignupOutput { tokens, user })))
+}
+
+//an invitation for one email to sign up into the workspace, from its owner or an admin
+#[instrument(skip(state))]
+pub(crate) async fn workspace_invite_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<JwtUser>,
+    Json(input): Json<WorkspaceInviteRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let Some(ws) = state.store.get_workspace(user.ws_id).await? else {
+        return Err(AppError::UserNotFound("workspace not found".into()));
+    };
+    if ws.owner_id != Some(user.id) && user.user_type != UserType::Admin {
+        return Err(AppError::PermissionDenied(
+            "only the owner or an admin can invite into the workspace".into(),
+        ));
+    }
+    let token = state
+        .keys
+        .key_set()
+        .sign_workspace_invite(WorkspaceInvite {
+            ws_id: ws.id,
+            email: input.email,
+        })?;
+    Ok((
+        StatusCode::CREATED,
+        Json(WorkspaceInviteOutput {
+            token,
+            expires_at: Utc::now() + Duration::seconds(WORKSPACE_INVITE_DURATION as i64),
+        }),
+    ))
+}
+
+#[instrument(skip(state))]
+pub(crate) async fn query_users_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<JwtUser>,
+    Query(query): Query<UserFilter>,
+) -> Result<impl IntoResponse, AppError> {
+    let ordering = query.ordering.unwrap_or_else(|| "asc".to_string());
+    let mut users = state.store.list_users(user.ws_id).await?;
+    if ordering == "asc" {
+        users.sort_by_key(|user| user.id);
+    } else {
+        users.sort_by_key(|user| Reverse(user.id));
+    }
+    users.truncate(query.limit as usize);
+    Ok(Json(users))
+}
+
+#[instrument(skip(state, signin_request))]
+pub(crate) async fn signin_handler(
+    State(state): State<AppState>,
+    Json(signin_request): Json<SigninRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    //first, find if the user with the email exists
+    let Some(user) = state
+        .store
+        .find_user_by_email(&signin_request.email)
+        .await?
+    else {
+        return Err(AppError::UserNotFound("email not found".into()));
+    };
+    //second, verify the password with argon2 and the hash_password stored in the store
+    if !verify_password_with_argon2(&signin_request.password, &user.hash_password) {
+        return Err(AppError::PasswordVerifyError("invalid password".into()));
+    }
+    //third ,return the jwt and the refresh token
+    let tokens = issue_tokens(&state, &user).await?;
+    Ok((StatusCode::OK, Json(tokens)))
+}
+
+//the public keys tokens are signed with, for services verifying them on their own
+pub(crate) async fn jwks_handler(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.keys.key_set().jwks())
+}
+
+//exchange a refresh token for a new pair, the old refresh token can not be used again
+#[instrument(skip(state, payload))]
+pub(crate) async fn refresh_handler(
+    State(state): State<AppState>,
+    Json(payload): Json<RefreshRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let Some(refresh_token) = state
+        .store
+        .take_refresh_token(&hash_refresh_token(&payload.refresh_token))
+        .await?
+    else {
+        return Err(AppError::JwtVerifyError("invalid refresh token".into()));
+    };
+    if refresh_token.expires_at <= Utc::now() {
+        return Err(AppError::JwtVerifyError("refresh token expired".into()));
+    }
+    let Some(user) = state.store.get_user(refresh_token.user_id).await? else {
+        return Err(AppError::UserNotFound("user not found".into()));
+    };
+    let tokens = issue_tokens(&state, &user).await?;
+    Ok((StatusCode::OK, Json(tokens)))
+}
+
+//revoke the access token used for this request, and the refresh token if it is given and
+//belongs to the same user
+#[instrument(skip(state, claims, payload))]
+pub(crate) async fn signout_handler(
+    State(state): State<AppState>,
+    Extension(claims): Extension<TokenClaims>,
+    payload: Option<Json<SignoutRequest>>,
+) -> Result<impl IntoResponse, AppError> {
+    let payload = payload.map(|Json(p)| p).unwrap_or_default();
+    if let Some(refresh_token) = payload.refresh_token {
+        state
+            .store
+            .delete_refresh_token(&hash_refresh_token(&refresh_token), claims.user.id)
+            .await?;
+    }
+    state
+        .store
+        .revoke_token(&claims.jti, claims.expires_at)
+        .await?;
+    info!("{} signed out", claims.user.name);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use axum::http::StatusCode;
+    use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+    use jwt_simple::prelude::*;
+    use serde_json::{Value, json};
+
+    use crate::{Jwk, JwtUser, RateLimit, RateLimitConfig, chat_server::test_util::*};
+
+    #[tokio::test]
+    async fn test_signup_and_signin() -> Result<()> {
+        let app = TestApp::new();
+        let (_, user) = app.signup("alice").await?;
+        assert_eq!(user["name"], "alice");
+        assert!(user.get("hash_password").is_none());
+
+        let (status, body) = app
+            .post(
+                "/users/signin",
+                None,
+                json!({"email": "alice@example.com", "password": "password123"}),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["token"].is_string());
+
+        let (status, _) = app
+            .post(
+                "/users/signin",
+                None,
+                json!({"email": "alice@example.com", "password": "wrong"}),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_refresh_rotates_refresh_token() -> Result<()> {
+        let app = TestApp::new();
+        app.signup("alice").await?;
+        let (_, tokens) = app
+            .post(
+                "/users/signin",
+                None,
+                json!({"email": "alice@example.com", "password": "password123"}),
+            )
+            .await?;
+        assert!(tokens["expires_in"].as_u64().unwrap() <= 15 * 60);
+        let refresh_token = tokens["refresh_token"].clone();
+
+        let (status, refreshed) = app
+            .post(
+                "/users/refresh",
+                None,
+                json!({"refresh_token": refresh_token}),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_ne!(refreshed["refresh_token"], refresh_token);
+        let token = refreshed["token"].as_str().unwrap();
+        let (status, _) = app
+            .post("/users/create_chat", Some(token), json!({"name": "rust"}))
+            .await?;
+        assert_eq!(status, StatusCode::CREATED);
+
+        // a refresh token works only once
+        let (status, _) = app
+            .post(
+                "/users/refresh",
+                None,
+                json!({"refresh_token": refresh_token}),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_signout_revokes_tokens() -> Result<()> {
+        let app = TestApp::new();
+        app.signup("alice").await?;
+        let (_, tokens) = app
+            .post(
+                "/users/signin",
+                None,
+                json!({"email": "alice@example.com", "password": "password123"}),
+            )
+            .await?;
+        let token = tokens["token"].as_str().unwrap();
+
+        let (status, _) = app
+            .post(
+                "/users/signout",
+                Some(token),
+                json!({"refresh_token": tokens["refresh_token"]}),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let (status, _) = app
+            .post("/users/create_chat", Some(token), json!({"name": "rust"}))
+            .await?;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = app
+            .post(
+                "/users/refresh",
+                None,
+                json!({"refresh_token": tokens["refresh_token"]}),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_signout_keeps_other_users_refresh_tokens() -> Result<()> {
+        let app = TestApp::new();
+        let (alice, _) = app.signup("alice").await?;
+        app.signup("bob").await?;
+        let (_, tokens) = app
+            .post(
+                "/users/signin",
+                None,
+                json!({"email": "bob@example.com", "password": "password123"}),
+            )
+            .await?;
+
+        let (status, _) = app
+            .post(
+                "/users/signout",
+                Some(&alice),
+                json!({"refresh_token": tokens["refresh_token"]}),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = app
+            .post(
+                "/users/refresh",
+                None,
+                json!({"refresh_token": tokens["refresh_token"]}),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_jwks_verifies_tokens() -> Result<()> {
+        let app = TestApp::new();
+        let (token, _) = app.signup("alice").await?;
+        let (status, jwks) = app.get("/.well-known/jwks.json", None).await?;
+        assert_eq!(status, StatusCode::OK);
+        let jwk: Jwk = serde_json::from_value(jwks["keys"][0].clone())?;
+        assert_eq!((jwk.kty.as_str(), jwk.crv.as_str()), ("OKP", "Ed25519"));
+
+        // another service can verify our tokens with nothing but the jwk
+        let pk = Ed25519PublicKey::from_bytes(&URL_SAFE_NO_PAD.decode(&jwk.x)?)?;
+        let metadata = Token::decode_metadata(&token)?;
+        assert_eq!(metadata.key_id(), Some(jwk.kid.as_str()));
+        assert!(pk.verify_token::<JwtUser>(&token, None).is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_query_users_ordering() -> Result<()> {
+        let app = TestApp::new();
+        let (admin, _) = app.signup_admin("root").await?;
+        for name in ["alice", "bob", "tom"] {
+            app.signup(name).await?;
+        }
+        let (status, users) = app
+            .get("/users/list_with_query?limit=2&ordering=desc", Some(&admin))
+            .await?;
+        assert_eq!(status, StatusCode::OK);
+        let names = users
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|u| u["name"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["tom", "bob"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_signup_duplicate_email() -> Result<()> {
+        let app = TestApp::new();
+        app.signup("alice").await?;
+        let (status, body) = app
+            .post(
+                "/users/create",
+                None,
+                json!({"name": "alice", "email": "alice@example.com", "gender": "female", "workspace": "acme", "password": "x"}),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "email already exists");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_signup_joins_workspaces_by_invitation() -> Result<()> {
+        let app = TestApp::new();
+        let (alice, alice_user) = app.signup("alice").await?;
+        let (bob, _) = app.signup("bob").await?;
+        let signup = |email: &str, ws: Value| {
+            let mut body =
+                json!({"name": "eve", "email": email, "gender": "female", "password": "x"});
+            body.as_object_mut()
+                .unwrap()
+                .extend(ws.as_object().unwrap().clone());
+            app.post("/users/create", None, body)
+        };
+
+        // nobody gets into a workspace by knowing its name
+        let (status, _) = signup("eve@example.com", json!({"workspace": "acme"})).await?;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let invite = json!({"email": "eve@example.com"});
+        let (status, _) = app
+            .post("/users/workspace/invite", Some(&bob), invite.clone())
+            .await?;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, body) = app
+            .post("/users/workspace/invite", Some(&alice), invite)
+            .await?;
+        assert_eq!(status, StatusCode::CREATED);
+        let token = body["token"].clone();
+
+        let (status, _) = signup("mallory@example.com", json!({"invite_token": token})).await?;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = signup("eve@example.com", json!({"invite_token": "x"})).await?;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, body) = signup("eve@example.com", json!({"invite_token": token})).await?;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["user"]["ws_id"], alice_user["ws_id"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_signup_rejects_long_fields() -> Result<()> {
+        let app = TestApp::new();
+        let (status, body) = app
+            .post(
+                "/users/create",
+                None,
+                json!({"name": "a".repeat(65), "email": "alice@example.com", "gender": "female", "workspace": "acme", "password": "x"}),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "name must be 1 to 64 characters");
+        let (status, _) = app
+            .post(
+                "/users/create",
+                None,
+                json!({"name": "alice", "email": "alice@example.com", "gender": "f".repeat(17), "workspace": "acme", "password": "x"}),
+            )
+            .await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_signin_is_rate_limited() -> Result<()> {
+        let app = TestApp::with_rate_limits(RateLimitConfig {
+            auth: Some(RateLimit {
+                burst: 3,
+                per_second: 0.01,
+            }),
+            ..Default::default()
+        })?;
+        app.signup("alice").await?;
+        let signin = json!({"email": "alice@example.com", "password": "wrong"});
+        let (status, _) = app.post("/users/signin", None, signin.clone()).await?;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = app.post("/users/signin", None, signin.clone()).await?;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        // sign up, sign in and refresh share the bucket of the client
+        let (status, body) = app.post("/users/signin", None, signin).await?;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        // tokens trickle back while the passwords are hashed
+        assert!(
+            body["error"]
+                .as_str()
+                .unwrap()
+                .starts_with("too many requests, retry after")
+        );
+        let (status, _) = app
+            .post("/users/refresh", None, json!({"refresh_token": "x"}))
+            .await?;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        Ok(())
+    }
+}
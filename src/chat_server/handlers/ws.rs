@@ -0,0 +1,265 @@
+use std::time::Duration;
+
+use axum::{
+    extract::{
+        Query, State, WebSocketUpgrade,
+        ws::{CloseFrame, Message as WsMessage, WebSocket, close_code},
+    },
+    http::{HeaderMap, header::SEC_WEBSOCKET_PROTOCOL},
+    response::IntoResponse,
+};
+use chrono::Utc;
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::{AppError, AppState, ChatSubscription, TokenClaims};
+
+// browsers cannot set the Authorization header on a websocket, so the jwt comes either
+// as `?token=` or as `Sec-WebSocket-Protocol: jwt, <token>`
+const WS_PROTOCOL: &str = "jwt";
+// how often an open connection checks that its token is not signed out and its user is not
+// deleted or given another role
+const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct WsQuery {
+    token: Option<String>,
+}
+
+pub(crate) async fn ws_handler(
+    State(state): State<AppState>,
+    Query(query): Query<WsQuery>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Result<impl IntoResponse, AppError> {
+    let (token, ws) = match query.token {
+        Some(token) => (token, ws),
+        None => {
+            let Some(token) = protocol_token(&headers) else {
+                return Err(AppError::JwtVerifyError(
+                    "missing token in query or Sec-WebSocket-Protocol".into(),
+                ));
+            };
+            (token, ws.protocols([WS_PROTOCOL]))
+        }
+    };
+    let claims = state.verify_token(&token).await?;
+    let user_id = claims.user.id;
+    // subscribe before answering the upgrade, so no event after the handshake is missed
+    let chats = state.store.list_user_chats(user_id).await?;
+    let events = ChatSubscription::new(state.hub.clone(), user_id, chats.iter().map(|c| c.id));
+    Ok(ws.on_upgrade(move |socket| handle_socket(socket, state, claims, events)))
+}
+
+// the token is only checked when connecting, a connection lives as long as its token would
+// pass. resolves when the token expires or AppState::check_claims refuses it
+pub(crate) async fn session_ended(state: AppState, claims: TokenClaims) {
+    let ttl = (claims.expires_at - Utc::now())
+        .to_std()
+        .unwrap_or_default();
+    let expired = tokio::time::sleep(ttl);
+    tokio::pin!(expired);
+    let mut checks = tokio::time::interval(SESSION_CHECK_INTERVAL);
+    // the first tick is right away, the token was just checked
+    checks.tick().await;
+    loop {
+        tokio::select! {
+            _ = &mut expired => {
+                info!("token of {} expired", claims.user.name);
+                return;
+            }
+            _ = checks.tick() => match state.check_claims(&claims).await {
+                Ok(()) => {}
+                Err(e @ AppError::JwtVerifyError(_)) => {
+                    info!("session of {} ended: {e}", claims.user.name);
+                    return;
+                }
+                // keep the connection through a store hiccup, the next check decides
+                Err(e) => warn!("check session of {} failed: {e}", claims.user.name),
+            },
+        }
+    }
+}
+
+fn protocol_token(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(SEC_WEBSOCKET_PROTOCOL)?.to_str().ok()?;
+    let mut protocols = value.split(',').map(str::trim);
+    if protocols.next()? != WS_PROTOCOL {
+        return None;
+    }
+    protocols.next().map(|token| token.to_string())
+}
+
+// push every event of the user's chats until the client goes away or the session ends
+async fn handle_socket(
+    mut socket: WebSocket,
+    state: AppState,
+    claims: TokenClaims,
+    mut events: ChatSubscription,
+) {
+    let user = claims.user.clone();
+    info!("{} connected to websocket", user.name);
+    let ended = session_ended(state, claims);
+    tokio::pin!(ended);
+    loop {
+        let event = tokio::select! {
+            _ = &mut ended => {
+                let close = CloseFrame {
+                    code: close_code::POLICY,
+                    reason: "session ended".into(),
+                };
+                let _ = socket.send(WsMessage::Close(Some(close))).await;
+                break;
+            }
+            Some(event) = events.recv() => event,
+            msg = socket.recv() => match msg {
+                Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+        };
+        let text = match serde_json::to_string(&event) {
+            Ok(text) => text,
+            Err(e) => {
+                warn!("serialize event failed: {e}");
+                continue;
+            }
+        };
+        if socket.send(WsMessage::Text(text.into())).await.is_err() {
+            break;
+        }
+    }
+    info!("{} disconnected from websocket", user.name);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use futures::StreamExt;
+    use serde_json::{Value, json};
+    use tokio_tungstenite::{
+        connect_async,
+        tungstenite::{Message as WsMessage, client::IntoClientRequest},
+    };
+
+    use super::{SESSION_CHECK_INTERVAL, session_ended};
+    use crate::chat_server::test_util::*;
+
+    async fn next_json<S>(ws: &mut S) -> Result<Value>
+    where
+        S: futures::Stream<Item = Result<WsMessage, tokio_tungstenite::tungstenite::Error>> + Unpin,
+    {
+        loop {
+            let msg = tokio::time::timeout(Duration::from_secs(5), ws.next())
+                .await?
+                .expect("websocket closed")?;
+            if let WsMessage::Text(text) = msg {
+                return Ok(serde_json::from_str(&text)?);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ws_pushes_messages_and_joins() -> Result<()> {
+        let app = TestApp::new();
+        let addr = app.serve().await?;
+        let (alice, _) = app.signup("alice").await?;
+        let (bob, bob_user) = app.signup("bob").await?;
+        app.post("/users/create_chat", Some(&alice), json!({"name": "rust"}))
+            .await?;
+
+        let (mut alice_ws, _) =
+            connect_async(format!("ws://{addr}/users/ws?token={alice}")).await?;
+        let mut req = format!("ws://{addr}/users/ws").into_client_request()?;
+        req.headers_mut()
+            .insert("sec-websocket-protocol", format!("jwt, {bob}").parse()?);
+        let (mut bob_ws, res) = connect_async(req).await?;
+        assert_eq!(res.headers()["sec-websocket-protocol"], "jwt");
+
+        app.post("/users/join_chat", Some(&bob), json!({"chat_id": 1}))
+            .await?;
+        let event = next_json(&mut alice_ws).await?;
+        assert_eq!(event["type"], "join");
+        assert_eq!(event["user_id"], bob_user["id"]);
+        let event = next_json(&mut bob_ws).await?;
+        assert_eq!(event["type"], "join");
+
+        app.post(
+            "/users/message/send_message/1",
+            Some(&alice),
+            json!({"content": "hello", "files": []}),
+        )
+        .await?;
+        for ws in [&mut alice_ws, &mut bob_ws] {
+            let event = next_json(ws).await?;
+            assert_eq!(event["type"], "new_message");
+            assert_eq!(event["content"], "hello");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ws_rejects_bad_token() -> Result<()> {
+        let app = TestApp::new();
+        let addr = app.serve().await?;
+        assert!(
+            connect_async(format!("ws://{addr}/users/ws?token=bad"))
+                .await
+                .is_err()
+        );
+        assert!(
+            connect_async(format!("ws://{addr}/users/ws"))
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ws_leave_is_pushed_once() -> Result<()> {
+        let app = TestApp::new();
+        let addr = app.serve().await?;
+        let (alice, _) = app.signup("alice").await?;
+        let (bob, bob_user) = app.signup("bob").await?;
+        app.post("/users/create_chat", Some(&alice), json!({"name": "rust"}))
+            .await?;
+        app.post("/users/join_chat", Some(&bob), json!({"chat_id": 1}))
+            .await?;
+        let (mut bob_ws, _) = connect_async(format!("ws://{addr}/users/ws?token={bob}")).await?;
+
+        app.post("/users/chats/1/leave", Some(&bob), json!({}))
+            .await?;
+        let event = next_json(&mut bob_ws).await?;
+        assert_eq!(event["type"], "leave");
+        assert_eq!(event["user_id"], bob_user["id"]);
+        // the next event is the new chat, not the leave again
+        app.post("/users/create_chat", Some(&bob), json!({"name": "go"}))
+            .await?;
+        let event = next_json(&mut bob_ws).await?;
+        assert_eq!(event["type"], "join");
+        assert_eq!(event["chat_id"], 2);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_session_ends_on_signout_and_expiry() -> Result<()> {
+        let app = TestApp::new();
+        let (alice, _) = app.signup("alice").await?;
+        let claims = app.state.verify_token(&alice).await?;
+        let mut ended = tokio::spawn(session_ended(app.state.clone(), claims));
+        tokio::time::sleep(SESSION_CHECK_INTERVAL * 3).await;
+        assert!(!ended.is_finished());
+        app.post("/users/signout", Some(&alice), json!({})).await?;
+        tokio::time::timeout(SESSION_CHECK_INTERVAL * 2, &mut ended).await??;
+
+        let (bob, _) = app.signup("bob").await?;
+        let mut claims = app.state.verify_token(&bob).await?;
+        claims.expires_at = chrono::Utc::now() + chrono::Duration::seconds(45);
+        let ended = tokio::spawn(session_ended(app.state.clone(), claims));
+        tokio::time::sleep(Duration::from_secs(40)).await;
+        assert!(!ended.is_finished());
+        tokio::time::timeout(Duration::from_secs(10), ended).await??;
+        Ok(())
+    }
+}
@@ -0,0 +1,179 @@
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tokio_stream::{
+    StreamExt, StreamMap,
+    wrappers::{BroadcastStream, errors::BroadcastStreamRecvError},
+};
+use tracing::warn;
+
+use crate::Message;
+
+const CHANNEL_CAPACITY: usize = 256;
+
+// what gets pushed to connected clients
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChatEvent {
+    NewMessage(Message),
+    // edited, deleted or reacted to, carrying the message as it is now
+    MessageUpdated(Message),
+    Join {
+        chat_id: u32,
+        user_id: u32,
+    },
+    Leave {
+        chat_id: u32,
+        user_id: u32,
+    },
+    Typing {
+        chat_id: u32,
+        user_id: u32,
+    },
+    // the user has read the chat up to the message
+    Read {
+        chat_id: u32,
+        user_id: u32,
+        message_id: u64,
+    },
+    // sent to the invited user only
+    Invited {
+        chat_id: u32,
+        user_id: u32,
+        inviter_id: u32,
+    },
+}
+
+// one broadcast channel per chat, plus one per user for events about the user itself
+// (e.g. joining a chat the connection is not subscribed to yet)
+#[derive(Default)]
+pub struct ChatHub {
+    chats: DashMap<u32, broadcast::Sender<ChatEvent>>,
+    users: DashMap<u32, broadcast::Sender<ChatEvent>>,
+}
+
+impl ChatHub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn subscribe_chat(&self, chat_id: u32) -> broadcast::Receiver<ChatEvent> {
+        Self::sender(&self.chats, chat_id).subscribe()
+    }
+
+    pub fn subscribe_user(&self, user_id: u32) -> broadcast::Receiver<ChatEvent> {
+        Self::sender(&self.users, user_id).subscribe()
+    }
+
+    // nobody listening is fine, the event is just dropped
+    pub fn publish_chat(&self, chat_id: u32, event: ChatEvent) {
+        if let Some(tx) = self.chats.get(&chat_id) {
+            let _ = tx.send(event);
+        }
+    }
+
+    pub fn publish_user(&self, user_id: u32, event: ChatEvent) {
+        if let Some(tx) = self.users.get(&user_id) {
+            let _ = tx.send(event);
+        }
+    }
+
+    fn sender(
+        map: &DashMap<u32, broadcast::Sender<ChatEvent>>,
+        id: u32,
+    ) -> broadcast::Sender<ChatEvent> {
+        map.entry(id)
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .clone()
+    }
+}
+
+// the events of every chat a user is in, following the user into chats joined later
+pub struct ChatSubscription {
+    hub: Arc<ChatHub>,
+    user_id: u32,
+    user_events: BroadcastStream<ChatEvent>,
+    chat_events: StreamMap<u32, BroadcastStream<ChatEvent>>,
+}
+
+impl ChatSubscription {
+    pub fn new(hub: Arc<ChatHub>, user_id: u32, chat_ids: impl IntoIterator<Item = u32>) -> Self {
+        let user_events = BroadcastStream::new(hub.subscribe_user(user_id));
+        let mut chat_events = StreamMap::new();
+        for chat_id in chat_ids {
+            chat_events.insert(chat_id, BroadcastStream::new(hub.subscribe_chat(chat_id)));
+        }
+        Self {
+            hub,
+            user_id,
+            user_events,
+            chat_events,
+        }
+    }
+
+    // cancel safe, so it can be used in tokio::select!
+    pub async fn recv(&mut self) -> Option<ChatEvent> {
+        loop {
+            let event = tokio::select! {
+                Some((_, event)) = self.chat_events.next() => {
+                    // the user's own joins and leaves come from the user channel
+                    if let Ok(ChatEvent::Join { user_id, .. } | ChatEvent::Leave { user_id, .. }) = &event
+                        && *user_id == self.user_id
+                    {
+                        continue;
+                    }
+                    event
+                }
+                Some(event) = self.user_events.next() => {
+                    match &event {
+                        Ok(ChatEvent::Join { chat_id, .. }) => {
+                            if !self.chat_events.contains_key(chat_id) {
+                                let rx = BroadcastStream::new(self.hub.subscribe_chat(*chat_id));
+                                self.chat_events.insert(*chat_id, rx);
+                            }
+                        }
+                        Ok(ChatEvent::Leave { chat_id, .. }) => {
+                            self.chat_events.remove(chat_id);
+                        }
+                        _ => {}
+                    }
+                    event
+                }
+                else => return None,
+            };
+            match event {
+                Ok(event) => return Some(event),
+                Err(BroadcastStreamRecvError::Lagged(n)) => {
+                    warn!("subscriber lagged behind, {n} events dropped");
+                }
+            }
+        }
+    }
+}
+
+impl ChatEvent {
+    pub fn chat_id(&self) -> u32 {
+        match self {
+            Self::NewMessage(msg) | Self::MessageUpdated(msg) => msg.chat_id,
+            Self::Join { chat_id, .. }
+            | Self::Leave { chat_id, .. }
+            | Self::Typing { chat_id, .. }
+            | Self::Read { chat_id, .. }
+            | Self::Invited { chat_id, .. } => *chat_id,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::NewMessage(_) => "new_message",
+            Self::MessageUpdated(_) => "message_updated",
+            Self::Join { .. } => "join",
+            Self::Leave { .. } => "leave",
+            Self::Typing { .. } => "typing",
+            Self::Read { .. } => "read",
+            Self::Invited { .. } => "invited",
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use jwt_simple::prelude::Token;
+use tokio::{fs, task::JoinHandle};
+use tracing::{info, warn};
+
+use crate::{
+    AppError, DecodingKey, EncodingKey, FileLink, Jwks, JwtUser, TokenClaims, WorkspaceInvite,
+};
+
+// how often the key directory is checked for changes
+const KEY_RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+// the key tokens are signed with, plus every public key a live token may be signed with.
+// rotating means adding `{new}.pem`, pointing `current` at it, and keeping the old key
+// (or just `{old}.pub.pem`) until the tokens it signed have expired
+#[derive(Clone)]
+pub struct KeySet {
+    signing: EncodingKey,
+    verifying: HashMap<String, DecodingKey>,
+}
+
+impl KeySet {
+    pub fn new(signing: EncodingKey, verifying: impl IntoIterator<Item = DecodingKey>) -> Self {
+        let mut verifying = verifying
+            .into_iter()
+            .map(|key| (key.kid().to_string(), key))
+            .collect::<HashMap<_, _>>();
+        verifying.insert(signing.kid().to_string(), signing.decoding_key());
+        Self { signing, verifying }
+    }
+
+    pub async fn load(dir: &Path) -> Result<Self, AppError> {
+        let read_err = |e| AppError::JwtLoadError(format!("read key dir {dir:?} failed: {e}"));
+        let mut signing = BTreeMap::new();
+        let mut verifying = vec![];
+        let mut entries = fs::read_dir(dir).await.map_err(read_err)?;
+        while let Some(entry) = entries.next_entry().await.map_err(read_err)? {
+            let path = entry.path();
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            if let Some(kid) = name.strip_suffix(".pub.pem") {
+                let pem = fs::read_to_string(&path).await.map_err(read_err)?;
+                verifying.push(DecodingKey::from_pem(&pem, kid)?);
+            } else if let Some(kid) = name.strip_suffix(".pem") {
+                let pem = fs::read_to_string(&path).await.map_err(read_err)?;
+                let key = EncodingKey::from_pem(&pem, kid)?;
+                verifying.push(key.decoding_key());
+                signing.insert(kid.to_string(), key);
+            }
+        }
+
+        // without a `current` file the newest kid (in sort order) signs
+        let current = match fs::read_to_string(dir.join("current")).await {
+            Ok(kid) => kid.trim().to_string(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                signing.keys().next_back().cloned().unwrap_or_default()
+            }
+            Err(e) => return Err(read_err(e)),
+        };
+        let Some(signing) = signing.remove(&current) else {
+            return Err(AppError::JwtLoadError(format!(
+                "no signing key {current:?} in {dir:?}"
+            )));
+        };
+        Ok(Self::new(signing, verifying))
+    }
+
+    pub fn kid(&self) -> &str {
+        self.signing.kid()
+    }
+
+    pub fn sign(&self, jwt_user: JwtUser) -> Result<String, AppError> {
+        self.signing.sign(jwt_user)
+    }
+
+    pub fn verify(&self, token: &str) -> Result<TokenClaims, AppError> {
+        self.verifying_key(token)?.verify(token)
+    }
+
+    pub fn sign_file_link(&self, link: FileLink, ttl: u64) -> Result<String, AppError> {
+        self.signing.sign_file_link(link, ttl)
+    }
+
+    pub fn verify_file_link(&self, token: &str) -> Result<FileLink, AppError> {
+        self.verifying_key(token)?.verify_file_link(token)
+    }
+
+    pub fn sign_workspace_invite(&self, invite: WorkspaceInvite) -> Result<String, AppError> {
+        self.signing.sign_workspace_invite(invite)
+    }
+
+    pub fn verify_workspace_invite(&self, token: &str) -> Result<WorkspaceInvite, AppError> {
+        self.verifying_key(token)?.verify_workspace_invite(token)
+    }
+
+    // pick the public key by the kid in the jwt header
+    fn verifying_key(&self, token: &str) -> Result<&DecodingKey, AppError> {
+        let metadata = Token::decode_metadata(token)
+            .map_err(|e| AppError::JwtVerifyError(format!("decode jwt header failed: {e}")))?;
+        let kid = metadata.key_id().unwrap_or(self.signing.kid());
+        self.verifying
+            .get(kid)
+            .ok_or_else(|| AppError::JwtVerifyError(format!("unknown key id {kid}")))
+    }
+
+    pub fn jwks(&self) -> Jwks {
+        let mut keys = self
+            .verifying
+            .values()
+            .map(DecodingKey::jwk)
+            .collect::<Vec<_>>();
+        keys.sort_by(|a, b| a.kid.cmp(&b.kid));
+        Jwks { keys }
+    }
+}
+
+// the key set in use, swapped as a whole when the key directory changes
+pub struct KeyRing {
+    dir: Option<PathBuf>,
+    keys: RwLock<Arc<KeySet>>,
+}
+
+impl KeyRing {
+    pub fn new(keys: KeySet) -> Self {
+        Self {
+            dir: None,
+            keys: RwLock::new(Arc::new(keys)),
+        }
+    }
+
+    pub async fn load(dir: impl Into<PathBuf>) -> Result<Self, AppError> {
+        let dir = dir.into();
+        let keys = KeySet::load(&dir).await?;
+        info!("loaded signing key {} from {dir:?}", keys.kid());
+        Ok(Self {
+            dir: Some(dir),
+            keys: RwLock::new(Arc::new(keys)),
+        })
+    }
+
+    pub fn key_set(&self) -> Arc<KeySet> {
+        self.keys.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    // a broken key directory keeps the keys already loaded
+    pub async fn reload(&self) -> Result<(), AppError> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        let keys = KeySet::load(dir).await?;
+        info!("reloaded keys from {dir:?}, signing with {}", keys.kid());
+        *self.keys.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(keys);
+        Ok(())
+    }
+
+    // poll the key directory and reload whenever a file in it changes
+    pub fn watch(self: Arc<Self>) -> Option<JoinHandle<()>> {
+        let dir = self.dir.clone()?;
+        Some(tokio::spawn(async move {
+            let mut last = fingerprint(&dir).await;
+            loop {
+                tokio::time::sleep(KEY_RELOAD_INTERVAL).await;
+                let current = fingerprint(&dir).await;
+                if current == last {
+                    continue;
+                }
+                match self.reload().await {
+                    Ok(()) => last = current,
+                    Err(e) => warn!("reload keys failed: {e}"),
+                }
+            }
+        }))
+    }
+}
+
+// names, sizes and modification times of the files in the directory
+async fn fingerprint(dir: &Path) -> Vec<(String, u64, Option<SystemTime>)> {
+    let mut files = vec![];
+    let Ok(mut entries) = fs::read_dir(dir).await else {
+        return files;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let Ok(meta) = entry.metadata().await else {
+            continue;
+        };
+        files.push((
+            entry.file_name().to_string_lossy().into_owned(),
+            meta.len(),
+            meta.modified().ok(),
+        ));
+    }
+    files.sort();
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use jwt_simple::prelude::Ed25519KeyPair;
+
+    use super::*;
+
+    fn user() -> JwtUser {
+        JwtUser {
+            id: 1,
+            name: "alice".into(),
+            email: "alice@example.com".into(),
+            user_type: Default::default(),
+            ws_id: 1,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rotate_keys() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("chat_keys_{}", nanoid::nanoid!()));
+        fs::create_dir_all(&dir).await?;
+        let old = Ed25519KeyPair::generate();
+        fs::write(dir.join("k1.pem"), old.to_pem()).await?;
+        let ring = KeyRing::load(&dir).await?;
+        let old_token = ring.key_set().sign(user())?;
+
+        // add a new key and make it current
+        fs::write(dir.join("k2.pem"), Ed25519KeyPair::generate().to_pem()).await?;
+        fs::write(dir.join("current"), "k2\n").await?;
+        ring.reload().await?;
+        let keys = ring.key_set();
+        assert_eq!(keys.kid(), "k2");
+        let new_token = keys.sign(user())?;
+        assert_eq!(Token::decode_metadata(&new_token)?.key_id(), Some("k2"));
+        assert_eq!(keys.verify(&old_token)?.user.id, 1);
+        let kids = keys
+            .jwks()
+            .keys
+            .into_iter()
+            .map(|k| k.kid)
+            .collect::<Vec<_>>();
+        assert_eq!(kids, vec!["k1", "k2"]);
+
+        // retire the old private key but keep verifying its tokens
+        fs::remove_file(dir.join("k1.pem")).await?;
+        fs::write(dir.join("k1.pub.pem"), old.public_key().to_pem()).await?;
+        ring.reload().await?;
+        assert!(ring.key_set().verify(&old_token).is_ok());
+
+        // drop it entirely, its tokens are rejected now
+        fs::remove_file(dir.join("k1.pub.pem")).await?;
+        ring.reload().await?;
+        assert!(ring.key_set().verify(&old_token).is_err());
+        assert!(ring.key_set().verify(&new_token).is_ok());
+
+        // a broken directory keeps the loaded keys
+        fs::write(dir.join("current"), "missing").await?;
+        assert!(ring.reload().await.is_err());
+        assert_eq!(ring.key_set().kid(), "k2");
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+use std::{io::Cursor, sync::Arc};
+
+use image::{DynamicImage, ImageFormat, ImageReader, ImageResult, Limits};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::{AppError, FileMeta, FileStore};
+
+// thumbnails fit in a square of this size
+const THUMB_SIZE: u32 = 256;
+// uploads waiting to be processed, the ones beyond are not processed
+const QUEUE_LEN: usize = 1024;
+// decoding gives up on larger images, so a small file can not expand into gigabytes
+const MAX_IMAGE_SIDE: u32 = 16384;
+const MAX_IMAGE_ALLOC: u64 = 512 * 1024 * 1024;
+
+struct Job {
+    hash: String,
+    mime: String,
+}
+
+// processes uploaded blobs in the background, one at a time in upload order
+pub struct MediaQueue {
+    tx: mpsc::Sender<Job>,
+}
+
+impl MediaQueue {
+    pub fn spawn(files: Arc<FileStore>) -> Self {
+        let (tx, mut rx) = mpsc::channel::<Job>(QUEUE_LEN);
+        tokio::spawn(async move {
+            while let Some(job) = rx.recv().await {
+                if let Err(e) = process(&files, &job.hash, &job.mime).await {
+                    warn!("processing blob {} failed: {e}", job.hash);
+                }
+            }
+        });
+        Self { tx }
+    }
+
+    // uploads do not wait for the processing, a full queue leaves the blob unprocessed until
+    // the file is attached to a message or its thumbnail is asked for
+    pub fn enqueue(&self, hash: &str, mime: &str) {
+        let job = Job {
+            hash: hash.to_string(),
+            mime: mime.to_string(),
+        };
+        if let Err(e) = self.tx.try_send(job) {
+            warn!("blob {hash} is not processed: {e}");
+        }
+    }
+}
+
+// find out the dimensions of an image and make its thumbnail. a blob is processed once, what
+// it is found to be is stored even when it is not an image we can read
+pub async fn process(files: &FileStore, hash: &str, mime: &str) -> Result<FileMeta, AppError> {
+    if let Some(meta) = files.meta(hash).await? {
+        return Ok(meta);
+    }
+    let mut meta = FileMeta::default();
+    if let Some(format) = image_format(mime) {
+        let content = files.open_blob(hash).await?.read_all().await?;
+        match tokio::task::spawn_blocking(move || thumbnail(&content, format)).await {
+            Ok(Ok(thumb)) => {
+                files.put_thumb(hash, &thumb.content).await?;
+                info!("made a thumbnail of blob {hash}");
+                meta = FileMeta {
+                    width: Some(thumb.width),
+                    height: Some(thumb.height),
+                    thumb_mime: Some(thumb.mime.to_string()),
+                };
+            }
+            Ok(Err(e)) => warn!("blob {hash} is not a readable {mime} image: {e}"),
+            Err(e) => warn!("making a thumbnail of blob {hash} failed: {e}"),
+        }
+    }
+    files.set_meta(hash, meta.clone()).await?;
+    Ok(meta)
+}
+
+// the image types we make thumbnails of
+fn image_format(mime: &str) -> Option<ImageFormat> {
+    ImageFormat::from_mime_type(mime).filter(|format| {
+        matches!(
+            format,
+            ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::Gif | ImageFormat::WebP
+        )
+    })
+}
+
+struct Thumbnail {
+    // of the original image
+    width: u32,
+    height: u32,
+    content: Vec<u8>,
+    mime: &'static str,
+}
+
+// images with transparency get a png thumbnail, the others a jpeg one
+fn thumbnail(content: &[u8], format: ImageFormat) -> ImageResult<Thumbnail> {
+    let mut reader = ImageReader::with_format(Cursor::new(content), format);
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_SIDE);
+    limits.max_image_height = Some(MAX_IMAGE_SIDE);
+    limits.max_alloc = Some(MAX_IMAGE_ALLOC);
+    reader.limits(limits);
+    let image = reader.decode()?;
+    let (width, height) = (image.width(), image.height());
+    // small images are not scaled up
+    let thumb = if width > THUMB_SIZE || height > THUMB_SIZE {
+        image.thumbnail(THUMB_SIZE, THUMB_SIZE)
+    } else {
+        image
+    };
+    let (thumb, format, mime) = if thumb.color().has_alpha() {
+        (thumb, ImageFormat::Png, "image/png")
+    } else {
+        // the jpeg encoder only takes 8 bit color
+        let rgb = DynamicImage::ImageRgb8(thumb.to_rgb8());
+        (rgb, ImageFormat::Jpeg, "image/jpeg")
+    };
+    let mut out = Cursor::new(vec![]);
+    thumb.write_to(&mut out, format)?;
+    Ok(Thumbnail {
+        width,
+        height,
+        content: out.into_inner(),
+        mime,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+    use super::*;
+
+    fn encode(image: DynamicImage, format: ImageFormat) -> Vec<u8> {
+        let mut out = Cursor::new(vec![]);
+        image.write_to(&mut out, format).unwrap();
+        out.into_inner()
+    }
+
+    #[test]
+    fn test_thumbnail() {
+        let photo = encode(
+            RgbImage::from_pixel(600, 300, Rgb([200, 10, 10])).into(),
+            ImageFormat::Png,
+        );
+        let thumb = thumbnail(&photo, ImageFormat::Png).unwrap();
+        assert_eq!((thumb.width, thumb.height), (600, 300));
+        assert_eq!(thumb.mime, "image/jpeg");
+        let decoded = image::load_from_memory(&thumb.content).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (256, 128));
+
+        let icon = encode(
+            RgbaImage::from_pixel(32, 16, Rgba([0, 0, 0, 0])).into(),
+            ImageFormat::Png,
+        );
+        let thumb = thumbnail(&icon, ImageFormat::Png).unwrap();
+        assert_eq!(thumb.mime, "image/png");
+        let decoded = image::load_from_memory(&thumb.content).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (32, 16));
+
+        assert!(thumbnail(b"\x89PNG\r\n\x1a\n0000", ImageFormat::Png).is_err());
+        assert_eq!(image_format("image/webp"), Some(ImageFormat::WebP));
+        assert_eq!(image_format("image/tiff"), None);
+        assert_eq!(image_format("text/plain"), None);
+    }
+}
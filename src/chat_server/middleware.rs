@@ -0,0 +1,164 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    extract::{ConnectInfo, FromRequestParts, Path, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_extra::{
+    TypedHeader,
+    headers::{Authorization, authorization::Bearer},
+};
+use tracing::warn;
+
+use crate::{AppError, AppState, JwtUser, RateKey, RateLimiter, RateLimits, UserType};
+
+pub(crate) async fn chat_verify_jwt_token_middleware(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let (mut parts, body) = req.into_parts();
+    let req =
+        match TypedHeader::<Authorization<Bearer>>::from_request_parts(&mut parts, &state).await {
+            Ok(TypedHeader(Authorization(bearer))) => {
+                let token = bearer.token();
+                match state.verify_token(token).await {
+                    Ok(claims) => {
+                        let mut req = Request::from_parts(parts, body);
+                        req.extensions_mut().insert(claims.user.clone());
+                        req.extensions_mut().insert(claims);
+                        req
+                    }
+                    Err(e) => {
+                        return (StatusCode::UNAUTHORIZED, format!("verify jwt failed: {e}"))
+                            .into_response();
+                    }
+                }
+            }
+            Err(e) => {
+                let msg = format!("parse Authorization header failed: {}", e);
+                warn!(msg);
+                return (StatusCode::UNAUTHORIZED, msg).into_response();
+            }
+        };
+    next.run(req).await
+}
+
+//only let users with at least the given role through, runs after chat_verify_jwt_token_middleware
+pub(crate) async fn require_role_middleware(
+    State(role): State<UserType>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(user) = req.extensions().get::<JwtUser>() else {
+        return (StatusCode::UNAUTHORIZED, "missing jwt user").into_response();
+    };
+    if user.user_type < role {
+        warn!(
+            "{} tried to access {} without {role:?}",
+            user.name,
+            req.uri()
+        );
+        return AppError::PermissionDenied(format!("{role:?} role required")).into_response();
+    }
+    next.run(req).await
+}
+
+//verify if the user is authorized to send message in the chat, which means the user has joined the chat
+pub(crate) async fn message_verify_jwt_token_middleware(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let (mut parts, body) = req.into_parts();
+    //get the chat_id from the path, routes below a message have more params than the id
+    let chat_id =
+        match Path::<HashMap<String, String>>::from_request_parts(&mut parts, &state).await {
+            Ok(Path(params)) => match params.get("id").map(|id| id.parse::<u32>()) {
+                Some(Ok(chat_id)) => chat_id,
+                _ => return (StatusCode::BAD_REQUEST, "invalid chat id").into_response(),
+            },
+            Err(e) => return e.into_response(),
+        };
+
+    let Some(user) = parts.extensions.get::<JwtUser>() else {
+        return (StatusCode::UNAUTHORIZED, "missing jwt user").into_response();
+    };
+
+    //verify if the user has joined the chat
+    match state.store.is_chat_member(chat_id, user.id).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return (StatusCode::UNAUTHORIZED, "user has not joined the chat").into_response();
+        }
+        Err(e) => return e.into_response(),
+    }
+
+    let mut req = Request::from_parts(parts, body);
+    req.extensions_mut().insert(chat_id);
+    next.run(req).await
+}
+
+//limit sign up, sign in and refresh per client ip, against password guessing
+pub(crate) async fn auth_rate_limit_middleware(
+    State(limits): State<RateLimits>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if let Some(limiter) = &limits.auth {
+        let ip = client_ip(&req, limits.trust_forwarded_for);
+        if let Err(wait) = limiter.check(RateKey::Ip(ip)) {
+            warn!("rate limited {ip} on {}", req.uri());
+            return rate_limited(wait);
+        }
+    }
+    next.run(req).await
+}
+
+//limit the requests of each user, runs after chat_verify_jwt_token_middleware
+pub(crate) async fn user_rate_limit_middleware(
+    State(limiter): State<Option<Arc<RateLimiter>>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if let Some(limiter) = &limiter {
+        let Some(user) = req.extensions().get::<JwtUser>() else {
+            return (StatusCode::UNAUTHORIZED, "missing jwt user").into_response();
+        };
+        if let Err(wait) = limiter.check(RateKey::User(user.id)) {
+            warn!("rate limited user {} on {}", user.id, req.uri());
+            return rate_limited(wait);
+        }
+    }
+    next.run(req).await
+}
+
+fn rate_limited(wait: Duration) -> Response {
+    let secs = wait.as_secs_f64().ceil().max(1.0) as u64;
+    AppError::RateLimited(secs).into_response()
+}
+
+//the address the request came from. behind a trusted proxy it is the last X-Forwarded-For entry,
+//the one the proxy appended. served without connect info, all clients share one address
+fn client_ip(req: &Request, trust_forwarded_for: bool) -> IpAddr {
+    let forwarded = trust_forwarded_for
+        .then(|| req.headers().get("x-forwarded-for"))
+        .flatten()
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next())
+        .and_then(|ip| ip.trim().parse().ok());
+    forwarded
+        .or_else(|| {
+            req.extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip())
+        })
+        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+}
@@ -0,0 +1,442 @@
+mod auth;
+mod config;
+mod error;
+mod file_store;
+mod handlers;
+mod hub;
+mod keys;
+mod media;
+mod middleware;
+mod models;
+mod rate_limit;
+mod storage;
+mod store;
+
+pub use auth::*;
+pub use config::*;
+pub use error::*;
+pub use file_store::*;
+pub use hub::*;
+pub use keys::*;
+pub use media::*;
+pub use models::*;
+pub use rate_limit::*;
+pub use storage::*;
+pub use store::*;
+
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use axum::{
+    Router,
+    extract::DefaultBodyLimit,
+    middleware::from_fn_with_state,
+    routing::{delete, get, patch, post},
+};
+use tokio::fs;
+
+use handlers::*;
+use middleware::*;
+
+#[derive(Clone)]
+pub struct AppState {
+    pub(crate) store: Arc<dyn ChatStore>,
+    pub(crate) keys: Arc<KeyRing>,
+    pub(crate) base_dir: PathBuf,
+    pub(crate) upload: Arc<UploadConfig>,
+    pub(crate) files: Arc<FileStore>,
+    // how long a signed download link works, in seconds
+    pub(crate) file_link_ttl: u64,
+    pub(crate) media: Arc<MediaQueue>,
+    pub(crate) hub: Arc<ChatHub>,
+    pub(crate) rate_limits: RateLimits,
+}
+
+impl AppState {
+    pub async fn try_new(config: &AppConfig) -> Result<Self, AppError> {
+        let keys = Arc::new(KeyRing::load(&config.server.key_dir).await?);
+        keys.clone().watch();
+        fs::create_dir_all(&config.server.base_dir).await?;
+        let store = chat_store(config).await?;
+        let files = Arc::new(FileStore::new(
+            &config.server.base_dir,
+            blob_storage(&config.server)?,
+            store.clone(),
+        ));
+        files.clone().spawn_gc();
+        let rate_limits = RateLimits::from_config(&config.server.rate_limit)?;
+        rate_limits.spawn_cleanup(Duration::from_secs(
+            config.server.rate_limit.cleanup_interval,
+        ));
+        Ok(Self {
+            store,
+            keys,
+            base_dir: config.server.base_dir.clone(),
+            upload: Arc::new(config.server.upload.clone()),
+            file_link_ttl: config.server.file_link_ttl,
+            media: Arc::new(MediaQueue::spawn(files.clone())),
+            files,
+            hub: Arc::new(ChatHub::new()),
+            rate_limits,
+        })
+    }
+
+    // a valid signature is not enough, the token must still stand for its user
+    pub(crate) async fn verify_token(&self, token: &str) -> Result<TokenClaims, AppError> {
+        let claims = self.keys.key_set().verify(token)?;
+        self.check_claims(&claims).await?;
+        Ok(claims)
+    }
+
+    // refuse the token if it was signed out, or its user deleted or given another role since.
+    // a refresh gives a token with the new role
+    pub(crate) async fn check_claims(&self, claims: &TokenClaims) -> Result<(), AppError> {
+        if self.store.is_token_revoked(&claims.jti).await? {
+            return Err(AppError::JwtVerifyError("token has been revoked".into()));
+        }
+        match self.store.get_user(claims.user.id).await? {
+            Some(user) if user.user_type == claims.user.user_type => Ok(()),
+            Some(_) => Err(AppError::JwtVerifyError(
+                "the user's role has changed".into(),
+            )),
+            None => Err(AppError::JwtVerifyError("the user no longer exists".into())),
+        }
+    }
+}
+
+pub fn get_router(state: AppState) -> Router {
+    let message_api = Router::new()
+        // the upload handler enforces the configured limits while streaming
+        .route(
+            "/upload_file/{id}",
+            post(upload_file_handler).layer(DefaultBodyLimit::disable()),
+        )
+        .route("/send_message/{id}", post(send_message_handler))
+        .route("/typing/{id}", post(typing_handler))
+        .route("/{id}", get(list_messages_handler))
+        .route(
+            "/{id}/{message_id}",
+            patch(edit_message_handler).delete(delete_message_handler),
+        )
+        .route("/{id}/{message_id}/history", get(message_history_handler))
+        .route("/{id}/{message_id}/thread", get(thread_handler))
+        .route(
+            "/{id}/{message_id}/reactions",
+            post(toggle_reaction_handler),
+        )
+        .layer(from_fn_with_state(
+            state.clone(),
+            message_verify_jwt_token_middleware,
+        ))
+        .layer(from_fn_with_state(
+            state.rate_limits.messages.clone(),
+            user_rate_limit_middleware,
+        ));
+
+    let chat_api = Router::new()
+        .route("/create_chat", post(create_chat_handler))
+        .route("/join_chat", post(join_chat_handler))
+        .route("/public_chats", get(public_chats_handler))
+        .route("/chats", get(my_chats_handler))
+        .route("/chats/{id}", delete(delete_own_chat_handler))
+        .route("/chats/{id}/read", post(mark_read_handler))
+        .route("/chats/{id}/leave", post(leave_chat_handler))
+        .route("/chats/{id}/invite", post(invite_handler))
+        .route("/chats/{id}/kick", post(kick_member_handler))
+        .route("/chats/{id}/moderators", post(set_moderator_handler))
+        .route("/chats/{id}/transfer", post(transfer_owner_handler))
+        .route("/workspace/invite", post(workspace_invite_handler))
+        .route("/invitations", get(list_invitations_handler))
+        .route("/invitations/{id}/accept", post(accept_invitation_handler))
+        .route(
+            "/invitations/{id}/decline",
+            post(decline_invitation_handler),
+        )
+        .route("/events", get(events_handler))
+        .route("/mentions", get(mentions_handler))
+        .route("/search", get(search_handler))
+        .route("/signout", post(signout_handler))
+        .route("/message/files/{id}/{*path}", get(file_handler))
+        .route("/message/links/{id}/{*path}", get(file_link_handler))
+        // messages have their own limit
+        .route_layer(from_fn_with_state(
+            state.rate_limits.api.clone(),
+            user_rate_limit_middleware,
+        ))
+        .nest("/message", message_api)
+        .layer(from_fn_with_state(
+            state.clone(),
+            chat_verify_jwt_token_middleware,
+        ));
+
+    let admin_api = Router::new()
+        .route("/users/list", get(list_users_handler))
+        .route("/users/list_with_query", get(query_users_handler))
+        .route("/admin/users/{id}", delete(delete_user_handler))
+        .route("/admin/users/{id}/role", post(set_user_type_handler))
+        .route("/admin/chats/{id}", delete(delete_chat_handler))
+        .route_layer(from_fn_with_state(UserType::Admin, require_role_middleware))
+        .route_layer(from_fn_with_state(
+            state.clone(),
+            chat_verify_jwt_token_middleware,
+        ));
+
+    let auth_api = Router::new()
+        .route("/users/create", post(signup_handler))
+        .route("/users/signin", post(signin_handler))
+        .route("/users/refresh", post(refresh_handler))
+        .route_layer(from_fn_with_state(
+            state.rate_limits.clone(),
+            auth_rate_limit_middleware,
+        ));
+
+    Router::new()
+        .merge(admin_api)
+        .merge(auth_api)
+        .route("/users/ws", get(ws_handler))
+        .route("/.well-known/jwks.json", get(jwks_handler))
+        // signed download links, the token in the path is the authorization
+        .route("/files/{token}", get(file_link_download_handler))
+        .nest("/users", chat_api)
+        .with_state(state)
+}
+
+#[cfg(test)]
+pub(crate) mod test_util {
+    use anyhow::Result;
+    use axum::{
+        Router,
+        body::Body,
+        http::{HeaderMap, HeaderName, Method, Request, StatusCode},
+    };
+    use bytes::Bytes;
+    use http_body_util::BodyExt;
+    use serde_json::{Value, json};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn filename_param(name: &str) -> String {
+        if name.is_empty() {
+            String::new()
+        } else {
+            format!("; filename=\"{name}\"")
+        }
+    }
+
+    pub(crate) struct TestApp {
+        pub(crate) state: AppState,
+        pub(crate) router: Router,
+    }
+
+    impl TestApp {
+        pub(crate) fn new() -> Self {
+            Self::with_upload(UploadConfig::default())
+        }
+
+        pub(crate) fn with_upload(upload: UploadConfig) -> Self {
+            let keys = KeySet::new(EncodingKey::generate("test"), []);
+            let base_dir = std::env::temp_dir().join(format!("chat_server_{}", nanoid::nanoid!()));
+            let store: Arc<dyn ChatStore> = Arc::new(MemoryStore::new());
+            let blobs = Arc::new(LocalStorage::new(&base_dir));
+            let files = Arc::new(FileStore::new(&base_dir, blobs, store.clone()));
+            let state = AppState {
+                media: Arc::new(MediaQueue::spawn(files.clone())),
+                files,
+                store,
+                keys: Arc::new(KeyRing::new(keys)),
+                base_dir,
+                upload: Arc::new(upload),
+                file_link_ttl: ServerConfig::default().file_link_ttl,
+                hub: Arc::new(ChatHub::new()),
+                rate_limits: RateLimits::default(),
+            };
+            let router = get_router(state.clone());
+            TestApp { state, router }
+        }
+
+        // the other test apps are not rate limited
+        pub(crate) fn with_rate_limits(config: RateLimitConfig) -> Result<Self> {
+            let mut state = Self::new().state;
+            state.rate_limits = RateLimits::from_config(&config)?;
+            let router = get_router(state.clone());
+            Ok(TestApp { state, router })
+        }
+
+        // serve the router on a random local port, for clients that need a real connection
+        pub(crate) async fn serve(&self) -> Result<std::net::SocketAddr> {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+            let addr = listener.local_addr()?;
+            let router = self.router.clone();
+            let service = router.into_make_service_with_connect_info::<std::net::SocketAddr>();
+            tokio::spawn(async move { axum::serve(listener, service).await });
+            Ok(addr)
+        }
+
+        pub(crate) async fn request(
+            &self,
+            method: Method,
+            uri: &str,
+            token: Option<&str>,
+            body: Option<Value>,
+        ) -> Result<(StatusCode, Value)> {
+            let mut req = Request::builder().method(method).uri(uri);
+            if let Some(token) = token {
+                req = req.header("authorization", format!("Bearer {token}"));
+            }
+            let req = match body {
+                Some(body) => req
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_vec(&body)?))?,
+                None => req.body(Body::empty())?,
+            };
+            let res = self.router.clone().oneshot(req).await?;
+            let status = res.status();
+            let bytes = res.into_body().collect().await?.to_bytes();
+            let value = serde_json::from_slice(&bytes)
+                .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&bytes).into()));
+            Ok((status, value))
+        }
+
+        pub(crate) async fn get(
+            &self,
+            uri: &str,
+            token: Option<&str>,
+        ) -> Result<(StatusCode, Value)> {
+            self.request(Method::GET, uri, token, None).await
+        }
+
+        pub(crate) async fn post(
+            &self,
+            uri: &str,
+            token: Option<&str>,
+            body: Value,
+        ) -> Result<(StatusCode, Value)> {
+            self.request(Method::POST, uri, token, Some(body)).await
+        }
+
+        // upload the files to the chat as one multipart request, an empty name sends no filename
+        pub(crate) async fn upload(
+            &self,
+            chat_id: u32,
+            token: &str,
+            files: &[(&str, &[u8])],
+        ) -> Result<(StatusCode, Value)> {
+            let mut body = vec![];
+            for (name, content) in files {
+                body.extend_from_slice(
+                    format!(
+                        "--BOUNDARY\r\nContent-Disposition: form-data; name=\"file\"{}\r\n\r\n",
+                        filename_param(name)
+                    )
+                    .as_bytes(),
+                );
+                body.extend_from_slice(content);
+                body.extend_from_slice(b"\r\n");
+            }
+            body.extend_from_slice(b"--BOUNDARY--\r\n");
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri(format!("/users/message/upload_file/{chat_id}"))
+                .header("authorization", format!("Bearer {token}"))
+                .header("content-type", "multipart/form-data; boundary=BOUNDARY")
+                .body(Body::from(body))?;
+            let res = self.router.clone().oneshot(req).await?;
+            let status = res.status();
+            let bytes = res.into_body().collect().await?.to_bytes();
+            let value = serde_json::from_slice(&bytes)
+                .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&bytes).into()));
+            Ok((status, value))
+        }
+
+        // get the raw response, for file downloads
+        pub(crate) async fn download(
+            &self,
+            uri: &str,
+            token: &str,
+            headers: &[(HeaderName, &str)],
+        ) -> Result<(StatusCode, HeaderMap, Bytes)> {
+            let mut req = Request::builder()
+                .uri(uri)
+                .header("authorization", format!("Bearer {token}"));
+            for (name, value) in headers {
+                req = req.header(name, *value);
+            }
+            let res = self
+                .router
+                .clone()
+                .oneshot(req.body(Body::empty())?)
+                .await?;
+            let status = res.status();
+            let headers = res.headers().clone();
+            let bytes = res.into_body().collect().await?.to_bytes();
+            Ok((status, headers, bytes))
+        }
+
+        // sign up `{name}@example.com` in the "acme" workspace and return its token and user json
+        pub(crate) async fn signup(&self, name: &str) -> Result<(String, Value)> {
+            self.signup_in(name, "acme").await
+        }
+
+        // the first user creates the workspace, the others join with an invitation
+        pub(crate) async fn signup_in(
+            &self,
+            name: &str,
+            workspace: &str,
+        ) -> Result<(String, Value)> {
+            let email = format!("{name}@example.com");
+            let mut body = json!({
+                "name": name,
+                "email": email,
+                "gender": "female",
+                "password": "password123",
+            });
+            match self.state.store.find_workspace_by_name(workspace).await? {
+                Some(ws) => {
+                    body["invite_token"] = self
+                        .state
+                        .keys
+                        .key_set()
+                        .sign_workspace_invite(WorkspaceInvite {
+                            ws_id: ws.id,
+                            email,
+                        })?
+                        .into()
+                }
+                None => body["workspace"] = workspace.into(),
+            }
+            let (status, body) = self.post("/users/create", None, body).await?;
+            assert_eq!(status, StatusCode::CREATED);
+            Ok((
+                body["token"].as_str().unwrap().to_string(),
+                body["user"].clone(),
+            ))
+        }
+
+        // sign up, promote to admin and sign in again, so the token carries the role
+        pub(crate) async fn signup_admin(&self, name: &str) -> Result<(String, Value)> {
+            self.signup_admin_in(name, "acme").await
+        }
+
+        pub(crate) async fn signup_admin_in(
+            &self,
+            name: &str,
+            workspace: &str,
+        ) -> Result<(String, Value)> {
+            let (_, user) = self.signup_in(name, workspace).await?;
+            let email = format!("{name}@example.com");
+            self.state
+                .store
+                .set_user_type(&email, UserType::Admin)
+                .await?;
+            let (status, body) = self
+                .post(
+                    "/users/signin",
+                    None,
+                    json!({"email": email, "password": "password123"}),
+                )
+                .await?;
+            assert_eq!(status, StatusCode::OK);
+            Ok((body["token"].as_str().unwrap().to_string(), user))
+        }
+    }
+}
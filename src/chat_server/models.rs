@@ -0,0 +1,593 @@
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::AppError;
+
+// the most messages one history page can return
+pub const MAX_MESSAGE_LIMIT: u32 = 100;
+// the most chats one listing can return
+pub const MAX_CHAT_LIMIT: u32 = 100;
+// users.name and users.email are varchar(64), users.gender is a varchar(16)
+pub const MAX_USER_NAME_LEN: usize = 64;
+pub const MAX_EMAIL_LEN: usize = 64;
+pub const MAX_GENDER_LEN: usize = 16;
+// workspaces.name is a varchar(32)
+pub const MAX_WORKSPACE_NAME_LEN: usize = 32;
+// chat_files.ext is a varchar(16)
+pub const MAX_FILE_EXT_LEN: usize = 16;
+
+fn default_limit() -> u32 {
+    10
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UserFilter {
+    #[serde(default = "default_limit")]
+    pub limit: u32,
+    pub ordering: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct JoinOutput {
+    pub message: String,
+}
+
+// ordered by privilege, so `user_type >= required` is the permission check
+#[derive(
+    Serialize, Deserialize, sqlx::Type, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "user_type", rename_all = "snake_case")]
+pub enum UserType {
+    #[default]
+    Regular,
+    Admin,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JwtUser {
+    pub id: u32,
+    pub name: String,
+    pub email: String,
+    #[serde(default)]
+    pub user_type: UserType,
+    #[serde(default)]
+    pub ws_id: u32,
+}
+
+#[derive(Deserialize)]
+pub struct SigninRequest {
+    pub email: String,
+    pub password: String,
+}
+
+// `token` is the access jwt, valid for `expires_in` seconds
+#[derive(Serialize, Debug)]
+pub struct SigninOutput {
+    pub token: String,
+    pub refresh_token: String,
+    pub expires_in: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+// the refresh token is dropped as well if it is given, the access token always is
+#[derive(Deserialize, Debug, Default)]
+pub struct SignoutRequest {
+    pub refresh_token: Option<String>,
+}
+
+// the role an admin gives to another user of the workspace
+#[derive(Deserialize, Debug)]
+pub struct UserTypeRequest {
+    pub user_type: UserType,
+}
+
+// an Ed25519 public key as a json web key (RFC 8037)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Jwk {
+    pub kty: String,
+    pub crv: String,
+    pub alg: String,
+    #[serde(rename = "use")]
+    pub key_use: String,
+    pub kid: String,
+    pub x: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Jwks {
+    pub keys: Vec<Jwk>,
+}
+
+// a refresh token as the store keeps it, looked up by the hash of the token
+#[derive(Debug, Clone)]
+pub struct RefreshToken {
+    pub user_id: u32,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Message {
+    pub id: u64,
+    pub chat_id: u32,
+    pub sender_id: u32,
+    pub content: String,
+    pub files: Vec<ChatFile>,
+    pub date: DateTime<Utc>,
+    #[serde(default)]
+    pub edited_at: Option<DateTime<Utc>>,
+    // a deleted message stays in the log as a tombstone without content, files or reactions
+    #[serde(default)]
+    pub deleted_at: Option<DateTime<Utc>>,
+    // in the order the emojis were first used
+    #[serde(default)]
+    pub reactions: Vec<Reaction>,
+    // the root of the thread the message replies in
+    #[serde(default)]
+    pub reply_to: Option<u64>,
+    // ids of the members mentioned with @name, in the order they appear
+    #[serde(default)]
+    pub mentions: Vec<u32>,
+}
+
+// a thread is its root message and the replies to it, oldest first
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MessageThread {
+    pub root: Message,
+    pub replies: Vec<Message>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Reaction {
+    pub emoji: String,
+    pub count: u32,
+    // in the order they reacted
+    pub user_ids: Vec<u32>,
+}
+
+// an earlier content of an edited message, `date` is when it was written
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MessageVersion {
+    pub content: String,
+    pub date: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EditMessageRequest {
+    pub content: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReactionRequest {
+    pub emoji: String,
+}
+
+// newest-first page of a chat, `before` is the id of the oldest message already seen
+#[derive(Deserialize, Debug)]
+pub struct ListMessages {
+    pub before: Option<u64>,
+    #[serde(default = "default_limit")]
+    pub limit: u32,
+}
+
+// oldest-first page of a thread, `after` is the id of the newest reply already seen
+#[derive(Deserialize, Debug)]
+pub struct ListReplies {
+    pub after: Option<u64>,
+    #[serde(default = "default_limit")]
+    pub limit: u32,
+}
+
+// full-text search over the messages of the user's chats. every word of `q` has to start a
+// word of the message, `from` is inclusive and `to` exclusive
+#[derive(Deserialize, Debug, Default)]
+pub struct SearchQuery {
+    #[serde(default)]
+    pub q: String,
+    pub chat_id: Option<u32>,
+    pub sender_id: Option<u32>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    #[serde(default = "default_limit")]
+    pub limit: u32,
+    #[serde(default)]
+    pub offset: u32,
+}
+
+// best matches first. `highlight` is the html escaped content with the matching words in <mark>
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SearchHit {
+    pub message: Message,
+    pub rank: f32,
+    pub highlight: String,
+}
+
+// `thumb=1` downloads the thumbnail of an image instead
+#[derive(Deserialize, Debug, Default)]
+pub struct FileQuery {
+    #[serde(default)]
+    pub thumb: u8,
+}
+
+// what a signed download link points at
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileLink {
+    pub chat_id: u32,
+    pub hash: String,
+    pub ext: String,
+    #[serde(default)]
+    pub thumb: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileLinkResponse {
+    pub url: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MessageRequest {
+    pub content: String,
+    // paths returned by the upload, {chat_id}/file/{hash}.{ext}
+    #[serde(default)]
+    pub files: Vec<String>,
+    // reply in the thread of this message
+    #[serde(default)]
+    pub reply_to: Option<u64>,
+}
+
+// a message that is not stored yet, the store assigns the id and date
+#[derive(Debug, Clone)]
+pub struct CreateMessage {
+    pub chat_id: u32,
+    pub sender_id: u32,
+    pub content: String,
+    pub files: Vec<ChatFile>,
+    pub reply_to: Option<u64>,
+    pub mentions: Vec<u32>,
+}
+
+// single: a direct chat of exactly two users, nobody can join
+// group: anyone who knows the id can join
+// private_channel: members are added by invitation only
+// public_channel: listed and searchable, anyone can join
+#[derive(Serialize, Deserialize, sqlx::Type, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "chat_type", rename_all = "snake_case")]
+pub enum ChatType {
+    Single,
+    #[default]
+    Group,
+    PrivateChannel,
+    PublicChannel,
+}
+
+// the chat id is generated by the server. `members` are added besides the creator to a single
+// chat, and invited to the other chats
+#[derive(Deserialize, Clone, Debug)]
+pub struct CreateChatRequest {
+    #[serde(default)]
+    pub chat_type: ChatType,
+    #[serde(alias = "chat_name")]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub members: Vec<u32>,
+}
+
+// a chat that is not stored yet, the store assigns the id
+#[derive(Debug, Clone)]
+pub struct CreateChat {
+    pub ws_id: u32,
+    pub name: String,
+    pub chat_type: ChatType,
+    pub owner_id: u32,
+    pub members: Vec<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChatMemberRequest {
+    pub user_id: u32,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ModeratorRequest {
+    pub user_id: u32,
+    pub moderator: bool,
+}
+
+// mark the chat read up to the message, or up to its latest message if it is not set
+#[derive(Deserialize, Debug, Default)]
+pub struct ReadRequest {
+    pub message_id: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReadReceipt {
+    pub chat_id: u32,
+    pub user_id: u32,
+    // the newest message the user has read, 0 if none
+    pub message_id: u64,
+}
+
+// one of the user's chats with how far the user has read it. the user's own and deleted
+// messages are never unread
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UserChat {
+    #[serde(flatten)]
+    pub chat: Chat,
+    pub last_read: u64,
+    pub unread: u32,
+}
+
+// a pending invitation of `user_id` into the chat
+#[derive(Serialize, Debug, Clone)]
+pub struct Invitation {
+    pub chat_id: u32,
+    pub chat_name: String,
+    pub chat_type: ChatType,
+    pub user_id: u32,
+    pub inviter_id: u32,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PublicChatFilter {
+    pub q: Option<String>,
+    #[serde(default = "default_limit")]
+    pub limit: u32,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct JoinChatRequest {
+    pub chat_id: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Chat {
+    pub id: u32,
+    pub ws_id: u32,
+    pub name: String,
+    pub chat_type: ChatType,
+    // None once the owner's account is deleted
+    pub owner_id: Option<u32>,
+    pub moderators: Vec<u32>,
+    pub members: Vec<u32>,
+}
+
+// identifies a file of a chat, the content is a blob shared by every chat that has the same
+// content
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FileId {
+    pub chat_id: u32,
+    // sha256 of the content, lowercase hex
+    pub hash: String,
+    pub ext: String,
+}
+
+impl FileId {
+    // {chat_id}/file/{hash}.{ext}, what an upload returns
+    pub fn path(&self) -> PathBuf {
+        format!("{}/file/{}.{}", self.chat_id, self.hash, self.ext).into()
+    }
+
+    // the `{hash}.{ext}` name a download asks for
+    pub fn parse(chat_id: u32, name: &str) -> Option<Self> {
+        let (hash, ext) = name.split_once('.')?;
+        if !is_blob_hash(hash) || !is_file_ext(ext) {
+            return None;
+        }
+        Some(Self {
+            chat_id,
+            hash: hash.to_string(),
+            ext: ext.to_string(),
+        })
+    }
+
+    // the reverse of `path`
+    pub fn parse_path(path: &str) -> Option<Self> {
+        let (chat_id, name) = path.split_once("/file/")?;
+        Self::parse(chat_id.parse().ok()?, name)
+    }
+}
+
+// a lowercase hex sha-256, what blobs are named by
+pub fn is_blob_hash(hash: &str) -> bool {
+    hash.len() == 64
+        && hash
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
+
+pub fn is_file_ext(ext: &str) -> bool {
+    !ext.is_empty()
+        && ext.len() <= MAX_FILE_EXT_LEN
+        && ext.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+// a file stored for a chat, counted against the quotas of the chat and the uploader
+#[derive(Serialize, Debug, Clone)]
+pub struct FileRecord {
+    #[serde(flatten)]
+    pub file: FileId,
+    pub size: u64,
+    pub mime: String,
+    pub uploader_id: u32,
+    pub created_at: DateTime<Utc>,
+}
+
+// the bytes the files of a user and of a chat may take
+#[derive(Debug, Clone, Copy)]
+pub struct FileQuota {
+    pub user: u64,
+    pub chat: u64,
+}
+
+impl FileQuota {
+    // whether `new_size` more bytes fit next to what the uploader and the chat use already
+    pub fn check(&self, new_size: u64, user_usage: u64, chat_usage: u64) -> Result<(), AppError> {
+        if new_size > self.user.saturating_sub(user_usage) {
+            return Err(AppError::UploadFileError(
+                "the upload quota of the user is exceeded".into(),
+            ));
+        }
+        if new_size > self.chat.saturating_sub(chat_usage) {
+            return Err(AppError::UploadFileError(
+                "the storage quota of the chat is exceeded".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl FileRecord {
+    // the file as a message carries it, with what processing found out about the content so far
+    pub fn chat_file(&self, meta: Option<&FileMeta>) -> ChatFile {
+        let FileId { chat_id, hash, ext } = &self.file;
+        let url = format!("/users/message/files/{chat_id}/{hash}.{ext}");
+        let meta = meta.cloned().unwrap_or_default();
+        ChatFile {
+            hash: hash.clone(),
+            ext: ext.clone(),
+            size: self.size,
+            mime: self.mime.clone(),
+            width: meta.width,
+            height: meta.height,
+            thumb_url: meta.thumb_mime.map(|_| format!("{url}?thumb=1")),
+            url,
+        }
+    }
+}
+
+// a file attached to a message, `url` downloads it. images carry their dimensions and a
+// thumbnail once they are processed
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChatFile {
+    pub hash: String,
+    pub ext: String,
+    pub size: u64,
+    pub mime: String,
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumb_url: Option<String>,
+}
+
+// what processing a blob after its upload found out, the same for every chat that has it
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileMeta {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    // the type of the thumbnail, None if there is none
+    pub thumb_mime: Option<String>,
+}
+
+// users and chats only see the users and chats of their own workspace
+#[derive(Serialize, Debug, Clone)]
+pub struct Workspace {
+    pub id: u32,
+    pub name: String,
+    pub owner_id: Option<u32>,
+    pub created_at: DateTime<Utc>,
+}
+
+// what a workspace invitation token carries, only `email` can sign up with it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WorkspaceInvite {
+    pub ws_id: u32,
+    pub email: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct WorkspaceInviteRequest {
+    pub email: String,
+}
+
+// the token goes into the `invite_token` of the sign up
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WorkspaceInviteOutput {
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SignupOutput {
+    #[serde(flatten)]
+    pub tokens: SigninOutput,
+    pub user: User,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UserCreateRequest {
+    pub name: String,
+    pub email: String,
+    pub gender: Option<String>,
+    #[serde(default)]
+    pub teenager: bool,
+    pub password: String,
+    // a new workspace with the user as owner, taken names are rejected
+    pub workspace: Option<String>,
+    // or an invitation to an existing workspace, issued by its owner or an admin
+    pub invite_token: Option<String>,
+}
+
+// a user that is not stored yet, the store assigns the id
+#[derive(Debug, Clone)]
+pub struct CreateUser {
+    pub ws_id: u32,
+    pub name: String,
+    pub email: String,
+    pub gender: String,
+    pub teenager: bool,
+    pub hash_password: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct User {
+    #[serde(default)]
+    pub id: u32,
+    pub ws_id: u32,
+    pub name: String,
+    pub email: String,
+    pub gender: String,
+    #[serde(default)]
+    pub teenager: bool,
+    pub user_type: UserType,
+    #[serde(skip_serializing)]
+    pub hash_password: String,
+}
+
+impl From<&User> for JwtUser {
+    fn from(user: &User) -> Self {
+        JwtUser {
+            id: user.id,
+            name: user.name.clone(),
+            email: user.email.clone(),
+            user_type: user.user_type,
+            ws_id: user.ws_id,
+        }
+    }
+}
+
+impl Chat {
+    pub fn is_member(&self, user_id: u32) -> bool {
+        self.members.contains(&user_id)
+    }
+
+    pub fn is_owner(&self, user_id: u32) -> bool {
+        self.owner_id == Some(user_id)
+    }
+
+    // the owner moderates as well
+    pub fn is_moderator(&self, user_id: u32) -> bool {
+        self.is_owner(user_id) || self.moderators.contains(&user_id)
+    }
+}
@@ -0,0 +1,196 @@
+use std::{
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+use crate::{AppError, RateLimit, RateLimitConfig};
+
+// who a bucket belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RateKey {
+    Ip(IpAddr),
+    User(u32),
+}
+
+// one token bucket per key, a request takes a token and tokens come back at `per_second`
+// up to `burst`
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: RateLimit,
+    buckets: DashMap<RateKey, Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Result<Self, AppError> {
+        if limit.burst == 0 || !limit.per_second.is_finite() || limit.per_second <= 0.0 {
+            return Err(AppError::ConfigError(format!(
+                "invalid rate limit {limit:?}, burst and per_second must be positive"
+            )));
+        }
+        Ok(Self {
+            limit,
+            buckets: DashMap::new(),
+        })
+    }
+
+    // take a token, or tell how long until the next one
+    pub fn check(&self, key: RateKey) -> Result<(), Duration> {
+        self.check_at(key, Instant::now())
+    }
+
+    pub fn check_at(&self, key: RateKey, now: Instant) -> Result<(), Duration> {
+        let burst = self.limit.burst as f64;
+        let mut bucket = self.buckets.entry(key).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        bucket.tokens = self.refill(&bucket, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.limit.per_second,
+            ))
+        }
+    }
+
+    // drop the buckets that are full again, a new bucket starts full anyway
+    pub fn cleanup_at(&self, now: Instant) -> usize {
+        let before = self.buckets.len();
+        let burst = self.limit.burst as f64;
+        self.buckets
+            .retain(|_, bucket| self.refill(bucket, now) < burst);
+        before - self.buckets.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.limit.per_second).min(self.limit.burst as f64)
+    }
+}
+
+// the limiters of the route groups, None for a group that is not limited
+#[derive(Debug, Clone, Default)]
+pub struct RateLimits {
+    pub auth: Option<Arc<RateLimiter>>,
+    pub messages: Option<Arc<RateLimiter>>,
+    pub api: Option<Arc<RateLimiter>>,
+    pub trust_forwarded_for: bool,
+}
+
+impl RateLimits {
+    pub fn from_config(config: &RateLimitConfig) -> Result<Self, AppError> {
+        if config.cleanup_interval == 0 {
+            return Err(AppError::ConfigError(
+                "invalid rate limit cleanup_interval 0, it must be positive".into(),
+            ));
+        }
+        let limiter = |limit: Option<RateLimit>| {
+            limit
+                .map(|limit| RateLimiter::new(limit).map(Arc::new))
+                .transpose()
+        };
+        Ok(Self {
+            auth: limiter(config.auth)?,
+            messages: limiter(config.messages)?,
+            api: limiter(config.api)?,
+            trust_forwarded_for: config.trust_forwarded_for,
+        })
+    }
+
+    pub fn spawn_cleanup(&self, interval: Duration) -> JoinHandle<()> {
+        let limiters = [&self.auth, &self.messages, &self.api]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect::<Vec<_>>();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let now = Instant::now();
+                let dropped: usize = limiters.iter().map(|l| l.cleanup_at(now)).sum();
+                if dropped > 0 {
+                    debug!("dropped {dropped} idle rate limit buckets");
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new(RateLimit {
+            burst: 3,
+            per_second: 2.0,
+        })
+        .unwrap();
+        let alice = RateKey::User(1);
+        let bob = RateKey::User(2);
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.check_at(alice, start).is_ok());
+        }
+        assert_eq!(
+            limiter.check_at(alice, start),
+            Err(Duration::from_millis(500))
+        );
+        // everyone has their own bucket
+        assert!(limiter.check_at(bob, start).is_ok());
+
+        // a token comes back every half second
+        let later = start + Duration::from_millis(600);
+        assert!(limiter.check_at(alice, later).is_ok());
+        assert!(limiter.check_at(alice, later).is_err());
+
+        // only buckets that are full again are dropped
+        assert_eq!(limiter.cleanup_at(start + Duration::from_millis(400)), 0);
+        assert_eq!(limiter.cleanup_at(start + Duration::from_secs(1)), 1);
+        assert_eq!(limiter.len(), 1);
+        assert_eq!(limiter.cleanup_at(start + Duration::from_secs(3)), 1);
+        assert!(limiter.is_empty());
+
+        let invalid = RateLimit {
+            burst: 1,
+            per_second: 0.0,
+        };
+        assert!(RateLimiter::new(invalid).is_err());
+    }
+
+    #[test]
+    fn test_zero_cleanup_interval_is_rejected() {
+        assert!(RateLimits::from_config(&RateLimitConfig::default()).is_ok());
+        let config = RateLimitConfig {
+            cleanup_interval: 0,
+            ..Default::default()
+        };
+        assert!(matches!(
+            RateLimits::from_config(&config),
+            Err(AppError::ConfigError(_))
+        ));
+    }
+}
@@ -0,0 +1,783 @@
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use bytes::Bytes;
+use chacha20poly1305::{
+    AeadCore, ChaCha20Poly1305, Key, KeyInit, Nonce,
+    aead::{Aead, OsRng, Payload, rand_core::RngCore},
+};
+use futures::stream;
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+};
+use tokio_util::io::StreamReader;
+
+use super::{BlobInfo, BlobReader, BlobStorage};
+use crate::{AppError, EncryptionConfig, ServerConfig, TMP_DIR};
+
+// an encrypted object is stored under its key with SEALED_SUFFIX, objects under the key itself
+// are in the clear. it is
+//   "CHE1" | kid length (1) | kid | wrap nonce (12) | wrapped file key (32 + 16) | nonce prefix (7)
+// followed by the content in segments of SEGMENT_LEN bytes, each sealed on its own so ranges
+// can be read without the rest. the last segment is shorter, empty if it has to be, and its
+// nonce is marked so a truncated object does not pass for a shorter one. the file key is
+// wrapped with the key of the object and every segment is sealed with the key and its index,
+// so neither can be moved to another object or place
+const SEALED_SUFFIX: &str = ".enc";
+const MAGIC: &[u8; 4] = b"CHE1";
+const SEGMENT_LEN: u64 = 64 * 1024;
+const TAG_LEN: u64 = 16;
+const SEALED_LEN: u64 = SEGMENT_LEN + TAG_LEN;
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const PREFIX_LEN: usize = 7;
+const MAX_KID_LEN: usize = u8::MAX as usize;
+const MAX_HEADER_LEN: u64 =
+    (MAGIC.len() + 1 + MAX_KID_LEN + NONCE_LEN + KEY_LEN + TAG_LEN as usize + PREFIX_LEN) as u64;
+// how often a ranged read starts over when the object is stored again while it is opened
+const READ_ATTEMPTS: usize = 3;
+
+fn crypto_error(key: &str, what: &str) -> AppError {
+    AppError::StorageError(format!("{key}: {what}"))
+}
+
+fn sealed_key(key: &str) -> String {
+    format!("{key}{SEALED_SUFFIX}")
+}
+
+fn segment_aad(key: &str, index: u64) -> Vec<u8> {
+    let mut aad = index.to_be_bytes().to_vec();
+    aad.extend_from_slice(key.as_bytes());
+    aad
+}
+
+// the keys file keys are wrapped with
+pub struct MasterKeys {
+    current: String,
+    keys: HashMap<String, ChaCha20Poly1305>,
+}
+
+impl MasterKeys {
+    pub fn from_config(config: &EncryptionConfig) -> Result<Self, AppError> {
+        let mut keys = HashMap::new();
+        for (kid, key) in &config.keys {
+            if kid.is_empty() || kid.len() > MAX_KID_LEN {
+                return Err(AppError::ConfigError(format!(
+                    "master key id {kid:?} must be 1 to {MAX_KID_LEN} bytes"
+                )));
+            }
+            let key = STANDARD.decode(key.trim()).map_err(|e| {
+                AppError::ConfigError(format!("master key {kid} is not base64: {e}"))
+            })?;
+            if key.len() != KEY_LEN {
+                return Err(AppError::ConfigError(format!(
+                    "master key {kid} must be {KEY_LEN} bytes"
+                )));
+            }
+            keys.insert(kid.clone(), ChaCha20Poly1305::new(Key::from_slice(&key)));
+        }
+        if !keys.contains_key(&config.current) {
+            return Err(AppError::ConfigError(format!(
+                "no master key {:?} among the keys",
+                config.current
+            )));
+        }
+        Ok(Self {
+            current: config.current.clone(),
+            keys,
+        })
+    }
+
+    // a new random master key, encoded the way the config takes it
+    pub fn generate() -> String {
+        STANDARD.encode(ChaCha20Poly1305::generate_key(&mut OsRng))
+    }
+}
+
+// the key and nonce prefix of one object, random for every time an object is stored
+#[derive(PartialEq)]
+struct FileHeader {
+    // of the master key the file key is wrapped with
+    kid: String,
+    key: Key,
+    prefix: [u8; PREFIX_LEN],
+    // of the header as it is stored
+    len: u64,
+}
+
+impl FileHeader {
+    fn generate() -> Self {
+        let mut prefix = [0; PREFIX_LEN];
+        OsRng.fill_bytes(&mut prefix);
+        Self {
+            kid: String::new(),
+            key: ChaCha20Poly1305::generate_key(&mut OsRng),
+            prefix,
+            len: 0,
+        }
+    }
+
+    // the header of the object under `name` with the file key wrapped by the current master key
+    fn encode(&self, name: &str, keys: &MasterKeys) -> Result<Vec<u8>, AppError> {
+        let master = &keys.keys[&keys.current];
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: self.key.as_slice(),
+            aad: name.as_bytes(),
+        };
+        let wrapped = master
+            .encrypt(&nonce, payload)
+            .map_err(|_| AppError::StorageError("wrapping a file key failed".into()))?;
+        let mut header = MAGIC.to_vec();
+        header.push(keys.current.len() as u8);
+        header.extend_from_slice(keys.current.as_bytes());
+        header.extend_from_slice(&nonce);
+        header.extend_from_slice(&wrapped);
+        header.extend_from_slice(&self.prefix);
+        Ok(header)
+    }
+
+    fn decode(name: &str, head: &[u8], keys: &MasterKeys) -> Result<Self, AppError> {
+        let Some(rest) = head.strip_prefix(MAGIC) else {
+            return Err(crypto_error(name, "not an encrypted object"));
+        };
+        let truncated = || crypto_error(name, "truncated encryption header");
+        let (&kid_len, rest) = rest.split_first().ok_or_else(truncated)?;
+        let kid_len = kid_len as usize;
+        let wrapped_len = KEY_LEN + TAG_LEN as usize;
+        if rest.len() < kid_len + NONCE_LEN + wrapped_len + PREFIX_LEN {
+            return Err(truncated());
+        }
+        let (kid, rest) = rest.split_at(kid_len);
+        let (nonce, rest) = rest.split_at(NONCE_LEN);
+        let (wrapped, rest) = rest.split_at(wrapped_len);
+        let kid = String::from_utf8_lossy(kid).into_owned();
+        let Some(master) = keys.keys.get(&kid) else {
+            return Err(crypto_error(name, &format!("unknown master key {kid}")));
+        };
+        let payload = Payload {
+            msg: wrapped,
+            aad: name.as_bytes(),
+        };
+        let key = master
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| {
+                crypto_error(name, &format!("unwrapping the file key with {kid} failed"))
+            })?;
+        Ok(Self {
+            kid,
+            key: *Key::from_slice(&key),
+            prefix: rest[..PREFIX_LEN].try_into().unwrap(),
+            len: (MAGIC.len() + 1 + kid_len + NONCE_LEN + wrapped_len + PREFIX_LEN) as u64,
+        })
+    }
+
+    // read the header off the start of an encrypted object, the reader is left at the content
+    async fn read(
+        name: &str,
+        reader: &mut (impl AsyncRead + Unpin),
+        keys: &MasterKeys,
+    ) -> Result<Self, AppError> {
+        let fixed = MAGIC.len() + 1;
+        let mut head = vec![0; fixed];
+        let n = read_full(reader, &mut head).await?;
+        if n == fixed {
+            let kid_len = head[MAGIC.len()] as usize;
+            head.resize(
+                fixed + kid_len + NONCE_LEN + KEY_LEN + TAG_LEN as usize + PREFIX_LEN,
+                0,
+            );
+            let n = read_full(reader, &mut head[fixed..]).await?;
+            head.truncate(fixed + n);
+        } else {
+            head.truncate(n);
+        }
+        Self::decode(name, &head, keys)
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(&self.key)
+    }
+}
+
+fn segment_nonce(prefix: &[u8; PREFIX_LEN], index: u64, last: bool) -> Nonce {
+    let mut nonce = [0; NONCE_LEN];
+    nonce[..PREFIX_LEN].copy_from_slice(prefix);
+    nonce[PREFIX_LEN..NONCE_LEN - 1].copy_from_slice(&(index as u32).to_be_bytes());
+    nonce[NONCE_LEN - 1] = last as u8;
+    *Nonce::from_slice(&nonce)
+}
+
+// how much content `body_len` encrypted bytes hold
+fn content_len(name: &str, body_len: u64) -> Result<u64, AppError> {
+    let rest = body_len % SEALED_LEN;
+    if rest < TAG_LEN {
+        return Err(crypto_error(name, "truncated encrypted content"));
+    }
+    Ok(body_len / SEALED_LEN * SEGMENT_LEN + rest - TAG_LEN)
+}
+
+// fill the buffer unless the reader ends first, return how much was read
+async fn read_full(reader: &mut (impl AsyncRead + Unpin), buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..]).await? {
+            0 => break,
+            read => n += read,
+        }
+    }
+    Ok(n)
+}
+
+// seal everything the reader gives as the content of `name`, segment by segment
+async fn encrypt(
+    header: &FileHeader,
+    name: &str,
+    mut reader: impl AsyncRead + Unpin,
+    writer: &mut (impl AsyncWrite + Unpin),
+) -> Result<(), AppError> {
+    let cipher = header.cipher();
+    let mut buf = vec![0; SEGMENT_LEN as usize];
+    for index in 0..=u32::MAX as u64 {
+        let n = read_full(&mut reader, &mut buf).await?;
+        let last = n < buf.len();
+        let nonce = segment_nonce(&header.prefix, index, last);
+        let payload = Payload {
+            msg: &buf[..n],
+            aad: &segment_aad(name, index),
+        };
+        let sealed = cipher
+            .encrypt(&nonce, payload)
+            .map_err(|_| AppError::StorageError("encrypting a file failed".into()))?;
+        writer.write_all(&sealed).await?;
+        if last {
+            return Ok(());
+        }
+    }
+    Err(AppError::StorageError("file too large to encrypt".into()))
+}
+
+// the content [start, end) of `name` from an encrypted body of `body_len` bytes, the reader
+// starts at the segment `start` is in
+fn decrypt(
+    header: &FileHeader,
+    name: &str,
+    reader: BlobReader,
+    body_len: u64,
+    start: u64,
+    end: u64,
+) -> BlobReader {
+    let cipher = header.cipher();
+    let prefix = header.prefix;
+    let name = name.to_string();
+    let segments = body_len.div_ceil(SEALED_LEN);
+    let first = start / SEGMENT_LEN;
+    let state = (reader, first, start - first * SEGMENT_LEN, end - start);
+    let stream = stream::try_unfold(state, move |(mut reader, index, skip, left)| {
+        let cipher = cipher.clone();
+        let aad = segment_aad(&name, index);
+        async move {
+            if left == 0 {
+                return Ok::<_, io::Error>(None);
+            }
+            let last = index + 1 == segments;
+            let sealed_len = if last {
+                body_len - index * SEALED_LEN
+            } else {
+                SEALED_LEN
+            };
+            let mut sealed = vec![0; sealed_len as usize];
+            reader.read_exact(&mut sealed).await?;
+            let payload = Payload {
+                msg: sealed.as_slice(),
+                aad: &aad,
+            };
+            let content = cipher
+                .decrypt(&segment_nonce(&prefix, index, last), payload)
+                .map_err(|_| {
+                    io::Error::new(ErrorKind::InvalidData, "encrypted content is corrupt")
+                })?;
+            let take = (content.len() as u64).saturating_sub(skip).min(left);
+            let part = Bytes::copy_from_slice(&content[skip as usize..(skip + take) as usize]);
+            Ok(Some((part, (reader, index + 1, 0, left - take))))
+        }
+    });
+    Box::pin(StreamReader::new(stream))
+}
+
+// encrypts objects on their way into another storage and decrypts them on the way out. every
+// object has its own random key, wrapped by a master key and kept in front of the content.
+// objects stored before encryption was turned on are read as they are until they are rotated.
+// sizes are of the content, except in listings which give the stored sizes
+pub struct EncryptedStorage {
+    inner: Arc<dyn BlobStorage>,
+    keys: MasterKeys,
+    // encrypted files are written here before they are handed to the inner storage
+    tmp_dir: PathBuf,
+}
+
+impl EncryptedStorage {
+    pub fn new(inner: Arc<dyn BlobStorage>, keys: MasterKeys, tmp_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            inner,
+            keys,
+            tmp_dir: tmp_dir.into(),
+        }
+    }
+
+    // None if the config has no master keys
+    pub fn from_config(
+        config: &ServerConfig,
+        inner: Arc<dyn BlobStorage>,
+    ) -> Result<Option<Self>, AppError> {
+        let Some(encryption) = &config.encryption else {
+            return Ok(None);
+        };
+        let keys = MasterKeys::from_config(encryption)?;
+        Ok(Some(Self::new(inner, keys, config.base_dir.join(TMP_DIR))))
+    }
+
+    // wrap the file keys of the objects below the prefix that are not wrapped by the current
+    // master key with it, and encrypt the ones stored in the clear. return how many objects
+    // were rewritten, once it is done the old master keys can be dropped
+    pub async fn rotate(&self, prefix: &str) -> Result<usize, AppError> {
+        let mut rotated = 0;
+        for object in self.inner.list(prefix).await? {
+            let result = match object.key.strip_suffix(SEALED_SUFFIX) {
+                Some(key) => self.rewrap(key, object.size).await,
+                None => self.seal(&object.key, object.size).await,
+            };
+            match result {
+                Ok(true) => rotated += 1,
+                Ok(false) => {}
+                // collected since it was listed
+                Err(AppError::FileNotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(rotated)
+    }
+
+    // the content stays as it is, only the wrapping of its key changes. the header and the
+    // content come from one read, so they belong together even if the object is stored again
+    async fn rewrap(&self, key: &str, size: u64) -> Result<bool, AppError> {
+        let sealed = sealed_key(key);
+        let mut object = self.inner.read(&sealed, 0, size).await?;
+        let header = FileHeader::read(key, &mut object, &self.keys).await?;
+        if header.kid == self.keys.current {
+            return Ok(false);
+        }
+        self.write_and_put(&sealed, async |out| {
+            out.write_all(&header.encode(key, &self.keys)?).await?;
+            tokio::io::copy(&mut object, out).await?;
+            Ok(())
+        })
+        .await?;
+        Ok(true)
+    }
+
+    // encrypt an object stored in the clear and drop the clear one
+    async fn seal(&self, key: &str, size: u64) -> Result<bool, AppError> {
+        // stored again since encryption was turned on
+        if self.inner.size(&sealed_key(key)).await?.is_none() {
+            let header = FileHeader::generate();
+            self.write_and_put(&sealed_key(key), async |out| {
+                out.write_all(&header.encode(key, &self.keys)?).await?;
+                let content = self.inner.read(key, 0, size).await?;
+                encrypt(&header, key, content, out).await
+            })
+            .await?;
+        }
+        self.inner.delete(key).await?;
+        Ok(true)
+    }
+
+    // write a temp file and hand it to the inner storage, the temp file is gone either way
+    async fn write_and_put(
+        &self,
+        key: &str,
+        write: impl AsyncFnOnce(&mut fs::File) -> Result<(), AppError>,
+    ) -> Result<(), AppError> {
+        fs::create_dir_all(&self.tmp_dir).await?;
+        let path = self.tmp_dir.join(nanoid::nanoid!());
+        let mut out = fs::File::create(&path).await?;
+        let result = async {
+            write(&mut out).await?;
+            out.sync_all().await?;
+            drop(out);
+            self.inner.put_file(key, &path).await
+        }
+        .await;
+        if result.is_err() {
+            let _ = fs::remove_file(&path).await;
+        }
+        result
+    }
+
+    // the header of the encrypted object of the key, `size` is of the stored object
+    async fn header(&self, key: &str, size: u64) -> Result<FileHeader, AppError> {
+        let mut head = vec![];
+        self.inner
+            .read(&sealed_key(key), 0, size.min(MAX_HEADER_LEN))
+            .await?
+            .read_to_end(&mut head)
+            .await?;
+        FileHeader::decode(key, &head, &self.keys)
+    }
+
+    // the stored size of the object of the key and its header, None if it is in the clear
+    async fn stored(&self, key: &str) -> Result<Option<(u64, Option<FileHeader>)>, AppError> {
+        if let Some(size) = self.inner.size(&sealed_key(key)).await? {
+            return Ok(Some((size, Some(self.header(key, size).await?))));
+        }
+        Ok(self.inner.size(key).await?.map(|size| (size, None)))
+    }
+
+    // the content [start, end) from the body of an object of `size` stored bytes
+    fn decrypt(
+        &self,
+        key: &str,
+        header: FileHeader,
+        body: BlobReader,
+        size: u64,
+        start: u64,
+        end: u64,
+    ) -> Result<BlobReader, AppError> {
+        let body_len = size - header.len;
+        let end = end.min(content_len(key, body_len)?);
+        if start >= end {
+            return Ok(Box::pin(tokio::io::empty()));
+        }
+        Ok(decrypt(&header, key, body, body_len, start, end))
+    }
+}
+
+#[async_trait]
+impl BlobStorage for EncryptedStorage {
+    async fn put_file(&self, key: &str, path: &Path) -> Result<(), AppError> {
+        let header = FileHeader::generate();
+        self.write_and_put(&sealed_key(key), async |out| {
+            out.write_all(&header.encode(key, &self.keys)?).await?;
+            encrypt(&header, key, fs::File::open(path).await?, out).await
+        })
+        .await?;
+        fs::remove_file(path).await?;
+        Ok(())
+    }
+
+    async fn put(&self, key: &str, content: Vec<u8>) -> Result<(), AppError> {
+        let header = FileHeader::generate();
+        let mut sealed = header.encode(key, &self.keys)?;
+        encrypt(&header, key, content.as_slice(), &mut sealed).await?;
+        self.inner.put(&sealed_key(key), sealed).await
+    }
+
+    async fn size(&self, key: &str) -> Result<Option<u64>, AppError> {
+        match self.stored(key).await? {
+            Some((size, Some(header))) => Ok(Some(content_len(key, size - header.len)?)),
+            Some((size, None)) => Ok(Some(size)),
+            None => Ok(None),
+        }
+    }
+
+    // the object may be stored again under the key while it is read, with a new header. the
+    // header and the content read must be of the same object, or the content does not decrypt
+    async fn read(&self, key: &str, start: u64, end: u64) -> Result<BlobReader, AppError> {
+        let sealed = sealed_key(key);
+        let Some(size) = self.inner.size(&sealed).await? else {
+            if self.inner.size(key).await?.is_none() {
+                return Err(AppError::FileNotFound("file not found".into()));
+            }
+            return self.inner.read(key, start, end).await;
+        };
+        if start >= end {
+            return Ok(Box::pin(tokio::io::empty()));
+        }
+        // whole segments, from the one `start` is in to the one `end - 1` is in
+        let segments = |header_len: u64| {
+            let from = header_len + start / SEGMENT_LEN * SEALED_LEN;
+            let to = (header_len + ((end - 1) / SEGMENT_LEN + 1) * SEALED_LEN).min(size);
+            (from, to)
+        };
+        if start < SEGMENT_LEN {
+            // from the first segment on, the header is read with it in one request
+            let (_, to) = segments(MAX_HEADER_LEN);
+            let mut object = self.inner.read(&sealed, 0, to).await?;
+            let header = FileHeader::read(key, &mut object, &self.keys).await?;
+            return self.decrypt(key, header, object, size, start, end);
+        }
+        // the header again after the content is opened, unchanged it is the one of the content
+        for _ in 0..READ_ATTEMPTS {
+            let header = self.header(key, size).await?;
+            let (from, to) = segments(header.len);
+            let body = self.inner.read(&sealed, from, to).await?;
+            if self.header(key, size).await? == header {
+                return self.decrypt(key, header, body, size, start, end);
+            }
+        }
+        Err(crypto_error(key, "stored again while it was read"))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), AppError> {
+        self.inner.delete(&sealed_key(key)).await?;
+        self.inner.delete(key).await
+    }
+
+    // by the keys they are read with, an object stored both in the clear and encrypted until it
+    // is rotated is listed once as the more recent of the two
+    async fn list(&self, prefix: &str) -> Result<Vec<BlobInfo>, AppError> {
+        let mut objects = HashMap::<String, BlobInfo>::new();
+        for mut object in self.inner.list(prefix).await? {
+            if let Some(key) = object.key.strip_suffix(SEALED_SUFFIX) {
+                object.key = key.to_string();
+            }
+            match objects.entry(object.key.clone()) {
+                Entry::Occupied(mut entry) => {
+                    if object.modified > entry.get().modified {
+                        entry.insert(object);
+                    }
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(object);
+                }
+            }
+        }
+        let mut objects = objects.into_values().collect::<Vec<_>>();
+        objects.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(objects)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, sync::Mutex};
+
+    use anyhow::Result;
+
+    use super::*;
+    use crate::LocalStorage;
+
+    // stores `again` under its key right before the first read that does not start at 0, as an
+    // upload of the same content would between reading the header and the content
+    struct RacingStorage {
+        inner: LocalStorage,
+        again: Mutex<Option<(String, Vec<u8>)>>,
+    }
+
+    #[async_trait]
+    impl BlobStorage for RacingStorage {
+        async fn put_file(&self, key: &str, path: &Path) -> Result<(), AppError> {
+            self.inner.put_file(key, path).await
+        }
+
+        async fn put(&self, key: &str, content: Vec<u8>) -> Result<(), AppError> {
+            self.inner.put(key, content).await
+        }
+
+        async fn size(&self, key: &str) -> Result<Option<u64>, AppError> {
+            self.inner.size(key).await
+        }
+
+        async fn read(&self, key: &str, start: u64, end: u64) -> Result<BlobReader, AppError> {
+            let again = match start {
+                0 => None,
+                _ => self.again.lock().unwrap().take(),
+            };
+            if let Some((key, content)) = again {
+                self.inner.put(&key, content).await?;
+            }
+            self.inner.read(key, start, end).await
+        }
+
+        async fn delete(&self, key: &str) -> Result<(), AppError> {
+            self.inner.delete(key).await
+        }
+
+        async fn list(&self, prefix: &str) -> Result<Vec<BlobInfo>, AppError> {
+            self.inner.list(prefix).await
+        }
+    }
+
+    fn master_keys(current: &str, keys: &[(&str, &str)]) -> Result<MasterKeys, AppError> {
+        MasterKeys::from_config(&EncryptionConfig {
+            current: current.into(),
+            keys: keys
+                .iter()
+                .map(|(kid, key)| (kid.to_string(), key.to_string()))
+                .collect::<BTreeMap<_, _>>(),
+        })
+    }
+
+    async fn read(storage: &dyn BlobStorage, key: &str, start: u64, end: u64) -> Result<Vec<u8>> {
+        let mut content = vec![];
+        storage
+            .read(key, start, end)
+            .await?
+            .read_to_end(&mut content)
+            .await?;
+        Ok(content)
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_storage() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("chat_encrypted_{}", nanoid::nanoid!()));
+        let inner = Arc::new(LocalStorage::new(&dir));
+        let key = MasterKeys::generate();
+        let storage = EncryptedStorage::new(
+            inner.clone(),
+            master_keys("k1", &[("k1", &key)])?,
+            dir.join("tmp"),
+        );
+
+        let content = (0..150_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        fs::create_dir_all(&dir).await?;
+        let upload = dir.join("upload");
+        fs::write(&upload, &content).await?;
+        storage.put_file("big", &upload).await?;
+        assert!(!upload.exists());
+        storage
+            .put("even", vec![7; 2 * SEGMENT_LEN as usize])
+            .await?;
+        storage.put("empty", vec![]).await?;
+
+        // nothing is stored in the clear
+        let stored = fs::read(dir.join("big.enc")).await?;
+        assert!(stored.starts_with(MAGIC));
+        assert!(!dir.join("big").exists());
+        assert!(!stored.windows(64).any(|w| w == &content[1000..1064]));
+        assert_eq!(storage.size("big").await?, Some(150_000));
+        assert_eq!(storage.size("even").await?, Some(2 * SEGMENT_LEN));
+        assert_eq!(storage.size("empty").await?, Some(0));
+
+        assert_eq!(read(&storage, "big", 0, 150_000).await?, content);
+        // ranges within a segment and across segments
+        for (start, end) in [(10, 20), (65_530, 65_540), (1, 140_000), (131_072, 150_000)] {
+            assert_eq!(
+                read(&storage, "big", start, end).await?,
+                content[start as usize..end as usize]
+            );
+        }
+        assert_eq!(
+            read(&storage, "even", 0, 2 * SEGMENT_LEN).await?,
+            vec![7; 2 * SEGMENT_LEN as usize]
+        );
+        assert!(read(&storage, "empty", 0, 0).await?.is_empty());
+
+        // tampering, truncation and moving are noticed
+        let mut tampered = stored.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        fs::write(dir.join("big.enc"), &tampered).await?;
+        assert!(read(&storage, "big", 0, 150_000).await.is_err());
+        fs::write(dir.join("big.enc"), &stored[..stored.len() - 5000]).await?;
+        let size = storage.size("big").await?.unwrap();
+        assert!(read(&storage, "big", 0, size).await.is_err());
+        // a whole segment less, the segment before is not marked as the last one
+        let short = stored.len() - (150_000 - 2 * SEGMENT_LEN as usize) - TAG_LEN as usize;
+        fs::write(dir.join("big.enc"), &stored[..short]).await?;
+        assert!(read(&storage, "big", 0, 2 * SEGMENT_LEN).await.is_err());
+        fs::write(dir.join("moved.enc"), &stored).await?;
+        assert!(read(&storage, "moved", 0, 150_000).await.is_err());
+
+        // content in the clear is never taken for an encrypted object
+        inner.put("clear", b"CHE1 in the clear".to_vec()).await?;
+        assert_eq!(storage.size("clear").await?, Some(17));
+        assert_eq!(read(&storage, "clear", 0, 17).await?, b"CHE1 in the clear");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_while_stored_again() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("chat_racing_{}", nanoid::nanoid!()));
+        let inner = Arc::new(RacingStorage {
+            inner: LocalStorage::new(&dir),
+            again: Mutex::new(None),
+        });
+        let storage = EncryptedStorage::new(
+            inner.clone(),
+            master_keys("k1", &[("k1", &MasterKeys::generate())])?,
+            dir.join("tmp"),
+        );
+        let content = (0..150_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        storage.put("big", content.clone()).await?;
+        let first = fs::read(dir.join("big.enc")).await?;
+        // the same content under a new file key
+        storage.put("big", content.clone()).await?;
+        assert_ne!(fs::read(dir.join("big.enc")).await?, first);
+
+        // a read from the start keeps reading the object it opened
+        let mut reader = storage.read("big", 0, 150_000).await?;
+        inner.inner.put("big.enc", first.clone()).await?;
+        let mut whole = vec![];
+        reader.read_to_end(&mut whole).await?;
+        assert_eq!(whole, content);
+
+        // a ranged read notices the header changed and reads again
+        *inner.again.lock().unwrap() = Some(("big.enc".into(), first));
+        assert_eq!(
+            read(&storage, "big", 70_000, 150_000).await?,
+            content[70_000..]
+        );
+        assert!(inner.again.lock().unwrap().is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rotate_master_key() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("chat_rotate_{}", nanoid::nanoid!()));
+        let inner = Arc::new(LocalStorage::new(&dir));
+        let (old, new) = (MasterKeys::generate(), MasterKeys::generate());
+        let before = EncryptedStorage::new(
+            inner.clone(),
+            master_keys("old", &[("old", &old)])?,
+            dir.join("tmp"),
+        );
+        before
+            .put("blobs/a", b"wrapped by the old key".to_vec())
+            .await?;
+        // stored before encryption was turned on
+        inner.put("blobs/b", b"in the clear".to_vec()).await?;
+
+        let both = EncryptedStorage::new(
+            inner.clone(),
+            master_keys("new", &[("old", &old), ("new", &new)])?,
+            dir.join("tmp"),
+        );
+        assert_eq!(read(&both, "blobs/b", 0, 12).await?, b"in the clear");
+        assert_eq!(both.rotate("blobs/").await?, 2);
+        assert_eq!(both.rotate("blobs/").await?, 0);
+
+        let after = EncryptedStorage::new(
+            inner.clone(),
+            master_keys("new", &[("new", &new)])?,
+            dir.join("tmp"),
+        );
+        assert_eq!(
+            read(&after, "blobs/a", 0, 22).await?,
+            b"wrapped by the old key"
+        );
+        assert_eq!(read(&after, "blobs/b", 0, 12).await?, b"in the clear");
+        assert!(fs::read(dir.join("blobs/b.enc")).await?.starts_with(MAGIC));
+        assert!(!dir.join("blobs/b").exists());
+        let keys = after
+            .list("blobs/")
+            .await?
+            .into_iter()
+            .map(|object| object.key)
+            .collect::<Vec<_>>();
+        assert_eq!(keys, ["blobs/a", "blobs/b"]);
+        assert!(read(&before, "blobs/a", 0, 22).await.is_err());
+
+        assert!(master_keys("new", &[("old", &old)]).is_err());
+        assert!(master_keys("k", &[("k", "c2hvcnQ=")]).is_err());
+        assert!(master_keys("k", &[("k", "not base64!")]).is_err());
+        Ok(())
+    }
+}
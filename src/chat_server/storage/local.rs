@@ -0,0 +1,125 @@
+use std::{
+    io::{ErrorKind, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+
+use super::{BlobInfo, BlobReader, BlobStorage};
+use crate::AppError;
+
+// objects are files below the root directory, the key is the relative path
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+
+    async fn create_parent(path: &Path) -> Result<(), AppError> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BlobStorage for LocalStorage {
+    // a rename, so the file should be on the same file system
+    async fn put_file(&self, key: &str, path: &Path) -> Result<(), AppError> {
+        let dest = self.path(key);
+        Self::create_parent(&dest).await?;
+        fs::rename(path, &dest).await?;
+        Ok(())
+    }
+
+    // written aside first, so readers never see half an object
+    async fn put(&self, key: &str, content: Vec<u8>) -> Result<(), AppError> {
+        let dest = self.path(key);
+        Self::create_parent(&dest).await?;
+        let tmp = dest.with_extension(format!("{}.tmp", nanoid::nanoid!()));
+        fs::write(&tmp, content).await?;
+        fs::rename(&tmp, &dest).await?;
+        Ok(())
+    }
+
+    async fn size(&self, key: &str) -> Result<Option<u64>, AppError> {
+        match fs::metadata(self.path(key)).await {
+            Ok(meta) if meta.is_file() => Ok(Some(meta.len())),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn read(&self, key: &str, start: u64, end: u64) -> Result<BlobReader, AppError> {
+        let mut file = fs::File::open(self.path(key))
+            .await
+            .map_err(|e| match e.kind() {
+                ErrorKind::NotFound => AppError::FileNotFound("file not found".into()),
+                _ => e.into(),
+            })?;
+        file.seek(SeekFrom::Start(start)).await?;
+        Ok(Box::pin(file.take(end.saturating_sub(start))))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), AppError> {
+        match fs::remove_file(self.path(key)).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<BlobInfo>, AppError> {
+        // walk the directory the prefix is in, then match the prefix on the keys
+        let start = match prefix.rsplit_once('/') {
+            Some((dir, _)) => self.root.join(dir),
+            None => self.root.clone(),
+        };
+        let mut objects = vec![];
+        let mut dirs = vec![start];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = match fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let meta = entry.metadata().await?;
+                let path = entry.path();
+                if meta.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+                let Some(key) = path
+                    .strip_prefix(&self.root)
+                    .ok()
+                    .and_then(|p| p.to_str())
+                    .map(|p| p.replace(std::path::MAIN_SEPARATOR, "/"))
+                else {
+                    continue;
+                };
+                if key.starts_with(prefix) {
+                    objects.push(BlobInfo {
+                        key,
+                        size: meta.len(),
+                        modified: DateTime::<Utc>::from(meta.modified()?),
+                    });
+                }
+            }
+        }
+        Ok(objects)
+    }
+}
@@ -0,0 +1,97 @@
+mod encrypted;
+mod local;
+mod s3;
+
+pub use encrypted::*;
+pub use local::*;
+#[cfg(test)]
+pub(crate) use s3::tests::stand_in as s3_stand_in;
+pub use s3::*;
+
+use std::{path::Path, pin::Pin, sync::Arc};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{AppError, BlobStorageConfig, ServerConfig};
+
+pub type BlobReader = Pin<Box<dyn AsyncRead + Send>>;
+
+// an object as it is listed
+#[derive(Debug, Clone)]
+pub struct BlobInfo {
+    pub key: String,
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+}
+
+// where the content of files is kept. keys are relative paths such as `blobs/ab/cd/{sha256}`
+#[async_trait]
+pub trait BlobStorage: Send + Sync {
+    // store the content of the local file under the key, the local file is gone afterwards
+    async fn put_file(&self, key: &str, path: &Path) -> Result<(), AppError>;
+    async fn put(&self, key: &str, content: Vec<u8>) -> Result<(), AppError>;
+    // None if there is no such object
+    async fn size(&self, key: &str) -> Result<Option<u64>, AppError>;
+    // the bytes [start, end) of the object
+    async fn read(&self, key: &str, start: u64, end: u64) -> Result<BlobReader, AppError>;
+    // deleting a missing object is not an error
+    async fn delete(&self, key: &str) -> Result<(), AppError>;
+    // every object whose key starts with the prefix
+    async fn list(&self, prefix: &str) -> Result<Vec<BlobInfo>, AppError>;
+}
+
+// the storage the config names for file contents, as it is without encryption
+pub fn blob_backend(config: &ServerConfig) -> Result<Arc<dyn BlobStorage>, AppError> {
+    Ok(match &config.blobs {
+        BlobStorageConfig::Local { dir } => {
+            Arc::new(LocalStorage::new(dir.as_ref().unwrap_or(&config.base_dir)))
+        }
+        BlobStorageConfig::S3(s3) => Arc::new(S3Storage::new(s3.clone())?),
+    })
+}
+
+// what file contents are stored in, encrypted when master keys are configured
+pub fn blob_storage(config: &ServerConfig) -> Result<Arc<dyn BlobStorage>, AppError> {
+    let backend = blob_backend(config)?;
+    Ok(
+        match EncryptedStorage::from_config(config, backend.clone())? {
+            Some(encrypted) => Arc::new(encrypted),
+            None => backend,
+        },
+    )
+}
+
+// an object that exists, with its size known before it is read
+pub struct BlobObject {
+    storage: Arc<dyn BlobStorage>,
+    key: String,
+    pub size: u64,
+}
+
+impl BlobObject {
+    pub async fn open(storage: Arc<dyn BlobStorage>, key: &str) -> Result<Self, AppError> {
+        let Some(size) = storage.size(key).await? else {
+            return Err(AppError::FileNotFound("file not found".into()));
+        };
+        Ok(Self {
+            storage,
+            key: key.to_string(),
+            size,
+        })
+    }
+
+    pub async fn read(&self, start: u64, end: u64) -> Result<BlobReader, AppError> {
+        self.storage.read(&self.key, start, end).await
+    }
+
+    pub async fn read_all(&self) -> Result<Vec<u8>, AppError> {
+        let mut content = Vec::with_capacity(self.size as usize);
+        self.read(0, self.size)
+            .await?
+            .read_to_end(&mut content)
+            .await?;
+        Ok(content)
+    }
+}
@@ -0,0 +1,949 @@
+use std::{
+    cmp::Reverse,
+    collections::HashSet,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU32, AtomicU64, Ordering},
+    },
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use dashmap::{DashMap, mapref::entry::Entry};
+
+use crate::{
+    AppError, Chat, ChatStore, ChatType, CreateChat, CreateMessage, CreateUser, FileId, FileMeta,
+    FileQuota, FileRecord, Invitation, Message, MessageLog, MessageVersion, Reaction, RefreshToken,
+    SearchHit, SearchIndex, SearchQuery, User, UserChat, UserType, Workspace, highlight, tokenize,
+};
+
+// keeps everything in DashMaps, used for tests and when no database is configured
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    // workspace name -> workspace
+    workspaces: Arc<DashMap<String, Workspace>>,
+    workspace_idx: Arc<AtomicU32>,
+    users: Arc<DashMap<u32, User>>,
+    // email -> user id, so concurrent signups cannot take the same email
+    emails: Arc<DashMap<String, u32>>,
+    user_idx: Arc<AtomicU32>,
+    chats: Arc<DashMap<u32, Chat>>,
+    chat_idx: Arc<AtomicU32>,
+    // (chat id, invited user id) -> pending invitation
+    invitations: Arc<DashMap<(u32, u32), Invitation>>,
+    // chat id -> ordered log of that chat
+    messages: Arc<DashMap<u32, MessageLog>>,
+    message_idx: Arc<AtomicU64>,
+    // held while a message takes its id and is appended, so ids are appended in order across
+    // all chats, not just within one
+    message_lock: Arc<Mutex<()>>,
+    // chat id -> search index of its messages
+    search: Arc<DashMap<u32, SearchIndex>>,
+    // (chat id, user id) -> last read message id
+    reads: Arc<DashMap<(u32, u32), u64>>,
+    // message id -> earlier contents, oldest first
+    message_history: Arc<DashMap<u64, Vec<MessageVersion>>>,
+    // chat file -> what is known about it
+    files: Arc<DashMap<FileId, FileRecord>>,
+    // blob hash -> how many chat files use it
+    blobs: Arc<DashMap<String, u32>>,
+    // held while an upload is checked against the quotas and added
+    uploads: Arc<Mutex<()>>,
+    // blob hash -> what processing found out
+    file_meta: Arc<DashMap<String, FileMeta>>,
+    // refresh token hash -> token
+    refresh_tokens: Arc<DashMap<String, RefreshToken>>,
+    // revoked jti -> when the access token expires
+    revoked_tokens: Arc<DashMap<String, DateTime<Utc>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // count the chat's reference to the blob unless the chat has the file already
+    fn insert_file(&self, file: FileRecord) -> bool {
+        match self.files.entry(file.file.clone()) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                *self.blobs.entry(file.file.hash.clone()).or_default() += 1;
+                entry.insert(file);
+                true
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl ChatStore for MemoryStore {
+    async fn find_workspace_by_name(&self, name: &str) -> Result<Option<Workspace>, AppError> {
+        Ok(self.workspaces.get(name).map(|ws| ws.value().clone()))
+    }
+
+    async fn create_workspace(&self, name: &str) -> Result<Option<Workspace>, AppError> {
+        match self.workspaces.entry(name.to_string()) {
+            Entry::Occupied(_) => Ok(None),
+            Entry::Vacant(entry) => {
+                let ws = Workspace {
+                    id: self.workspace_idx.fetch_add(1, Ordering::SeqCst) + 1,
+                    name: name.to_string(),
+                    owner_id: None,
+                    created_at: Utc::now(),
+                };
+                entry.insert(ws.clone());
+                Ok(Some(ws))
+            }
+        }
+    }
+
+    async fn get_workspace(&self, ws_id: u32) -> Result<Option<Workspace>, AppError> {
+        Ok(self
+            .workspaces
+            .iter()
+            .find(|ws| ws.id == ws_id)
+            .map(|ws| ws.value().clone()))
+    }
+
+    async fn set_workspace_owner(&self, ws_id: u32, owner_id: u32) -> Result<(), AppError> {
+        if let Some(mut ws) = self.workspaces.iter_mut().find(|ws| ws.id == ws_id) {
+            ws.owner_id = Some(owner_id);
+        }
+        Ok(())
+    }
+
+    async fn delete_empty_workspace(&self, ws_id: u32) -> Result<(), AppError> {
+        self.workspaces
+            .retain(|_, ws| ws.id != ws_id || self.users.iter().any(|u| u.ws_id == ws_id));
+        Ok(())
+    }
+
+    async fn create_user(&self, input: CreateUser) -> Result<User, AppError> {
+        let Entry::Vacant(email) = self.emails.entry(input.email.clone()) else {
+            return Err(AppError::CreateUserError("email already exists".into()));
+        };
+        let id = self.user_idx.fetch_add(1, Ordering::SeqCst) + 1;
+        let user = User {
+            id,
+            ws_id: input.ws_id,
+            name: input.name,
+            email: input.email,
+            gender: input.gender,
+            teenager: input.teenager,
+            user_type: UserType::Regular,
+            hash_password: input.hash_password,
+        };
+        self.users.insert(id, user.clone());
+        email.insert(id);
+        Ok(user)
+    }
+
+    async fn find_user_by_email(&self, email: &str) -> Result<Option<User>, AppError> {
+        Ok(self
+            .users
+            .iter()
+            .find(|user| user.value().email == email)
+            .map(|user| user.value().clone()))
+    }
+
+    async fn get_user(&self, user_id: u32) -> Result<Option<User>, AppError> {
+        Ok(self.users.get(&user_id).map(|user| user.value().clone()))
+    }
+
+    async fn list_users(&self, ws_id: u32) -> Result<Vec<User>, AppError> {
+        let mut users = self
+            .users
+            .iter()
+            .filter(|user| user.ws_id == ws_id)
+            .map(|user| user.value().clone())
+            .collect::<Vec<_>>();
+        users.sort_by_key(|user| user.id);
+        Ok(users)
+    }
+
+    async fn find_chat_members_by_name(
+        &self,
+        chat_id: u32,
+        names: &[String],
+    ) -> Result<Vec<User>, AppError> {
+        let Some(members) = self.chats.get(&chat_id).map(|chat| chat.members.clone()) else {
+            return Ok(vec![]);
+        };
+        let mut users = members
+            .iter()
+            .filter_map(|id| self.users.get(id).map(|user| user.value().clone()))
+            .filter(|user| names.contains(&user.name.to_lowercase()))
+            .collect::<Vec<_>>();
+        users.sort_by_key(|user| user.id);
+        Ok(users)
+    }
+
+    async fn set_user_type(
+        &self,
+        email: &str,
+        user_type: UserType,
+    ) -> Result<Option<User>, AppError> {
+        Ok(self
+            .users
+            .iter_mut()
+            .find(|user| user.email == email)
+            .map(|mut user| {
+                user.user_type = user_type;
+                user.clone()
+            }))
+    }
+
+    async fn delete_user(&self, user_id: u32) -> Result<bool, AppError> {
+        let Some((_, user)) = self.users.remove(&user_id) else {
+            return Ok(false);
+        };
+        self.emails.remove(&user.email);
+        // the longest standing member takes over, nobody if there is none
+        let heir = self
+            .users
+            .iter()
+            .filter(|u| u.ws_id == user.ws_id)
+            .map(|u| u.id)
+            .min();
+        for mut ws in self.workspaces.iter_mut() {
+            if ws.owner_id == Some(user_id) {
+                ws.owner_id = heir;
+            }
+        }
+        let mut orphans = vec![];
+        for mut chat in self.chats.iter_mut() {
+            chat.members.retain(|id| *id != user_id);
+            chat.moderators.retain(|id| *id != user_id);
+            if chat.owner_id == Some(user_id) {
+                // the longest standing moderator takes over, the longest standing member if
+                // there is no moderator
+                let heir = chat
+                    .members
+                    .iter()
+                    .find(|id| chat.moderators.contains(id))
+                    .or(chat.members.first())
+                    .copied();
+                chat.owner_id = heir;
+                match heir {
+                    Some(heir) => chat.moderators.retain(|id| *id != heir),
+                    None => orphans.push(chat.id),
+                }
+            }
+        }
+        // the chats nobody is left in go with the user
+        for chat_id in orphans {
+            self.delete_chat(chat_id).await?;
+        }
+        self.invitations
+            .retain(|_, inv| inv.user_id != user_id && inv.inviter_id != user_id);
+        for mut log in self.messages.iter_mut() {
+            let mut removed = HashSet::new();
+            let chat_id = *log.key();
+            for msg in log.iter().filter(|msg| msg.sender_id == user_id) {
+                self.message_history.remove(&msg.id);
+                if let Some(mut index) = self.search.get_mut(&chat_id) {
+                    index.remove(msg.id);
+                }
+                removed.insert(msg.id);
+            }
+            log.retain(|msg| msg.sender_id != user_id);
+            for msg in log.iter_mut() {
+                remove_reaction(msg, user_id, None);
+                msg.mentions.retain(|id| *id != user_id);
+                // the replies to the user's messages are left without a thread
+                if msg.reply_to.is_some_and(|id| removed.contains(&id)) {
+                    msg.reply_to = None;
+                }
+            }
+        }
+        self.reads.retain(|(_, id), _| *id != user_id);
+        self.refresh_tokens
+            .retain(|_, token| token.user_id != user_id);
+        Ok(true)
+    }
+
+    async fn create_refresh_token(
+        &self,
+        token_hash: &str,
+        user_id: u32,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), AppError> {
+        let now = Utc::now();
+        self.refresh_tokens
+            .retain(|_, token| token.expires_at > now);
+        self.refresh_tokens.insert(
+            token_hash.to_string(),
+            RefreshToken {
+                user_id,
+                expires_at,
+            },
+        );
+        Ok(())
+    }
+
+    async fn take_refresh_token(&self, token_hash: &str) -> Result<Option<RefreshToken>, AppError> {
+        Ok(self
+            .refresh_tokens
+            .remove(token_hash)
+            .map(|(_, token)| token))
+    }
+
+    async fn delete_refresh_token(&self, token_hash: &str, user_id: u32) -> Result<bool, AppError> {
+        Ok(self
+            .refresh_tokens
+            .remove_if(token_hash, |_, token| token.user_id == user_id)
+            .is_some())
+    }
+
+    async fn revoke_token(&self, jti: &str, expires_at: DateTime<Utc>) -> Result<(), AppError> {
+        let now = Utc::now();
+        self.revoked_tokens.retain(|_, exp| *exp > now);
+        self.revoked_tokens.insert(jti.to_string(), expires_at);
+        Ok(())
+    }
+
+    async fn is_token_revoked(&self, jti: &str) -> Result<bool, AppError> {
+        Ok(self.revoked_tokens.contains_key(jti))
+    }
+
+    async fn create_chat(&self, input: CreateChat) -> Result<Chat, AppError> {
+        let chat = Chat {
+            id: self.chat_idx.fetch_add(1, Ordering::SeqCst) + 1,
+            ws_id: input.ws_id,
+            name: input.name,
+            chat_type: input.chat_type,
+            owner_id: Some(input.owner_id),
+            moderators: vec![],
+            members: input.members,
+        };
+        self.chats.insert(chat.id, chat.clone());
+        Ok(chat)
+    }
+
+    async fn get_chat(&self, chat_id: u32) -> Result<Option<Chat>, AppError> {
+        Ok(self.chats.get(&chat_id).map(|chat| chat.value().clone()))
+    }
+
+    async fn list_user_chats(&self, user_id: u32) -> Result<Vec<Chat>, AppError> {
+        Ok(self
+            .chats
+            .iter()
+            .filter(|chat| chat.members.contains(&user_id))
+            .map(|chat| chat.value().clone())
+            .collect())
+    }
+
+    async fn list_user_chats_unread(&self, user_id: u32) -> Result<Vec<UserChat>, AppError> {
+        let mut chats = self.list_user_chats(user_id).await?;
+        chats.sort_by_key(|chat| chat.id);
+        Ok(chats
+            .into_iter()
+            .map(|chat| {
+                let last_read = self.reads.get(&(chat.id, user_id)).map_or(0, |id| *id);
+                let unread = self.messages.get(&chat.id).map_or(0, |log| {
+                    log.iter()
+                        .rev()
+                        .take_while(|msg| msg.id > last_read)
+                        .filter(|msg| msg.sender_id != user_id && msg.deleted_at.is_none())
+                        .count()
+                });
+                UserChat {
+                    chat,
+                    last_read,
+                    unread: unread as u32,
+                }
+            })
+            .collect())
+    }
+
+    async fn mark_read(
+        &self,
+        chat_id: u32,
+        user_id: u32,
+        message_id: u64,
+    ) -> Result<u64, AppError> {
+        let mut last_read = self.reads.entry((chat_id, user_id)).or_default();
+        let earlier = *last_read;
+        *last_read = earlier.max(message_id);
+        Ok(earlier)
+    }
+
+    async fn search_public_chats(
+        &self,
+        ws_id: u32,
+        query: Option<&str>,
+        limit: u32,
+    ) -> Result<Vec<Chat>, AppError> {
+        let query = query.map(str::to_lowercase);
+        let mut chats = self
+            .chats
+            .iter()
+            .filter(|chat| chat.ws_id == ws_id && chat.chat_type == ChatType::PublicChannel)
+            .filter(|chat| {
+                query
+                    .as_ref()
+                    .is_none_or(|q| chat.name.to_lowercase().contains(q))
+            })
+            .map(|chat| chat.value().clone())
+            .collect::<Vec<_>>();
+        chats.sort_by_key(|chat| chat.id);
+        chats.truncate(limit as usize);
+        Ok(chats)
+    }
+
+    async fn delete_chat(&self, chat_id: u32) -> Result<bool, AppError> {
+        if let Some((_, log)) = self.messages.remove(&chat_id) {
+            for msg in log.iter() {
+                self.message_history.remove(&msg.id);
+            }
+        }
+        self.invitations.retain(|(id, _), _| *id != chat_id);
+        self.reads.retain(|(id, _), _| *id != chat_id);
+        self.search.remove(&chat_id);
+        self.files.retain(|file, _| {
+            if file.chat_id != chat_id {
+                return true;
+            }
+            if let Some(mut refcount) = self.blobs.get_mut(&file.hash) {
+                *refcount = refcount.saturating_sub(1);
+            }
+            false
+        });
+        Ok(self.chats.remove(&chat_id).is_some())
+    }
+
+    async fn add_chat_member(&self, chat_id: u32, user_id: u32) -> Result<Option<Chat>, AppError> {
+        let Some(mut chat) = self.chats.get_mut(&chat_id) else {
+            return Ok(None);
+        };
+        if !chat.members.contains(&user_id) {
+            chat.members.push(user_id);
+        }
+        Ok(Some(chat.clone()))
+    }
+
+    async fn remove_chat_member(
+        &self,
+        chat_id: u32,
+        user_id: u32,
+    ) -> Result<Option<Chat>, AppError> {
+        let Some(mut chat) = self.chats.get_mut(&chat_id) else {
+            return Ok(None);
+        };
+        chat.members.retain(|id| *id != user_id);
+        chat.moderators.retain(|id| *id != user_id);
+        Ok(Some(chat.clone()))
+    }
+
+    async fn set_chat_moderator(
+        &self,
+        chat_id: u32,
+        user_id: u32,
+        moderator: bool,
+    ) -> Result<Option<Chat>, AppError> {
+        let Some(mut chat) = self.chats.get_mut(&chat_id) else {
+            return Ok(None);
+        };
+        chat.moderators.retain(|id| *id != user_id);
+        if moderator {
+            chat.moderators.push(user_id);
+        }
+        Ok(Some(chat.clone()))
+    }
+
+    async fn set_chat_owner(&self, chat_id: u32, owner_id: u32) -> Result<Option<Chat>, AppError> {
+        let Some(mut chat) = self.chats.get_mut(&chat_id) else {
+            return Ok(None);
+        };
+        chat.owner_id = Some(owner_id);
+        chat.moderators.retain(|id| *id != owner_id);
+        Ok(Some(chat.clone()))
+    }
+
+    async fn create_invitation(
+        &self,
+        chat_id: u32,
+        user_id: u32,
+        inviter_id: u32,
+    ) -> Result<(), AppError> {
+        let Some(chat) = self.chats.get(&chat_id) else {
+            return Err(AppError::ChatNotFound(format!("chat {chat_id} not found")));
+        };
+        let invitation = Invitation {
+            chat_id,
+            chat_name: chat.name.clone(),
+            chat_type: chat.chat_type,
+            user_id,
+            inviter_id,
+            created_at: Utc::now(),
+        };
+        self.invitations.insert((chat_id, user_id), invitation);
+        Ok(())
+    }
+
+    async fn list_invitations(&self, user_id: u32) -> Result<Vec<Invitation>, AppError> {
+        let mut invitations = self
+            .invitations
+            .iter()
+            .filter(|inv| inv.user_id == user_id)
+            .map(|inv| inv.value().clone())
+            .collect::<Vec<_>>();
+        invitations.sort_by_key(|inv| inv.created_at);
+        Ok(invitations)
+    }
+
+    async fn take_invitation(
+        &self,
+        chat_id: u32,
+        user_id: u32,
+    ) -> Result<Option<Invitation>, AppError> {
+        Ok(self
+            .invitations
+            .remove(&(chat_id, user_id))
+            .map(|(_, inv)| inv))
+    }
+
+    async fn create_message(&self, input: CreateMessage) -> Result<Message, AppError> {
+        // a later id is never appended first, in this chat or any other
+        let _order = self
+            .message_lock
+            .lock()
+            .map_err(|e| AppError::StorageError(e.to_string()))?;
+        let mut log = self.messages.entry(input.chat_id).or_default();
+        let msg = Message {
+            id: self.message_idx.fetch_add(1, Ordering::SeqCst) + 1,
+            chat_id: input.chat_id,
+            sender_id: input.sender_id,
+            content: input.content,
+            files: input.files,
+            date: Utc::now(),
+            edited_at: None,
+            deleted_at: None,
+            reactions: vec![],
+            reply_to: input.reply_to,
+            mentions: input.mentions,
+        };
+        self.search
+            .entry(msg.chat_id)
+            .or_default()
+            .insert(msg.id, &msg.content);
+        log.append(msg.clone());
+        Ok(msg)
+    }
+
+    async fn list_messages(
+        &self,
+        chat_id: u32,
+        before: Option<u64>,
+        limit: u32,
+    ) -> Result<Vec<Message>, AppError> {
+        Ok(self
+            .messages
+            .get(&chat_id)
+            .map(|log| log.page(before, limit as usize))
+            .unwrap_or_default())
+    }
+
+    async fn list_messages_after(
+        &self,
+        chat_id: u32,
+        after: u64,
+        limit: u32,
+    ) -> Result<Vec<Message>, AppError> {
+        Ok(self
+            .messages
+            .get(&chat_id)
+            .map(|log| log.after(after, limit as usize))
+            .unwrap_or_default())
+    }
+
+    async fn get_message(
+        &self,
+        chat_id: u32,
+        message_id: u64,
+    ) -> Result<Option<Message>, AppError> {
+        Ok(self
+            .messages
+            .get(&chat_id)
+            .and_then(|log| log.get(message_id).cloned()))
+    }
+
+    async fn list_replies(
+        &self,
+        chat_id: u32,
+        root_id: u64,
+        after: Option<u64>,
+        limit: u32,
+    ) -> Result<Vec<Message>, AppError> {
+        let Some(log) = self.messages.get(&chat_id) else {
+            return Ok(vec![]);
+        };
+        Ok(log
+            .iter()
+            .filter(|msg| msg.reply_to == Some(root_id) && after.is_none_or(|after| msg.id > after))
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn list_mentions(
+        &self,
+        user_id: u32,
+        before: Option<u64>,
+        limit: u32,
+    ) -> Result<Vec<Message>, AppError> {
+        let chat_ids = self
+            .chats
+            .iter()
+            .filter(|chat| chat.members.contains(&user_id))
+            .map(|chat| chat.id)
+            .collect::<Vec<_>>();
+        let mut mentions = vec![];
+        for chat_id in chat_ids {
+            let Some(log) = self.messages.get(&chat_id) else {
+                continue;
+            };
+            mentions.extend(
+                log.iter()
+                    .rev()
+                    .filter(|msg| before.is_none_or(|before| msg.id < before))
+                    .filter(|msg| msg.mentions.contains(&user_id))
+                    .take(limit as usize)
+                    .cloned(),
+            );
+        }
+        mentions.sort_by_key(|msg| Reverse(msg.id));
+        mentions.truncate(limit as usize);
+        Ok(mentions)
+    }
+
+    async fn search_messages(
+        &self,
+        user_id: u32,
+        query: &SearchQuery,
+    ) -> Result<Vec<SearchHit>, AppError> {
+        let terms = tokenize(&query.q).collect::<Vec<_>>();
+        if terms.is_empty() {
+            return Ok(vec![]);
+        }
+        let chat_ids = self
+            .chats
+            .iter()
+            .filter(|chat| chat.members.contains(&user_id))
+            .filter(|chat| query.chat_id.is_none_or(|id| chat.id == id))
+            .map(|chat| chat.id)
+            .collect::<Vec<_>>();
+        let mut hits = vec![];
+        for chat_id in chat_ids {
+            // the index is let go before the log is locked, writers lock the log first
+            let found = match self.search.get(&chat_id) {
+                Some(index) => index.search(&terms),
+                None => continue,
+            };
+            let Some(log) = self.messages.get(&chat_id) else {
+                continue;
+            };
+            for (id, rank) in found {
+                let Some(msg) = log.get(id) else {
+                    continue;
+                };
+                if msg.deleted_at.is_some()
+                    || query.sender_id.is_some_and(|id| msg.sender_id != id)
+                    || query.from.is_some_and(|from| msg.date < from)
+                    || query.to.is_some_and(|to| msg.date >= to)
+                {
+                    continue;
+                }
+                hits.push(SearchHit {
+                    highlight: highlight(&msg.content, &terms),
+                    message: msg.clone(),
+                    rank,
+                });
+            }
+        }
+        hits.sort_by(|a, b| {
+            b.rank
+                .total_cmp(&a.rank)
+                .then(b.message.id.cmp(&a.message.id))
+        });
+        Ok(hits
+            .into_iter()
+            .skip(query.offset as usize)
+            .take(query.limit as usize)
+            .collect())
+    }
+
+    async fn edit_message(
+        &self,
+        chat_id: u32,
+        message_id: u64,
+        content: String,
+        mentions: Vec<u32>,
+    ) -> Result<Option<Message>, AppError> {
+        let Some(mut log) = self.messages.get_mut(&chat_id) else {
+            return Ok(None);
+        };
+        let Some(msg) = log
+            .get_mut(message_id)
+            .filter(|msg| msg.deleted_at.is_none())
+        else {
+            return Ok(None);
+        };
+        let earlier = MessageVersion {
+            content: std::mem::replace(&mut msg.content, content),
+            date: msg.edited_at.unwrap_or(msg.date),
+        };
+        self.message_history
+            .entry(message_id)
+            .or_default()
+            .push(earlier);
+        msg.mentions = mentions;
+        msg.edited_at = Some(Utc::now());
+        self.search
+            .entry(chat_id)
+            .or_default()
+            .insert(message_id, &msg.content);
+        Ok(Some(msg.clone()))
+    }
+
+    async fn delete_message(
+        &self,
+        chat_id: u32,
+        message_id: u64,
+    ) -> Result<Option<Message>, AppError> {
+        let Some(mut log) = self.messages.get_mut(&chat_id) else {
+            return Ok(None);
+        };
+        let Some(msg) = log
+            .get_mut(message_id)
+            .filter(|msg| msg.deleted_at.is_none())
+        else {
+            return Ok(None);
+        };
+        msg.content.clear();
+        msg.files.clear();
+        msg.mentions.clear();
+        msg.reactions.clear();
+        msg.deleted_at = Some(Utc::now());
+        self.message_history.remove(&message_id);
+        if let Some(mut index) = self.search.get_mut(&chat_id) {
+            index.remove(message_id);
+        }
+        Ok(Some(msg.clone()))
+    }
+
+    async fn toggle_reaction(
+        &self,
+        chat_id: u32,
+        message_id: u64,
+        user_id: u32,
+        emoji: &str,
+    ) -> Result<Option<Message>, AppError> {
+        let Some(mut log) = self.messages.get_mut(&chat_id) else {
+            return Ok(None);
+        };
+        let Some(msg) = log
+            .get_mut(message_id)
+            .filter(|msg| msg.deleted_at.is_none())
+        else {
+            return Ok(None);
+        };
+        if !remove_reaction(msg, user_id, Some(emoji)) {
+            match msg.reactions.iter_mut().find(|r| r.emoji == emoji) {
+                Some(reaction) => {
+                    reaction.user_ids.push(user_id);
+                    reaction.count += 1;
+                }
+                None => msg.reactions.push(Reaction {
+                    emoji: emoji.to_string(),
+                    count: 1,
+                    user_ids: vec![user_id],
+                }),
+            }
+        }
+        Ok(Some(msg.clone()))
+    }
+
+    async fn message_history(
+        &self,
+        chat_id: u32,
+        message_id: u64,
+    ) -> Result<Vec<MessageVersion>, AppError> {
+        let in_chat = self
+            .messages
+            .get(&chat_id)
+            .is_some_and(|log| log.get(message_id).is_some());
+        if !in_chat {
+            return Ok(vec![]);
+        }
+        Ok(self
+            .message_history
+            .get(&message_id)
+            .map(|history| history.clone())
+            .unwrap_or_default())
+    }
+
+    async fn add_chat_file(&self, file: FileRecord) -> Result<bool, AppError> {
+        Ok(self.insert_file(file))
+    }
+
+    async fn add_chat_files(
+        &self,
+        files: Vec<FileRecord>,
+        quota: FileQuota,
+    ) -> Result<(), AppError> {
+        let Some(first) = files.first() else {
+            return Ok(());
+        };
+        let (chat_id, uploader_id) = (first.file.chat_id, first.uploader_id);
+        let _uploads = self
+            .uploads
+            .lock()
+            .map_err(|e| AppError::StorageError(e.to_string()))?;
+        let mut new_files = HashSet::new();
+        let new_size: u64 = files
+            .iter()
+            .filter(|record| {
+                !self.files.contains_key(&record.file) && new_files.insert(&record.file)
+            })
+            .map(|record| record.size)
+            .sum();
+        let (user_usage, chat_usage) = self.files.iter().fold((0, 0), |(user, chat), record| {
+            (
+                user + if record.uploader_id == uploader_id {
+                    record.size
+                } else {
+                    0
+                },
+                chat + if record.file.chat_id == chat_id {
+                    record.size
+                } else {
+                    0
+                },
+            )
+        });
+        quota.check(new_size, user_usage, chat_usage)?;
+        for record in files {
+            self.insert_file(record);
+        }
+        Ok(())
+    }
+
+    async fn get_chat_file(&self, file: &FileId) -> Result<Option<FileRecord>, AppError> {
+        Ok(self.files.get(file).map(|record| record.value().clone()))
+    }
+
+    async fn user_file_usage(&self, user_id: u32) -> Result<u64, AppError> {
+        Ok(self
+            .files
+            .iter()
+            .filter(|record| record.uploader_id == user_id)
+            .map(|record| record.size)
+            .sum())
+    }
+
+    async fn chat_file_usage(&self, chat_id: u32) -> Result<u64, AppError> {
+        Ok(self
+            .files
+            .iter()
+            .filter(|record| record.file.chat_id == chat_id)
+            .map(|record| record.size)
+            .sum())
+    }
+
+    async fn blob_refcount(&self, hash: &str) -> Result<Option<u32>, AppError> {
+        Ok(self.blobs.get(hash).map(|refcount| *refcount))
+    }
+
+    async fn set_file_meta(&self, hash: &str, meta: FileMeta) -> Result<(), AppError> {
+        if self.blobs.contains_key(hash) {
+            self.file_meta.insert(hash.to_string(), meta);
+        }
+        Ok(())
+    }
+
+    async fn get_file_meta(&self, hash: &str) -> Result<Option<FileMeta>, AppError> {
+        Ok(self.file_meta.get(hash).map(|meta| meta.value().clone()))
+    }
+
+    async fn delete_blob(&self, hash: &str) -> Result<bool, AppError> {
+        self.blobs.remove_if(hash, |_, refcount| *refcount == 0);
+        if self.blobs.contains_key(hash) {
+            return Ok(false);
+        }
+        self.file_meta.remove(hash);
+        Ok(true)
+    }
+}
+
+// take back the user's reaction with the emoji, or all of them, return false if there was none.
+// emojis nobody reacts with any more are dropped
+fn remove_reaction(msg: &mut Message, user_id: u32, emoji: Option<&str>) -> bool {
+    let mut removed = false;
+    for reaction in &mut msg.reactions {
+        if emoji.is_none_or(|emoji| reaction.emoji == emoji) && reaction.user_ids.contains(&user_id)
+        {
+            reaction.user_ids.retain(|id| *id != user_id);
+            reaction.count -= 1;
+            removed = true;
+        }
+    }
+    msg.reactions.retain(|reaction| reaction.count > 0);
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+
+    fn new_message(chat_id: u32, sender_id: u32, content: &str) -> CreateMessage {
+        CreateMessage {
+            chat_id,
+            sender_id,
+            content: content.to_string(),
+            files: vec![],
+            reply_to: None,
+            mentions: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_messages_are_retained_per_chat() -> Result<()> {
+        let store = MemoryStore::new();
+        store.create_message(new_message(1, 1, "a")).await?;
+        store.create_message(new_message(2, 1, "b")).await?;
+        store.create_message(new_message(1, 2, "c")).await?;
+
+        let chat1 = store.list_messages(1, None, 10).await?;
+        let contents = chat1.iter().map(|m| m.content.as_str()).collect::<Vec<_>>();
+        assert_eq!(contents, vec!["c", "a"]);
+        assert_eq!(store.list_messages(2, None, 10).await?.len(), 1);
+        assert!(store.list_messages(3, None, 10).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_ids_are_monotonic() -> Result<()> {
+        let store = MemoryStore::new();
+        let handles = (0..100)
+            .map(|i| {
+                let store = store.clone();
+                tokio::spawn(async move {
+                    store
+                        .create_message(new_message(1, i % 3, &i.to_string()))
+                        .await
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.await??;
+        }
+
+        let messages = store.list_messages(1, None, 1000).await?;
+        assert_eq!(messages.len(), 100);
+        assert!(messages.windows(2).all(|w| w[0].id > w[1].id));
+        Ok(())
+    }
+}
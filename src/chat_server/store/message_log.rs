@@ -0,0 +1,121 @@
+use crate::Message;
+
+// the messages of one chat, always sorted by id
+#[derive(Debug, Default, Clone)]
+pub struct MessageLog {
+    messages: Vec<Message>,
+}
+
+impl MessageLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // the caller must hold the log exclusively while assigning `msg.id`,
+    // so that ids only grow in the order messages are appended
+    pub fn append(&mut self, msg: Message) {
+        debug_assert!(self.messages.last().is_none_or(|last| last.id < msg.id));
+        self.messages.push(msg);
+    }
+
+    // newest first, only messages older than `before` if it is set
+    pub fn page(&self, before: Option<u64>, limit: usize) -> Vec<Message> {
+        let end = match before {
+            Some(before) => self.messages.partition_point(|msg| msg.id < before),
+            None => self.messages.len(),
+        };
+        self.messages[..end]
+            .iter()
+            .rev()
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    // oldest first, the messages newer than `after`
+    pub fn after(&self, after: u64, limit: usize) -> Vec<Message> {
+        let start = self.messages.partition_point(|msg| msg.id <= after);
+        self.messages[start..].iter().take(limit).cloned().collect()
+    }
+
+    // drop messages, order is kept
+    pub fn retain(&mut self, f: impl FnMut(&Message) -> bool) {
+        self.messages.retain(f);
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Message> {
+        self.messages
+            .binary_search_by_key(&id, |msg| msg.id)
+            .ok()
+            .map(|idx| &self.messages[idx])
+    }
+
+    // ids must not be changed, they keep the log in order
+    pub fn get_mut(&mut self, id: u64) -> Option<&mut Message> {
+        self.messages
+            .binary_search_by_key(&id, |msg| msg.id)
+            .ok()
+            .map(|idx| &mut self.messages[idx])
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Message> {
+        self.messages.iter()
+    }
+
+    // ids must not be changed, they keep the log in order
+    pub fn iter_mut(&mut self) -> impl DoubleEndedIterator<Item = &mut Message> {
+        self.messages.iter_mut()
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+
+    fn msg(id: u64) -> Message {
+        Message {
+            id,
+            chat_id: 1,
+            sender_id: 1,
+            content: format!("msg {id}"),
+            files: vec![],
+            date: Utc::now(),
+            edited_at: None,
+            deleted_at: None,
+            reactions: vec![],
+            reply_to: None,
+            mentions: vec![],
+        }
+    }
+
+    #[test]
+    fn test_page() {
+        let mut log = MessageLog::new();
+        for id in [2, 4, 6, 8] {
+            log.append(msg(id));
+        }
+        assert_eq!(log.len(), 4);
+        assert_eq!(log.get(6).map(|m| m.content.as_str()), Some("msg 6"));
+        assert!(log.get(5).is_none());
+        log.get_mut(6).unwrap().content = "edited".into();
+        assert_eq!(log.get(6).map(|m| m.content.as_str()), Some("edited"));
+        let ids = |page: Vec<Message>| page.iter().map(|m| m.id).collect::<Vec<_>>();
+        assert_eq!(ids(log.page(None, 3)), vec![8, 6, 4]);
+        assert_eq!(ids(log.page(Some(6), 10)), vec![4, 2]);
+        assert_eq!(ids(log.page(Some(5), 1)), vec![4]);
+        assert_eq!(ids(log.page(Some(2), 10)), Vec::<u64>::new());
+        assert_eq!(ids(log.after(4, 10)), vec![6, 8]);
+        assert_eq!(ids(log.after(5, 1)), vec![6]);
+        assert_eq!(ids(log.after(8, 10)), Vec::<u64>::new());
+    }
+}
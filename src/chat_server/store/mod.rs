@@ -0,0 +1,527 @@
+mod memory;
+mod message_log;
+mod postgres;
+mod search_index;
+
+pub use memory::*;
+pub use message_log::*;
+pub use postgres::*;
+pub use search_index::*;
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use crate::{
+    AppConfig, AppError, Chat, CreateChat, CreateMessage, CreateUser, FileId, FileMeta, FileQuota,
+    FileRecord, Invitation, Message, MessageVersion, RefreshToken, SearchHit, SearchQuery,
+    StorageKind, User, UserChat, UserType, Workspace,
+};
+
+// the store the config asks for, postgres needs `database.url`
+pub async fn chat_store(config: &AppConfig) -> Result<Arc<dyn ChatStore>, AppError> {
+    Ok(match config.server.storage {
+        StorageKind::Memory => Arc::new(MemoryStore::new()),
+        StorageKind::Postgres => {
+            let Some(db) = &config.database else {
+                return Err(AppError::ConfigError(
+                    "database url is required for postgres storage".into(),
+                ));
+            };
+            Arc::new(PgStore::try_new(&db.url).await?)
+        }
+    })
+}
+
+// everything the chat server keeps, so the handlers do not care where it lives
+#[async_trait]
+pub trait ChatStore: Send + Sync {
+    async fn find_workspace_by_name(&self, name: &str) -> Result<Option<Workspace>, AppError>;
+    // create the workspace without an owner, return None if the name is already taken
+    async fn create_workspace(&self, name: &str) -> Result<Option<Workspace>, AppError>;
+    async fn get_workspace(&self, ws_id: u32) -> Result<Option<Workspace>, AppError>;
+    async fn set_workspace_owner(&self, ws_id: u32, owner_id: u32) -> Result<(), AppError>;
+    // drop the workspace unless it has users, to undo a sign up that failed after creating it
+    async fn delete_empty_workspace(&self, ws_id: u32) -> Result<(), AppError>;
+
+    async fn create_user(&self, input: CreateUser) -> Result<User, AppError>;
+    async fn find_user_by_email(&self, email: &str) -> Result<Option<User>, AppError>;
+    async fn get_user(&self, user_id: u32) -> Result<Option<User>, AppError>;
+    // the users of one workspace
+    async fn list_users(&self, ws_id: u32) -> Result<Vec<User>, AppError>;
+    // the members of the chat whose lowercased name is one of `names`, ordered by id. empty
+    // if the chat does not exist
+    async fn find_chat_members_by_name(
+        &self,
+        chat_id: u32,
+        names: &[String],
+    ) -> Result<Vec<User>, AppError>;
+    // return None if there is no user with the email
+    async fn set_user_type(
+        &self,
+        email: &str,
+        user_type: UserType,
+    ) -> Result<Option<User>, AppError>;
+    // also drops the user's messages, chat memberships and refresh tokens, the workspaces
+    // the user owns go to another member. the chats the user owns go to the longest standing
+    // moderator or member, and are deleted if nobody is left. return false if the user does
+    // not exist
+    async fn delete_user(&self, user_id: u32) -> Result<bool, AppError>;
+
+    async fn create_refresh_token(
+        &self,
+        token_hash: &str,
+        user_id: u32,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), AppError>;
+    // remove the token and return it, so a refresh token can be used only once
+    async fn take_refresh_token(&self, token_hash: &str) -> Result<Option<RefreshToken>, AppError>;
+    // remove the token if it belongs to the user, return false if it does not exist or is
+    // someone else's, those are left alone
+    async fn delete_refresh_token(&self, token_hash: &str, user_id: u32) -> Result<bool, AppError>;
+    // revoked access tokens are kept until they would have expired anyway
+    async fn revoke_token(&self, jti: &str, expires_at: DateTime<Utc>) -> Result<(), AppError>;
+    async fn is_token_revoked(&self, jti: &str) -> Result<bool, AppError>;
+
+    async fn create_chat(&self, input: CreateChat) -> Result<Chat, AppError>;
+    async fn get_chat(&self, chat_id: u32) -> Result<Option<Chat>, AppError>;
+    // the chats the user is a member of
+    async fn list_user_chats(&self, user_id: u32) -> Result<Vec<Chat>, AppError>;
+    // the chats the user is a member of with the read state, ordered by id
+    async fn list_user_chats_unread(&self, user_id: u32) -> Result<Vec<UserChat>, AppError>;
+    // move the user's last read message of the chat forward to `message_id`, it never moves
+    // back. return the last read message from before, 0 if there was none
+    async fn mark_read(&self, chat_id: u32, user_id: u32, message_id: u64)
+    -> Result<u64, AppError>;
+    // public channels of the workspace whose name contains `query`, case insensitive
+    async fn search_public_chats(
+        &self,
+        ws_id: u32,
+        query: Option<&str>,
+        limit: u32,
+    ) -> Result<Vec<Chat>, AppError>;
+    // also drops the chat's messages, invitations and files, releasing their blobs, return false if the chat does not exist
+    async fn delete_chat(&self, chat_id: u32) -> Result<bool, AppError>;
+    // the membership changes below return None if the chat does not exist
+    async fn add_chat_member(&self, chat_id: u32, user_id: u32) -> Result<Option<Chat>, AppError>;
+    // also drops the user from the moderators
+    async fn remove_chat_member(
+        &self,
+        chat_id: u32,
+        user_id: u32,
+    ) -> Result<Option<Chat>, AppError>;
+    async fn set_chat_moderator(
+        &self,
+        chat_id: u32,
+        user_id: u32,
+        moderator: bool,
+    ) -> Result<Option<Chat>, AppError>;
+    async fn set_chat_owner(&self, chat_id: u32, owner_id: u32) -> Result<Option<Chat>, AppError>;
+
+    // inviting again replaces the pending invitation
+    async fn create_invitation(
+        &self,
+        chat_id: u32,
+        user_id: u32,
+        inviter_id: u32,
+    ) -> Result<(), AppError>;
+    async fn list_invitations(&self, user_id: u32) -> Result<Vec<Invitation>, AppError>;
+    // remove the invitation and return it, for accepting or declining it
+    async fn take_invitation(
+        &self,
+        chat_id: u32,
+        user_id: u32,
+    ) -> Result<Option<Invitation>, AppError>;
+
+    // ids are store wide and become visible in order, once a message is stored every message
+    // with a smaller id is too
+    async fn create_message(&self, input: CreateMessage) -> Result<Message, AppError>;
+    // newest first, only messages older than `before` if it is set
+    async fn list_messages(
+        &self,
+        chat_id: u32,
+        before: Option<u64>,
+        limit: u32,
+    ) -> Result<Vec<Message>, AppError>;
+    // oldest first, the messages newer than `after`, used to replay missed events
+    async fn list_messages_after(
+        &self,
+        chat_id: u32,
+        after: u64,
+        limit: u32,
+    ) -> Result<Vec<Message>, AppError>;
+    async fn get_message(&self, chat_id: u32, message_id: u64)
+    -> Result<Option<Message>, AppError>;
+    // oldest first, the replies in the thread of `root_id` newer than `after` if it is set
+    async fn list_replies(
+        &self,
+        chat_id: u32,
+        root_id: u64,
+        after: Option<u64>,
+        limit: u32,
+    ) -> Result<Vec<Message>, AppError>;
+    // newest first, the messages mentioning the user in the chats the user is a member of,
+    // only messages older than `before` if it is set
+    async fn list_mentions(
+        &self,
+        user_id: u32,
+        before: Option<u64>,
+        limit: u32,
+    ) -> Result<Vec<Message>, AppError>;
+    // best first, the messages of the user's chats with a word starting with each word of the
+    // query. deleted messages are not found
+    async fn search_messages(
+        &self,
+        user_id: u32,
+        query: &SearchQuery,
+    ) -> Result<Vec<SearchHit>, AppError>;
+    // replace the content and mentions and keep the old content in the history. this and the
+    // changes below return None if the message does not exist or is deleted
+    async fn edit_message(
+        &self,
+        chat_id: u32,
+        message_id: u64,
+        content: String,
+        mentions: Vec<u32>,
+    ) -> Result<Option<Message>, AppError>;
+    // leave a tombstone, the content, files, mentions, reactions and history are dropped
+    async fn delete_message(
+        &self,
+        chat_id: u32,
+        message_id: u64,
+    ) -> Result<Option<Message>, AppError>;
+    // add the user's reaction, or take it back if the user already reacted with the emoji
+    async fn toggle_reaction(
+        &self,
+        chat_id: u32,
+        message_id: u64,
+        user_id: u32,
+        emoji: &str,
+    ) -> Result<Option<Message>, AppError>;
+    // the earlier contents of the message, oldest first
+    async fn message_history(
+        &self,
+        chat_id: u32,
+        message_id: u64,
+    ) -> Result<Vec<MessageVersion>, AppError>;
+
+    // index the blob if it is new and count the chat's reference to it, return false if the
+    // chat already has the file, it is not counted twice
+    async fn add_chat_file(&self, file: FileRecord) -> Result<bool, AppError>;
+    // add the files one user uploaded to one chat, all of them if the new ones fit in the
+    // quotas and none otherwise. the check and the insert are atomic, so concurrent uploads
+    // cannot get past the quotas together. files the chat already has take no more space
+    async fn add_chat_files(
+        &self,
+        files: Vec<FileRecord>,
+        quota: FileQuota,
+    ) -> Result<(), AppError>;
+    async fn get_chat_file(&self, file: &FileId) -> Result<Option<FileRecord>, AppError>;
+    // total size of the files the user uploaded
+    async fn user_file_usage(&self, user_id: u32) -> Result<u64, AppError>;
+    // total size of the files stored for the chat
+    async fn chat_file_usage(&self, chat_id: u32) -> Result<u64, AppError>;
+    // store what processing the blob found out, nothing happens if the blob is not indexed
+    async fn set_file_meta(&self, hash: &str, meta: FileMeta) -> Result<(), AppError>;
+    // None until the blob is processed
+    async fn get_file_meta(&self, hash: &str) -> Result<Option<FileMeta>, AppError>;
+    // how many chat files use the blob, None if it is not indexed
+    async fn blob_refcount(&self, hash: &str) -> Result<Option<u32>, AppError>;
+    // drop the blob from the index unless it is used, return false if it is still used
+    async fn delete_blob(&self, hash: &str) -> Result<bool, AppError>;
+
+    async fn is_chat_member(&self, chat_id: u32, user_id: u32) -> Result<bool, AppError> {
+        Ok(self
+            .get_chat(chat_id)
+            .await?
+            .map(|chat| chat.members.contains(&user_id))
+            .unwrap_or(false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::ChatType;
+
+    // every store the tests run against, postgres only if TEST_DATABASE_URL is set
+    async fn stores() -> Result<Vec<Arc<dyn ChatStore>>> {
+        let mut stores: Vec<Arc<dyn ChatStore>> = vec![Arc::new(MemoryStore::new())];
+        if let Ok(url) = std::env::var("TEST_DATABASE_URL") {
+            stores.push(Arc::new(PgStore::try_new(&url).await?));
+        }
+        Ok(stores)
+    }
+
+    // a new workspace with one user and `chats` chats of the user, so tests can share a database
+    async fn seed(store: &dyn ChatStore, chats: usize) -> Result<(u32, Vec<u32>)> {
+        let name = nanoid::nanoid!(12);
+        let ws = store.create_workspace(&name).await?.expect("new workspace");
+        let user = store
+            .create_user(CreateUser {
+                ws_id: ws.id,
+                name: name.clone(),
+                email: format!("{name}@example.com"),
+                gender: "female".into(),
+                teenager: false,
+                hash_password: String::new(),
+            })
+            .await?;
+        let mut chat_ids = vec![];
+        for i in 0..chats {
+            let chat = store
+                .create_chat(CreateChat {
+                    ws_id: ws.id,
+                    name: format!("chat {i}"),
+                    chat_type: ChatType::Group,
+                    owner_id: user.id,
+                    members: vec![user.id],
+                })
+                .await?;
+            chat_ids.push(chat.id);
+        }
+        Ok((user.id, chat_ids))
+    }
+
+    #[tokio::test]
+    async fn test_list_messages_before_any_id() -> Result<()> {
+        for store in stores().await? {
+            let (user_id, chats) = seed(store.as_ref(), 2).await?;
+            let mut ids = vec![];
+            for i in 0..6 {
+                let msg = store
+                    .create_message(CreateMessage {
+                        chat_id: chats[i % 2],
+                        sender_id: user_id,
+                        content: i.to_string(),
+                        files: vec![],
+                        reply_to: None,
+                        mentions: vec![],
+                    })
+                    .await?;
+                ids.push(msg.id);
+            }
+            let page = async |before: Option<u64>| -> Result<Vec<String>> {
+                let messages = store.list_messages(chats[0], before, 2).await?;
+                Ok(messages.into_iter().map(|msg| msg.content).collect())
+            };
+            assert_eq!(page(None).await?, vec!["4", "2"]);
+            assert_eq!(page(Some(ids[4])).await?, vec!["2", "0"]);
+            // the cursor may be a message of another chat, or no message at all
+            assert_eq!(page(Some(ids[3])).await?, vec!["2", "0"]);
+            assert_eq!(page(Some(ids[5] + 1000)).await?, vec!["4", "2"]);
+            assert!(page(Some(ids[0])).await?.is_empty());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_search_splits_words_alike() -> Result<()> {
+        for store in stores().await? {
+            let (user_id, chats) = seed(store.as_ref(), 1).await?;
+            store
+                .create_message(CreateMessage {
+                    chat_id: chats[0],
+                    sender_id: user_id,
+                    content: "mail bob@example.com or see example.org/docs-v2".into(),
+                    files: vec![],
+                    reply_to: None,
+                    mentions: vec![],
+                })
+                .await?;
+            // emails, hosts and paths are words like any others on every backend
+            for q in ["example", "bob com", "example docs v2"] {
+                let query = SearchQuery {
+                    q: q.into(),
+                    limit: 10,
+                    ..Default::default()
+                };
+                let hits = store.search_messages(user_id, &query).await?;
+                assert_eq!(hits.len(), 1, "{q}");
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_find_chat_members_by_name() -> Result<()> {
+        for store in stores().await? {
+            let (owner, chats) = seed(store.as_ref(), 1).await?;
+            let ws_id = store.get_user(owner).await?.expect("seeded").ws_id;
+            let mut users = vec![];
+            for name in ["Bob", "bob", "carol"] {
+                let user = store
+                    .create_user(CreateUser {
+                        ws_id,
+                        name: name.into(),
+                        email: format!("{}@example.com", nanoid::nanoid!(12)),
+                        gender: "female".into(),
+                        teenager: false,
+                        hash_password: String::new(),
+                    })
+                    .await?;
+                users.push(user.id);
+            }
+            // carol is in the workspace, not in the chat
+            store.add_chat_member(chats[0], users[0]).await?;
+            store.add_chat_member(chats[0], users[1]).await?;
+            let names = ["bob".to_string(), "carol".to_string()];
+            let found = store.find_chat_members_by_name(chats[0], &names).await?;
+            let ids = found.iter().map(|user| user.id).collect::<Vec<_>>();
+            assert_eq!(ids, users[..2]);
+            assert!(store.find_chat_members_by_name(0, &names).await?.is_empty());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deleted_owner_hands_over_chats() -> Result<()> {
+        for store in stores().await? {
+            let (owner, chats) = seed(store.as_ref(), 2).await?;
+            let ws_id = store.get_user(owner).await?.expect("seeded").ws_id;
+            let mut users = vec![];
+            for _ in 0..3 {
+                let name = nanoid::nanoid!(12);
+                let user = store
+                    .create_user(CreateUser {
+                        ws_id,
+                        name: name.clone(),
+                        email: format!("{name}@example.com"),
+                        gender: "female".into(),
+                        teenager: false,
+                        hash_password: String::new(),
+                    })
+                    .await?;
+                store.add_chat_member(chats[0], user.id).await?;
+                users.push(user.id);
+            }
+            store.set_chat_moderator(chats[0], users[2], true).await?;
+            store.set_chat_moderator(chats[0], users[1], true).await?;
+
+            assert!(store.delete_user(owner).await?);
+            // the moderator who joined first takes over, the chat nobody is left in is gone
+            let chat = store.get_chat(chats[0]).await?.expect("handed over");
+            assert_eq!(chat.owner_id, Some(users[1]));
+            assert_eq!(chat.moderators, vec![users[2]]);
+            assert!(store.get_chat(chats[1]).await?.is_none());
+
+            // without moderators the member who joined first takes over
+            store.delete_user(users[1]).await?;
+            store.set_chat_moderator(chats[0], users[2], false).await?;
+            store.delete_user(users[2]).await?;
+            let chat = store.get_chat(chats[0]).await?.expect("handed over");
+            assert_eq!(chat.owner_id, Some(users[0]));
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_signups_take_the_email_once() -> Result<()> {
+        for store in stores().await? {
+            let ws = store
+                .create_workspace(&nanoid::nanoid!(12))
+                .await?
+                .expect("new workspace");
+            let email = format!("{}@example.com", nanoid::nanoid!(12));
+            let signup = || {
+                let store = store.clone();
+                let input = CreateUser {
+                    ws_id: ws.id,
+                    name: "alice".into(),
+                    email: email.clone(),
+                    gender: "female".into(),
+                    teenager: false,
+                    hash_password: String::new(),
+                };
+                tokio::spawn(async move { store.create_user(input).await })
+            };
+            let (a, b) = (signup(), signup());
+            let results = [a.await?, b.await?];
+            assert_eq!(results.iter().filter(|res| res.is_ok()).count(), 1);
+            assert!(results.iter().any(|res| matches!(
+                res,
+                Err(AppError::CreateUserError(msg)) if msg == "email already exists"
+            )));
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_messages_are_stored_in_id_order() -> Result<()> {
+        for store in stores().await? {
+            let (user_id, chats) = seed(store.as_ref(), 2).await?;
+            let send = |i: usize| {
+                let store = store.clone();
+                let chats = chats.clone();
+                tokio::spawn(async move {
+                    let msg = store
+                        .create_message(CreateMessage {
+                            chat_id: chats[i % 2],
+                            sender_id: user_id,
+                            content: i.to_string(),
+                            files: vec![],
+                            reply_to: None,
+                            mentions: vec![],
+                        })
+                        .await?;
+                    // every smaller id must be visible once the message is
+                    let mut seen = 0;
+                    for chat_id in chats {
+                        seen += store.list_messages(chat_id, Some(msg.id), 100).await?.len();
+                    }
+                    anyhow::Ok((msg.id, seen))
+                })
+            };
+            let tasks = (0..40).map(send).collect::<Vec<_>>();
+            let mut sent = vec![];
+            for task in tasks {
+                sent.push(task.await??);
+            }
+            for (id, seen) in &sent {
+                let smaller = sent.iter().filter(|(other, _)| other < id).count();
+                assert_eq!(
+                    *seen, smaller,
+                    "message {id} was stored before a smaller id"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_uploads_share_the_quota() -> Result<()> {
+        for store in stores().await? {
+            let (user_id, chats) = seed(store.as_ref(), 1).await?;
+            let quota = FileQuota {
+                user: 150,
+                chat: 1000,
+            };
+            let upload = |content: char| {
+                let store = store.clone();
+                let record = FileRecord {
+                    file: FileId {
+                        chat_id: chats[0],
+                        hash: format!("{}{}", nanoid::nanoid!(8), content.to_string().repeat(56)),
+                        ext: "txt".into(),
+                    },
+                    size: 100,
+                    mime: "text/plain".into(),
+                    uploader_id: user_id,
+                    created_at: Utc::now(),
+                };
+                tokio::spawn(async move { store.add_chat_files(vec![record], quota).await })
+            };
+            let (a, b) = (upload('a'), upload('b'));
+            let added = [a.await?, b.await?]
+                .iter()
+                .filter(|res| res.is_ok())
+                .count();
+            assert_eq!(added, 1);
+            assert_eq!(store.user_file_usage(user_id).await?, 100);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,1429 @@
+use std::collections::HashSet;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::{FromRow, PgConnection, PgPool, types::Json};
+
+use crate::{
+    AppError, Chat, ChatFile, ChatStore, ChatType, CreateChat, CreateMessage, CreateUser, FileId,
+    FileMeta, FileQuota, FileRecord, Invitation, Message, MessageVersion, Reaction, RefreshToken,
+    SearchHit, SearchQuery, User, UserChat, UserType, Workspace, highlight, tokenize,
+};
+
+// the classes of the advisory locks that serialize uploads per uploader and per chat
+const UPLOADER_LOCK: i32 = 1;
+const CHAT_UPLOAD_LOCK: i32 = 2;
+// serializes new messages, so message ids commit in the order they are taken
+const MESSAGE_LOCK: i32 = 3;
+
+// keeps everything in the tables defined in migrations/
+#[derive(Clone)]
+pub struct PgStore {
+    pool: PgPool,
+}
+
+#[derive(FromRow)]
+struct UserRow {
+    id: i64,
+    ws_id: i64,
+    name: String,
+    email: String,
+    password: String,
+    gender: Option<String>,
+    teenager: bool,
+    r#type: UserType,
+}
+
+#[derive(FromRow)]
+struct WorkspaceRow {
+    id: i64,
+    name: String,
+    owner_id: Option<i64>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(FromRow)]
+struct RefreshTokenRow {
+    user_id: i64,
+    expires_at: DateTime<Utc>,
+}
+
+#[derive(FromRow)]
+struct ChatRow {
+    chat_id: i64,
+    ws_id: i64,
+    chat_name: String,
+    r#type: ChatType,
+    owner_id: Option<i64>,
+    moderators: Vec<i64>,
+    members: Option<Vec<i64>>,
+}
+
+#[derive(FromRow)]
+struct SearchRow {
+    #[sqlx(flatten)]
+    message: MessageRow,
+    rank: f32,
+}
+
+#[derive(FromRow)]
+struct UserChatRow {
+    #[sqlx(flatten)]
+    chat: ChatRow,
+    last_read: i64,
+    unread: i64,
+}
+
+#[derive(FromRow)]
+struct InvitationRow {
+    chat_id: i64,
+    chat_name: String,
+    r#type: ChatType,
+    user_id: i64,
+    inviter_id: i64,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(FromRow)]
+struct FileRow {
+    chat_id: i64,
+    hash: String,
+    ext: String,
+    size: i64,
+    mime: String,
+    uploader_id: i64,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(FromRow)]
+struct FileMetaRow {
+    width: Option<i32>,
+    height: Option<i32>,
+    thumb_mime: Option<String>,
+}
+
+#[derive(FromRow)]
+struct MessageRow {
+    message_id: i64,
+    chat_id: i64,
+    sender_id: i64,
+    content: String,
+    files: Json<Vec<ChatFile>>,
+    created_at: Option<DateTime<Utc>>,
+    edited_at: Option<DateTime<Utc>>,
+    deleted_at: Option<DateTime<Utc>>,
+    reactions: Json<Vec<Reaction>>,
+    reply_to: Option<i64>,
+    mentions: Vec<i64>,
+}
+
+// the columns of MessageRow, reactions are grouped by emoji in the order the emojis were first used
+const MESSAGE_COLUMNS: &str = r#"
+    message_id, chat_id, sender_id, content, files, created_at, edited_at, deleted_at,
+    reply_to, mentions,
+    COALESCE((
+        SELECT jsonb_agg(
+            jsonb_build_object('emoji', r.emoji, 'count', r.count, 'user_ids', r.user_ids)
+            ORDER BY r.first_at, r.emoji)
+        FROM (
+            SELECT emoji, count(*) AS count, min(created_at) AS first_at,
+                array_agg(user_id ORDER BY created_at, user_id) AS user_ids
+            FROM message_reactions
+            WHERE message_id = messages.message_id
+            GROUP BY emoji
+        ) r
+    ), '[]'::jsonb) AS reactions
+"#;
+
+impl PgStore {
+    pub async fn try_new(url: &str) -> Result<Self, AppError> {
+        let pool = PgPool::connect(url).await?;
+        sqlx::migrate!("./migrations").run(&pool).await?;
+        Ok(Self { pool })
+    }
+
+    pub fn from_pool(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    // drop the chat with its messages and files, releasing their blobs
+    async fn delete_chat_in(conn: &mut PgConnection, chat_id: u32) -> Result<bool, AppError> {
+        sqlx::query(
+            r#"
+            UPDATE blobs SET refcount = blobs.refcount - f.n
+            FROM (SELECT hash, count(*)::integer AS n FROM chat_files WHERE chat_id = $1 GROUP BY hash) f
+            WHERE blobs.hash = f.hash
+            "#,
+        )
+        .bind(chat_id as i64)
+        .execute(&mut *conn)
+        .await?;
+        sqlx::query("DELETE FROM chat_files WHERE chat_id = $1")
+            .bind(chat_id as i64)
+            .execute(&mut *conn)
+            .await?;
+        sqlx::query("DELETE FROM messages WHERE chat_id = $1")
+            .bind(chat_id as i64)
+            .execute(&mut *conn)
+            .await?;
+        let deleted = sqlx::query("DELETE FROM chats WHERE chat_id = $1")
+            .bind(chat_id as i64)
+            .execute(&mut *conn)
+            .await?
+            .rows_affected();
+        Ok(deleted > 0)
+    }
+
+    // index the blob if it is new and count the chat's reference to it unless the chat has the
+    // file already
+    async fn insert_chat_file(
+        conn: &mut PgConnection,
+        file: &FileRecord,
+    ) -> Result<bool, AppError> {
+        sqlx::query(
+            r#"
+            INSERT INTO blobs (hash, size, mime) VALUES ($1, $2, $3)
+            ON CONFLICT (hash) DO NOTHING
+            "#,
+        )
+        .bind(&file.file.hash)
+        .bind(file.size as i64)
+        .bind(&file.mime)
+        .execute(&mut *conn)
+        .await?;
+        let added = sqlx::query(
+            r#"
+            INSERT INTO chat_files (chat_id, hash, ext, size, mime, uploader_id, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (chat_id, hash, ext) DO NOTHING
+            "#,
+        )
+        .bind(file.file.chat_id as i64)
+        .bind(&file.file.hash)
+        .bind(&file.file.ext)
+        .bind(file.size as i64)
+        .bind(&file.mime)
+        .bind(file.uploader_id as i64)
+        .bind(file.created_at)
+        .execute(&mut *conn)
+        .await?
+        .rows_affected()
+            > 0;
+        if added {
+            sqlx::query("UPDATE blobs SET refcount = refcount + 1 WHERE hash = $1")
+                .bind(&file.file.hash)
+                .execute(&mut *conn)
+                .await?;
+        }
+        Ok(added)
+    }
+}
+
+#[async_trait]
+impl ChatStore for PgStore {
+    async fn find_workspace_by_name(&self, name: &str) -> Result<Option<Workspace>, AppError> {
+        let row: Option<WorkspaceRow> = sqlx::query_as(
+            r#"
+            SELECT id, name, owner_id, created_at FROM workspaces WHERE name = $1
+            "#,
+        )
+        .bind(name)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(Workspace::from))
+    }
+
+    async fn create_workspace(&self, name: &str) -> Result<Option<Workspace>, AppError> {
+        let row: Option<WorkspaceRow> = sqlx::query_as(
+            r#"
+            INSERT INTO workspaces (name) VALUES ($1)
+            ON CONFLICT (name) DO NOTHING
+            RETURNING id, name, owner_id, created_at
+            "#,
+        )
+        .bind(name)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(Workspace::from))
+    }
+
+    async fn get_workspace(&self, ws_id: u32) -> Result<Option<Workspace>, AppError> {
+        let row: Option<WorkspaceRow> = sqlx::query_as(
+            r#"
+            SELECT id, name, owner_id, created_at FROM workspaces WHERE id = $1
+            "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(Workspace::from))
+    }
+
+    async fn set_workspace_owner(&self, ws_id: u32, owner_id: u32) -> Result<(), AppError> {
+        sqlx::query("UPDATE workspaces SET owner_id = $2 WHERE id = $1")
+            .bind(ws_id as i64)
+            .bind(owner_id as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_empty_workspace(&self, ws_id: u32) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            DELETE FROM workspaces
+            WHERE id = $1 AND NOT EXISTS (SELECT 1 FROM users WHERE ws_id = $1)
+            "#,
+        )
+        .bind(ws_id as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn create_user(&self, input: CreateUser) -> Result<User, AppError> {
+        let row: UserRow = sqlx::query_as(
+            r#"
+            INSERT INTO users (ws_id, name, email, password, gender, teenager, type)
+            VALUES ($1, $2, $3, $4, $5, $6, 'regular')
+            RETURNING id, ws_id, name, email, password, gender, teenager, type
+            "#,
+        )
+        .bind(input.ws_id as i64)
+        .bind(input.name)
+        .bind(input.email)
+        .bind(input.hash_password)
+        .bind(input.gender)
+        .bind(input.teenager)
+        .fetch_one(&self.pool)
+        .await
+        // the unique email_index decides between concurrent signups
+        .map_err(|e| match e.as_database_error() {
+            Some(db) if db.is_unique_violation() => {
+                AppError::CreateUserError("email already exists".into())
+            }
+            _ => e.into(),
+        })?;
+        Ok(row.into())
+    }
+
+    async fn find_user_by_email(&self, email: &str) -> Result<Option<User>, AppError> {
+        let row: Option<UserRow> = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, name, email, password, gender, teenager, type FROM users WHERE email = $1
+            "#,
+        )
+        .bind(email)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(User::from))
+    }
+
+    async fn get_user(&self, user_id: u32) -> Result<Option<User>, AppError> {
+        let row: Option<UserRow> = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, name, email, password, gender, teenager, type FROM users WHERE id = $1
+            "#,
+        )
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(User::from))
+    }
+
+    async fn set_user_type(
+        &self,
+        email: &str,
+        user_type: UserType,
+    ) -> Result<Option<User>, AppError> {
+        let row: Option<UserRow> = sqlx::query_as(
+            r#"
+            UPDATE users SET type = $2 WHERE email = $1
+            RETURNING id, ws_id, name, email, password, gender, teenager, type
+            "#,
+        )
+        .bind(email)
+        .bind(user_type)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(User::from))
+    }
+
+    async fn delete_user(&self, user_id: u32) -> Result<bool, AppError> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM messages WHERE sender_id = $1")
+            .bind(user_id as i64)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            "UPDATE messages SET mentions = array_remove(mentions, $1) WHERE mentions @> ARRAY[$1]",
+        )
+        .bind(user_id as i64)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+            UPDATE chats
+            SET members = array_remove(members, $1), moderators = array_remove(moderators, $1)
+            WHERE $1 = ANY(members)
+            "#,
+        )
+        .bind(user_id as i64)
+        .execute(&mut *tx)
+        .await?;
+        // the longest standing moderator takes over the user's chats, the longest standing
+        // member if there is no moderator
+        sqlx::query(
+            r#"
+            WITH heirs AS (
+                SELECT chat_id, COALESCE(
+                    (SELECT m FROM unnest(members) WITH ORDINALITY AS t(m, i)
+                     WHERE m = ANY(moderators) ORDER BY i LIMIT 1),
+                    members[1]
+                ) AS heir
+                FROM chats WHERE owner_id = $1
+            )
+            UPDATE chats SET owner_id = heirs.heir, moderators = array_remove(moderators, heirs.heir)
+            FROM heirs
+            WHERE chats.chat_id = heirs.chat_id AND heirs.heir IS NOT NULL
+            "#,
+        )
+        .bind(user_id as i64)
+        .execute(&mut *tx)
+        .await?;
+        // the chats nobody is left in go with the user
+        let orphans: Vec<i64> = sqlx::query_scalar("SELECT chat_id FROM chats WHERE owner_id = $1")
+            .bind(user_id as i64)
+            .fetch_all(&mut *tx)
+            .await?;
+        for chat_id in orphans {
+            Self::delete_chat_in(&mut tx, chat_id as u32).await?;
+        }
+        // the longest standing member takes over the workspace, nobody if there is none
+        sqlx::query(
+            r#"
+            UPDATE workspaces
+            SET owner_id = (SELECT id FROM users WHERE ws_id = workspaces.id AND id <> $1 ORDER BY id LIMIT 1)
+            WHERE owner_id = $1
+            "#,
+        )
+        .bind(user_id as i64)
+        .execute(&mut *tx)
+        .await?;
+        // refresh tokens go with the user, ON DELETE CASCADE
+        let deleted = sqlx::query("DELETE FROM users WHERE id = $1")
+            .bind(user_id as i64)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        tx.commit().await?;
+        Ok(deleted > 0)
+    }
+
+    async fn create_refresh_token(
+        &self,
+        token_hash: &str,
+        user_id: u32,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM refresh_tokens WHERE expires_at < now()")
+            .execute(&self.pool)
+            .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO refresh_tokens (token_hash, user_id, expires_at) VALUES ($1, $2, $3)
+            "#,
+        )
+        .bind(token_hash)
+        .bind(user_id as i64)
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn take_refresh_token(&self, token_hash: &str) -> Result<Option<RefreshToken>, AppError> {
+        let row: Option<RefreshTokenRow> = sqlx::query_as(
+            r#"
+            DELETE FROM refresh_tokens WHERE token_hash = $1 RETURNING user_id, expires_at
+            "#,
+        )
+        .bind(token_hash)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(|row| RefreshToken {
+            user_id: row.user_id as u32,
+            expires_at: row.expires_at,
+        }))
+    }
+
+    async fn delete_refresh_token(&self, token_hash: &str, user_id: u32) -> Result<bool, AppError> {
+        let res = sqlx::query("DELETE FROM refresh_tokens WHERE token_hash = $1 AND user_id = $2")
+            .bind(token_hash)
+            .bind(user_id as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    async fn revoke_token(&self, jti: &str, expires_at: DateTime<Utc>) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM revoked_tokens WHERE expires_at < now()")
+            .execute(&self.pool)
+            .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO revoked_tokens (jti, expires_at) VALUES ($1, $2)
+            ON CONFLICT (jti) DO NOTHING
+            "#,
+        )
+        .bind(jti)
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn is_token_revoked(&self, jti: &str) -> Result<bool, AppError> {
+        let revoked: bool =
+            sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM revoked_tokens WHERE jti = $1)")
+                .bind(jti)
+                .fetch_one(&self.pool)
+                .await?;
+        Ok(revoked)
+    }
+
+    async fn list_users(&self, ws_id: u32) -> Result<Vec<User>, AppError> {
+        let rows: Vec<UserRow> = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, name, email, password, gender, teenager, type FROM users
+            WHERE ws_id = $1
+            ORDER BY id
+            "#,
+        )
+        .bind(ws_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(User::from).collect())
+    }
+
+    async fn find_chat_members_by_name(
+        &self,
+        chat_id: u32,
+        names: &[String],
+    ) -> Result<Vec<User>, AppError> {
+        let rows: Vec<UserRow> = sqlx::query_as(
+            r#"
+            SELECT u.id, u.ws_id, u.name, u.email, u.password, u.gender, u.teenager, u.type
+            FROM chats c JOIN users u ON u.id = ANY(c.members)
+            WHERE c.chat_id = $1 AND lower(u.name) = ANY($2)
+            ORDER BY u.id
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(names)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(User::from).collect())
+    }
+
+    async fn create_chat(&self, input: CreateChat) -> Result<Chat, AppError> {
+        let members = input
+            .members
+            .iter()
+            .map(|id| *id as i64)
+            .collect::<Vec<_>>();
+        let row: ChatRow = sqlx::query_as(
+            r#"
+            INSERT INTO chats (ws_id, chat_name, type, owner_id, members)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING chat_id, ws_id, chat_name, type, owner_id, moderators, members
+            "#,
+        )
+        .bind(input.ws_id as i64)
+        .bind(input.name)
+        .bind(input.chat_type)
+        .bind(input.owner_id as i64)
+        .bind(members)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(row.into())
+    }
+
+    async fn get_chat(&self, chat_id: u32) -> Result<Option<Chat>, AppError> {
+        let row: Option<ChatRow> = sqlx::query_as(
+            r#"
+            SELECT chat_id, ws_id, chat_name, type, owner_id, moderators, members FROM chats WHERE chat_id = $1
+            "#,
+        )
+        .bind(chat_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(Chat::from))
+    }
+
+    async fn list_user_chats(&self, user_id: u32) -> Result<Vec<Chat>, AppError> {
+        let rows: Vec<ChatRow> = sqlx::query_as(
+            r#"
+            SELECT chat_id, ws_id, chat_name, type, owner_id, moderators, members FROM chats WHERE $1 = ANY(members) ORDER BY chat_id
+            "#,
+        )
+        .bind(user_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(Chat::from).collect())
+    }
+
+    async fn list_user_chats_unread(&self, user_id: u32) -> Result<Vec<UserChat>, AppError> {
+        let rows: Vec<UserChatRow> = sqlx::query_as(
+            r#"
+            SELECT c.chat_id, c.ws_id, c.chat_name, c.type, c.owner_id, c.moderators, c.members,
+                COALESCE(r.message_id, 0) AS last_read,
+                (SELECT count(*) FROM messages m
+                 WHERE m.chat_id = c.chat_id AND m.message_id > COALESCE(r.message_id, 0)
+                   AND m.sender_id <> $1 AND m.deleted_at IS NULL) AS unread
+            FROM chats c
+            LEFT JOIN chat_reads r ON r.chat_id = c.chat_id AND r.user_id = $1
+            WHERE $1 = ANY(c.members)
+            ORDER BY c.chat_id
+            "#,
+        )
+        .bind(user_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| UserChat {
+                chat: row.chat.into(),
+                last_read: row.last_read as u64,
+                unread: row.unread as u32,
+            })
+            .collect())
+    }
+
+    async fn mark_read(
+        &self,
+        chat_id: u32,
+        user_id: u32,
+        message_id: u64,
+    ) -> Result<u64, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let earlier: Option<i64> = sqlx::query_scalar(
+            "SELECT message_id FROM chat_reads WHERE chat_id = $1 AND user_id = $2 FOR UPDATE",
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .fetch_optional(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO chat_reads (chat_id, user_id, message_id) VALUES ($1, $2, $3)
+            ON CONFLICT (chat_id, user_id) DO UPDATE
+            SET message_id = GREATEST(chat_reads.message_id, EXCLUDED.message_id),
+                updated_at = now()
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .bind(message_id as i64)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(earlier.unwrap_or(0) as u64)
+    }
+
+    async fn search_public_chats(
+        &self,
+        ws_id: u32,
+        query: Option<&str>,
+        limit: u32,
+    ) -> Result<Vec<Chat>, AppError> {
+        // the query is matched literally, not as a LIKE pattern
+        let pattern = query.map(|q| {
+            let q = q
+                .to_lowercase()
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            format!("%{q}%")
+        });
+        let rows: Vec<ChatRow> = sqlx::query_as(
+            r#"
+            SELECT chat_id, ws_id, chat_name, type, owner_id, moderators, members FROM chats
+            WHERE ws_id = $1 AND type = 'public_channel'
+              AND ($2::text IS NULL OR lower(chat_name) LIKE $2)
+            ORDER BY chat_id
+            LIMIT $3
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(pattern)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(Chat::from).collect())
+    }
+
+    async fn delete_chat(&self, chat_id: u32) -> Result<bool, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let deleted = Self::delete_chat_in(&mut tx, chat_id).await?;
+        tx.commit().await?;
+        Ok(deleted)
+    }
+
+    async fn add_chat_member(&self, chat_id: u32, user_id: u32) -> Result<Option<Chat>, AppError> {
+        let row: Option<ChatRow> = sqlx::query_as(
+            r#"
+            UPDATE chats
+            SET members = CASE
+                WHEN $2 = ANY(COALESCE(members, '{}')) THEN members
+                ELSE array_append(COALESCE(members, '{}'), $2)
+            END
+            WHERE chat_id = $1
+            RETURNING chat_id, ws_id, chat_name, type, owner_id, moderators, members
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(Chat::from))
+    }
+
+    async fn remove_chat_member(
+        &self,
+        chat_id: u32,
+        user_id: u32,
+    ) -> Result<Option<Chat>, AppError> {
+        let row: Option<ChatRow> = sqlx::query_as(
+            r#"
+            UPDATE chats
+            SET members = array_remove(members, $2), moderators = array_remove(moderators, $2)
+            WHERE chat_id = $1
+            RETURNING chat_id, ws_id, chat_name, type, owner_id, moderators, members
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(Chat::from))
+    }
+
+    async fn set_chat_moderator(
+        &self,
+        chat_id: u32,
+        user_id: u32,
+        moderator: bool,
+    ) -> Result<Option<Chat>, AppError> {
+        let row: Option<ChatRow> = sqlx::query_as(
+            r#"
+            UPDATE chats
+            SET moderators = CASE
+                WHEN $3 THEN array_append(array_remove(moderators, $2), $2)
+                ELSE array_remove(moderators, $2)
+            END
+            WHERE chat_id = $1
+            RETURNING chat_id, ws_id, chat_name, type, owner_id, moderators, members
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .bind(moderator)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(Chat::from))
+    }
+
+    async fn set_chat_owner(&self, chat_id: u32, owner_id: u32) -> Result<Option<Chat>, AppError> {
+        let row: Option<ChatRow> = sqlx::query_as(
+            r#"
+            UPDATE chats SET owner_id = $2, moderators = array_remove(moderators, $2)
+            WHERE chat_id = $1
+            RETURNING chat_id, ws_id, chat_name, type, owner_id, moderators, members
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(owner_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(Chat::from))
+    }
+
+    async fn create_invitation(
+        &self,
+        chat_id: u32,
+        user_id: u32,
+        inviter_id: u32,
+    ) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            INSERT INTO chat_invitations (chat_id, user_id, inviter_id) VALUES ($1, $2, $3)
+            ON CONFLICT (chat_id, user_id)
+            DO UPDATE SET inviter_id = EXCLUDED.inviter_id, created_at = now()
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .bind(inviter_id as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn list_invitations(&self, user_id: u32) -> Result<Vec<Invitation>, AppError> {
+        let rows: Vec<InvitationRow> = sqlx::query_as(
+            r#"
+            SELECT i.chat_id, c.chat_name, c.type, i.user_id, i.inviter_id, i.created_at
+            FROM chat_invitations i JOIN chats c ON c.chat_id = i.chat_id
+            WHERE i.user_id = $1
+            ORDER BY i.created_at
+            "#,
+        )
+        .bind(user_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(Invitation::from).collect())
+    }
+
+    async fn take_invitation(
+        &self,
+        chat_id: u32,
+        user_id: u32,
+    ) -> Result<Option<Invitation>, AppError> {
+        let row: Option<InvitationRow> = sqlx::query_as(
+            r#"
+            DELETE FROM chat_invitations i USING chats c
+            WHERE i.chat_id = $1 AND i.user_id = $2 AND c.chat_id = i.chat_id
+            RETURNING i.chat_id, c.chat_name, c.type, i.user_id, i.inviter_id, i.created_at
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(Invitation::from))
+    }
+
+    async fn create_message(&self, input: CreateMessage) -> Result<Message, AppError> {
+        // without the lock a later id can commit first, and whoever already read past it
+        // (e.g. a client resuming events after it) never sees the earlier one
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("SELECT pg_advisory_xact_lock($1, 0)")
+            .bind(MESSAGE_LOCK)
+            .execute(&mut *tx)
+            .await?;
+        let sql = format!(
+            r#"
+            INSERT INTO messages (chat_id, sender_id, content, files, reply_to, mentions)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING {MESSAGE_COLUMNS}
+            "#
+        );
+        let row: MessageRow = sqlx::query_as(&sql)
+            .bind(input.chat_id as i64)
+            .bind(input.sender_id as i64)
+            .bind(input.content)
+            .bind(Json(input.files))
+            .bind(input.reply_to.map(|id| id as i64))
+            .bind(
+                input
+                    .mentions
+                    .iter()
+                    .map(|id| *id as i64)
+                    .collect::<Vec<_>>(),
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(row.into())
+    }
+
+    async fn list_messages(
+        &self,
+        chat_id: u32,
+        before: Option<u64>,
+        limit: u32,
+    ) -> Result<Vec<Message>, AppError> {
+        // keyset pagination on message_id, served by chat_id_message_id_index. this replaces the
+        // (chat_id, created_at DESC) ordering on purpose: ids grow with every insert like the
+        // memory store's, so the order is the same, and unlike created_at they are unique and
+        // are what the `before` cursor holds. `before` does not have to be a message of the chat
+        let sql = format!(
+            r#"
+            SELECT {MESSAGE_COLUMNS}
+            FROM messages
+            WHERE chat_id = $1 AND ($2::bigint IS NULL OR message_id < $2)
+            ORDER BY message_id DESC
+            LIMIT $3
+            "#
+        );
+        let rows: Vec<MessageRow> = sqlx::query_as(&sql)
+            .bind(chat_id as i64)
+            .bind(before.map(|id| id as i64))
+            .bind(limit as i64)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter().map(Message::from).collect())
+    }
+
+    async fn list_messages_after(
+        &self,
+        chat_id: u32,
+        after: u64,
+        limit: u32,
+    ) -> Result<Vec<Message>, AppError> {
+        let sql = format!(
+            r#"
+            SELECT {MESSAGE_COLUMNS}
+            FROM messages
+            WHERE chat_id = $1 AND message_id > $2
+            ORDER BY message_id
+            LIMIT $3
+            "#
+        );
+        let rows: Vec<MessageRow> = sqlx::query_as(&sql)
+            .bind(chat_id as i64)
+            .bind(after as i64)
+            .bind(limit as i64)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter().map(Message::from).collect())
+    }
+
+    async fn get_message(
+        &self,
+        chat_id: u32,
+        message_id: u64,
+    ) -> Result<Option<Message>, AppError> {
+        let sql = format!(
+            "SELECT {MESSAGE_COLUMNS} FROM messages WHERE chat_id = $1 AND message_id = $2"
+        );
+        let row: Option<MessageRow> = sqlx::query_as(&sql)
+            .bind(chat_id as i64)
+            .bind(message_id as i64)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(Message::from))
+    }
+
+    async fn list_replies(
+        &self,
+        chat_id: u32,
+        root_id: u64,
+        after: Option<u64>,
+        limit: u32,
+    ) -> Result<Vec<Message>, AppError> {
+        let sql = format!(
+            r#"
+            SELECT {MESSAGE_COLUMNS}
+            FROM messages
+            WHERE chat_id = $1 AND reply_to = $2 AND ($3::bigint IS NULL OR message_id > $3)
+            ORDER BY message_id
+            LIMIT $4
+            "#
+        );
+        let rows: Vec<MessageRow> = sqlx::query_as(&sql)
+            .bind(chat_id as i64)
+            .bind(root_id as i64)
+            .bind(after.map(|id| id as i64))
+            .bind(limit as i64)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter().map(Message::from).collect())
+    }
+
+    async fn list_mentions(
+        &self,
+        user_id: u32,
+        before: Option<u64>,
+        limit: u32,
+    ) -> Result<Vec<Message>, AppError> {
+        // served by messages_mentions_index
+        let sql = format!(
+            r#"
+            SELECT {MESSAGE_COLUMNS}
+            FROM messages
+            WHERE mentions @> ARRAY[$1::bigint]
+              AND chat_id IN (SELECT chat_id FROM chats WHERE $1 = ANY(members))
+              AND ($2::bigint IS NULL OR message_id < $2)
+            ORDER BY message_id DESC
+            LIMIT $3
+            "#
+        );
+        let rows: Vec<MessageRow> = sqlx::query_as(&sql)
+            .bind(user_id as i64)
+            .bind(before.map(|id| id as i64))
+            .bind(limit as i64)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter().map(Message::from).collect())
+    }
+
+    async fn search_messages(
+        &self,
+        user_id: u32,
+        query: &SearchQuery,
+    ) -> Result<Vec<SearchHit>, AppError> {
+        let terms = tokenize(&query.q).collect::<Vec<_>>();
+        if terms.is_empty() {
+            return Ok(vec![]);
+        }
+        // the terms are letters and digits only, safe to put into a tsquery as prefixes
+        let tsquery = terms
+            .iter()
+            .map(|term| format!("{term}:*"))
+            .collect::<Vec<_>>()
+            .join(" & ");
+        // served by messages_search_index
+        let sql = format!(
+            r#"
+            SELECT {MESSAGE_COLUMNS}, ts_rank(search_vector, query, 1) AS rank
+            FROM messages, to_tsquery('simple', $2) query
+            WHERE search_vector @@ query
+              AND chat_id IN (SELECT chat_id FROM chats WHERE $1 = ANY(members))
+              AND deleted_at IS NULL
+              AND ($3::bigint IS NULL OR chat_id = $3)
+              AND ($4::bigint IS NULL OR sender_id = $4)
+              AND ($5::timestamptz IS NULL OR created_at >= $5)
+              AND ($6::timestamptz IS NULL OR created_at < $6)
+            ORDER BY rank DESC, message_id DESC
+            LIMIT $7 OFFSET $8
+            "#
+        );
+        let rows: Vec<SearchRow> = sqlx::query_as(&sql)
+            .bind(user_id as i64)
+            .bind(tsquery)
+            .bind(query.chat_id.map(|id| id as i64))
+            .bind(query.sender_id.map(|id| id as i64))
+            .bind(query.from)
+            .bind(query.to)
+            .bind(query.limit as i64)
+            .bind(query.offset as i64)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                let message = Message::from(row.message);
+                SearchHit {
+                    highlight: highlight(&message.content, &terms),
+                    message,
+                    rank: row.rank,
+                }
+            })
+            .collect())
+    }
+
+    async fn edit_message(
+        &self,
+        chat_id: u32,
+        message_id: u64,
+        content: String,
+        mentions: Vec<u32>,
+    ) -> Result<Option<Message>, AppError> {
+        let mut tx = self.pool.begin().await?;
+        // locked, so two edits at once both end up in the history
+        let earlier: Option<(String, DateTime<Utc>)> = sqlx::query_as(
+            r#"
+            SELECT content, COALESCE(edited_at, created_at, now())
+            FROM messages
+            WHERE chat_id = $1 AND message_id = $2 AND deleted_at IS NULL
+            FOR UPDATE
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(message_id as i64)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some((earlier, written_at)) = earlier else {
+            return Ok(None);
+        };
+        sqlx::query(
+            "INSERT INTO message_edits (message_id, content, created_at) VALUES ($1, $2, $3)",
+        )
+        .bind(message_id as i64)
+        .bind(earlier)
+        .bind(written_at)
+        .execute(&mut *tx)
+        .await?;
+        let sql = format!(
+            r#"
+            UPDATE messages SET content = $2, mentions = $3, edited_at = now()
+            WHERE message_id = $1
+            RETURNING {MESSAGE_COLUMNS}
+            "#
+        );
+        let row: MessageRow = sqlx::query_as(&sql)
+            .bind(message_id as i64)
+            .bind(content)
+            .bind(mentions.iter().map(|id| *id as i64).collect::<Vec<_>>())
+            .fetch_one(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(Some(row.into()))
+    }
+
+    async fn delete_message(
+        &self,
+        chat_id: u32,
+        message_id: u64,
+    ) -> Result<Option<Message>, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let deleted = sqlx::query(
+            r#"
+            UPDATE messages
+            SET content = '', files = '[]'::jsonb, mentions = '{}', deleted_at = now()
+            WHERE chat_id = $1 AND message_id = $2 AND deleted_at IS NULL
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(message_id as i64)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if deleted == 0 {
+            return Ok(None);
+        }
+        for sql in [
+            "DELETE FROM message_edits WHERE message_id = $1",
+            "DELETE FROM message_reactions WHERE message_id = $1",
+        ] {
+            sqlx::query(sql)
+                .bind(message_id as i64)
+                .execute(&mut *tx)
+                .await?;
+        }
+        let sql = format!("SELECT {MESSAGE_COLUMNS} FROM messages WHERE message_id = $1");
+        let row: MessageRow = sqlx::query_as(&sql)
+            .bind(message_id as i64)
+            .fetch_one(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(Some(row.into()))
+    }
+
+    async fn toggle_reaction(
+        &self,
+        chat_id: u32,
+        message_id: u64,
+        user_id: u32,
+        emoji: &str,
+    ) -> Result<Option<Message>, AppError> {
+        let mut tx = self.pool.begin().await?;
+        // a share lock keeps the message from being deleted meanwhile
+        let found = sqlx::query(
+            r#"
+            SELECT 1 FROM messages
+            WHERE chat_id = $1 AND message_id = $2 AND deleted_at IS NULL
+            FOR SHARE
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(message_id as i64)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if found.is_none() {
+            return Ok(None);
+        }
+        let removed = sqlx::query(
+            "DELETE FROM message_reactions WHERE message_id = $1 AND user_id = $2 AND emoji = $3",
+        )
+        .bind(message_id as i64)
+        .bind(user_id as i64)
+        .bind(emoji)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if removed == 0 {
+            sqlx::query(
+                r#"
+                INSERT INTO message_reactions (message_id, user_id, emoji) VALUES ($1, $2, $3)
+                ON CONFLICT DO NOTHING
+                "#,
+            )
+            .bind(message_id as i64)
+            .bind(user_id as i64)
+            .bind(emoji)
+            .execute(&mut *tx)
+            .await?;
+        }
+        let sql = format!("SELECT {MESSAGE_COLUMNS} FROM messages WHERE message_id = $1");
+        let row: MessageRow = sqlx::query_as(&sql)
+            .bind(message_id as i64)
+            .fetch_one(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(Some(row.into()))
+    }
+
+    async fn message_history(
+        &self,
+        chat_id: u32,
+        message_id: u64,
+    ) -> Result<Vec<MessageVersion>, AppError> {
+        let rows: Vec<(String, DateTime<Utc>)> = sqlx::query_as(
+            r#"
+            SELECT e.content, e.created_at
+            FROM message_edits e JOIN messages m ON m.message_id = e.message_id
+            WHERE m.chat_id = $1 AND e.message_id = $2
+            ORDER BY e.id
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(message_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(content, date)| MessageVersion { content, date })
+            .collect())
+    }
+
+    async fn add_chat_file(&self, file: FileRecord) -> Result<bool, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let added = Self::insert_chat_file(&mut tx, &file).await?;
+        tx.commit().await?;
+        Ok(added)
+    }
+
+    async fn add_chat_files(
+        &self,
+        files: Vec<FileRecord>,
+        quota: FileQuota,
+    ) -> Result<(), AppError> {
+        let Some(first) = files.first() else {
+            return Ok(());
+        };
+        let (chat_id, uploader_id) = (first.file.chat_id, first.uploader_id);
+        let mut tx = self.pool.begin().await?;
+        // always the uploader first, the locks go with the transaction
+        for (class, id) in [(UPLOADER_LOCK, uploader_id), (CHAT_UPLOAD_LOCK, chat_id)] {
+            sqlx::query("SELECT pg_advisory_xact_lock($1, $2)")
+                .bind(class)
+                .bind(id as i32)
+                .execute(&mut *tx)
+                .await?;
+        }
+        let (user_usage, chat_usage): (i64, i64) = sqlx::query_as(
+            r#"
+            SELECT COALESCE(SUM(size) FILTER (WHERE uploader_id = $1), 0)::bigint,
+                   COALESCE(SUM(size) FILTER (WHERE chat_id = $2), 0)::bigint
+            FROM chat_files WHERE uploader_id = $1 OR chat_id = $2
+            "#,
+        )
+        .bind(uploader_id as i64)
+        .bind(chat_id as i64)
+        .fetch_one(&mut *tx)
+        .await?;
+        let mut new_files = HashSet::new();
+        let mut new_size = 0;
+        for record in &files {
+            let exists: bool = sqlx::query_scalar(
+                "SELECT EXISTS(SELECT 1 FROM chat_files WHERE chat_id = $1 AND hash = $2 AND ext = $3)",
+            )
+            .bind(record.file.chat_id as i64)
+            .bind(&record.file.hash)
+            .bind(&record.file.ext)
+            .fetch_one(&mut *tx)
+            .await?;
+            if !exists && new_files.insert(&record.file) {
+                new_size += record.size;
+            }
+        }
+        quota.check(new_size, user_usage as u64, chat_usage as u64)?;
+        for record in &files {
+            Self::insert_chat_file(&mut tx, record).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_chat_file(&self, file: &FileId) -> Result<Option<FileRecord>, AppError> {
+        let row: Option<FileRow> = sqlx::query_as(
+            r#"
+            SELECT chat_id, hash, ext, size, mime, uploader_id, created_at FROM chat_files
+            WHERE chat_id = $1 AND hash = $2 AND ext = $3
+            "#,
+        )
+        .bind(file.chat_id as i64)
+        .bind(&file.hash)
+        .bind(&file.ext)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(FileRecord::from))
+    }
+
+    async fn user_file_usage(&self, user_id: u32) -> Result<u64, AppError> {
+        let usage: i64 = sqlx::query_scalar(
+            "SELECT COALESCE(SUM(size), 0)::bigint FROM chat_files WHERE uploader_id = $1",
+        )
+        .bind(user_id as i64)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(usage as u64)
+    }
+
+    async fn chat_file_usage(&self, chat_id: u32) -> Result<u64, AppError> {
+        let usage: i64 = sqlx::query_scalar(
+            "SELECT COALESCE(SUM(size), 0)::bigint FROM chat_files WHERE chat_id = $1",
+        )
+        .bind(chat_id as i64)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(usage as u64)
+    }
+
+    async fn blob_refcount(&self, hash: &str) -> Result<Option<u32>, AppError> {
+        let refcount: Option<i32> =
+            sqlx::query_scalar("SELECT refcount FROM blobs WHERE hash = $1")
+                .bind(hash)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(refcount.map(|n| n as u32))
+    }
+
+    async fn set_file_meta(&self, hash: &str, meta: FileMeta) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            UPDATE blobs SET width = $2, height = $3, thumb_mime = $4, processed_at = now()
+            WHERE hash = $1
+            "#,
+        )
+        .bind(hash)
+        .bind(meta.width.map(|n| n as i32))
+        .bind(meta.height.map(|n| n as i32))
+        .bind(meta.thumb_mime)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_file_meta(&self, hash: &str) -> Result<Option<FileMeta>, AppError> {
+        let row: Option<FileMetaRow> = sqlx::query_as(
+            r#"
+            SELECT width, height, thumb_mime FROM blobs
+            WHERE hash = $1 AND processed_at IS NOT NULL
+            "#,
+        )
+        .bind(hash)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(FileMeta::from))
+    }
+
+    async fn delete_blob(&self, hash: &str) -> Result<bool, AppError> {
+        // one statement sees one snapshot, a reference added meanwhile keeps the row
+        let deleted: bool = sqlx::query_scalar(
+            r#"
+            WITH deleted AS (DELETE FROM blobs WHERE hash = $1 AND refcount = 0 RETURNING hash)
+            SELECT EXISTS(SELECT 1 FROM deleted) OR NOT EXISTS(SELECT 1 FROM blobs WHERE hash = $1)
+            "#,
+        )
+        .bind(hash)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(deleted)
+    }
+}
+
+impl From<UserRow> for User {
+    fn from(row: UserRow) -> Self {
+        User {
+            id: row.id as u32,
+            ws_id: row.ws_id as u32,
+            name: row.name,
+            email: row.email,
+            gender: row.gender.unwrap_or_default(),
+            teenager: row.teenager,
+            user_type: row.r#type,
+            hash_password: row.password,
+        }
+    }
+}
+
+impl From<WorkspaceRow> for Workspace {
+    fn from(row: WorkspaceRow) -> Self {
+        Workspace {
+            id: row.id as u32,
+            name: row.name,
+            owner_id: row.owner_id.map(|id| id as u32),
+            created_at: row.created_at,
+        }
+    }
+}
+
+impl From<ChatRow> for Chat {
+    fn from(row: ChatRow) -> Self {
+        Chat {
+            id: row.chat_id as u32,
+            ws_id: row.ws_id as u32,
+            name: row.chat_name,
+            chat_type: row.r#type,
+            owner_id: row.owner_id.map(|id| id as u32),
+            moderators: row.moderators.into_iter().map(|id| id as u32).collect(),
+            members: row
+                .members
+                .unwrap_or_default()
+                .into_iter()
+                .map(|id| id as u32)
+                .collect(),
+        }
+    }
+}
+
+impl From<InvitationRow> for Invitation {
+    fn from(row: InvitationRow) -> Self {
+        Invitation {
+            chat_id: row.chat_id as u32,
+            chat_name: row.chat_name,
+            chat_type: row.r#type,
+            user_id: row.user_id as u32,
+            inviter_id: row.inviter_id as u32,
+            created_at: row.created_at,
+        }
+    }
+}
+
+impl From<FileMetaRow> for FileMeta {
+    fn from(row: FileMetaRow) -> Self {
+        FileMeta {
+            width: row.width.map(|n| n as u32),
+            height: row.height.map(|n| n as u32),
+            thumb_mime: row.thumb_mime,
+        }
+    }
+}
+
+impl From<FileRow> for FileRecord {
+    fn from(row: FileRow) -> Self {
+        FileRecord {
+            file: FileId {
+                chat_id: row.chat_id as u32,
+                hash: row.hash,
+                ext: row.ext,
+            },
+            size: row.size as u64,
+            mime: row.mime,
+            uploader_id: row.uploader_id as u32,
+            created_at: row.created_at,
+        }
+    }
+}
+
+impl From<MessageRow> for Message {
+    fn from(row: MessageRow) -> Self {
+        Message {
+            id: row.message_id as u64,
+            chat_id: row.chat_id as u32,
+            sender_id: row.sender_id as u32,
+            content: row.content,
+            files: row.files.0,
+            date: row.created_at.unwrap_or_else(Utc::now),
+            edited_at: row.edited_at,
+            deleted_at: row.deleted_at,
+            reactions: row.reactions.0,
+            reply_to: row.reply_to.map(|id| id as u64),
+            mentions: row.mentions.into_iter().map(|id| id as u32).collect(),
+        }
+    }
+}
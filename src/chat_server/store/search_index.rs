@@ -0,0 +1,185 @@
+use std::collections::{BTreeMap, HashMap};
+
+// the lowercased words of the text, anything that is not a letter or digit separates words.
+// postgres' `simple` configuration alone would keep e.g. bob@example.com whole, the
+// search_vector column splits the content the same way before it is parsed
+pub fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
+
+// the html escaped text with the words starting with one of the terms wrapped in <mark>
+pub fn highlight(text: &str, terms: &[String]) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while !rest.is_empty() {
+        let word_len = rest
+            .find(|c: char| !c.is_alphanumeric())
+            .unwrap_or(rest.len());
+        if word_len == 0 {
+            let c = rest.chars().next().unwrap_or_default();
+            escape_into(&mut out, c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+        let (word, tail) = rest.split_at(word_len);
+        let lower = word.to_lowercase();
+        if terms.iter().any(|term| lower.starts_with(term.as_str())) {
+            out.push_str("<mark>");
+            word.chars().for_each(|c| escape_into(&mut out, c));
+            out.push_str("</mark>");
+        } else {
+            word.chars().for_each(|c| escape_into(&mut out, c));
+        }
+        rest = tail;
+    }
+    out
+}
+
+fn escape_into(out: &mut String, c: char) {
+    match c {
+        '&' => out.push_str("&amp;"),
+        '<' => out.push_str("&lt;"),
+        '>' => out.push_str("&gt;"),
+        '"' => out.push_str("&quot;"),
+        '\'' => out.push_str("&#39;"),
+        c => out.push(c),
+    }
+}
+
+// an inverted index of message contents for the in-memory store
+#[derive(Debug, Default)]
+pub struct SearchIndex {
+    // word -> message id -> how often the word occurs in the message
+    postings: BTreeMap<String, HashMap<u64, u32>>,
+    // message id -> its words, to take the message out again
+    docs: HashMap<u64, Doc>,
+}
+
+#[derive(Debug)]
+struct Doc {
+    words: Vec<String>,
+    len: usize,
+}
+
+impl SearchIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // index the content of the message, replacing what was indexed for it before
+    pub fn insert(&mut self, id: u64, content: &str) {
+        self.remove(id);
+        let mut counts = HashMap::<String, u32>::new();
+        let mut len = 0;
+        for word in tokenize(content) {
+            *counts.entry(word).or_default() += 1;
+            len += 1;
+        }
+        if len == 0 {
+            return;
+        }
+        let words = counts.keys().cloned().collect();
+        for (word, count) in counts {
+            self.postings.entry(word).or_default().insert(id, count);
+        }
+        self.docs.insert(id, Doc { words, len });
+    }
+
+    pub fn remove(&mut self, id: u64) {
+        let Some(doc) = self.docs.remove(&id) else {
+            return;
+        };
+        for word in doc.words {
+            if let Some(ids) = self.postings.get_mut(&word) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    self.postings.remove(&word);
+                }
+            }
+        }
+    }
+
+    // the messages with a word starting with each of the terms, with their rank: how often
+    // the terms occur divided by 1 + the log of the message length, like postgres' ts_rank
+    // with normalization 1. unordered
+    pub fn search(&self, terms: &[String]) -> Vec<(u64, f32)> {
+        let mut found: Option<HashMap<u64, u32>> = None;
+        for term in terms {
+            let mut matches = HashMap::<u64, u32>::new();
+            let words = self
+                .postings
+                .range(term.clone()..)
+                .take_while(|(word, _)| word.starts_with(term.as_str()));
+            for (_, ids) in words {
+                for (id, count) in ids {
+                    *matches.entry(*id).or_default() += count;
+                }
+            }
+            found = Some(match found {
+                None => matches,
+                Some(mut found) => {
+                    found.retain(|id, _| matches.contains_key(id));
+                    for (id, count) in found.iter_mut() {
+                        *count += matches[id];
+                    }
+                    found
+                }
+            });
+        }
+        found
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(id, count)| {
+                let len = self.docs.get(&id).map_or(1, |doc| doc.len);
+                (id, count as f32 / (1.0 + (len as f32).ln()))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn terms(query: &str) -> Vec<String> {
+        tokenize(query).collect()
+    }
+
+    #[test]
+    fn test_search_index() {
+        let mut index = SearchIndex::new();
+        index.insert(1, "Rust is fast, rustaceans love Rust");
+        index.insert(2, "Go is fast too");
+        index.insert(3, "nothing to see here");
+
+        let ids = |query: &str| {
+            let mut hits = index.search(&terms(query));
+            hits.sort_by(|a, b| b.1.total_cmp(&a.1));
+            hits.into_iter().map(|(id, _)| id).collect::<Vec<_>>()
+        };
+        assert_eq!(ids("FAST"), vec![2, 1]);
+        assert_eq!(ids("rust fast"), vec![1]);
+        assert_eq!(ids("rustacean"), vec![1]);
+        assert_eq!(ids("fast nothing"), Vec::<u64>::new());
+
+        index.insert(2, "Go is simple");
+        index.remove(1);
+        assert_eq!(index.search(&terms("fast")), vec![]);
+        assert_eq!(index.search(&terms("simple")).len(), 1);
+        assert!(!index.postings.contains_key("rust"));
+    }
+
+    #[test]
+    fn test_highlight() {
+        assert_eq!(
+            highlight("Rust <3 rustaceans, trust me", &terms("rust")),
+            "<mark>Rust</mark> &lt;3 <mark>rustaceans</mark>, trust me"
+        );
+        assert_eq!(
+            highlight("héllo wörld", &terms("WÖR")),
+            "héllo <mark>wörld</mark>"
+        );
+    }
+}
@@ -1,8 +1,10 @@
 mod arcmutex;
+mod chat_server;
 mod error;
 mod my_matrix;
 mod tokio;
 pub use arcmutex::*;
+pub use chat_server::*;
 pub use error::*;
 pub use my_matrix::*;
 pub use tokio::*;
\ No newline at end of file